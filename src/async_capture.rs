@@ -0,0 +1,203 @@
+//! Async capture, with the `tokio` feature.
+//!
+//! An `AsyncCapture` reads a serial port within a tokio runtime and is a
+//! `Stream` of `SerialEvent`s. Frame gaps are timed with tokio timers, rather
+//! than the port's read timeout, so many ports can be captured by one task.
+//!
+//! ```no_run
+//! use serialpcap_rs::capture::Capture;
+//!
+//! # async fn capture() -> std::io::Result<()> {
+//! let mut capture = Capture::builder("/dev/ttyUSB0").baud_rate(115200).open_async()?;
+//! while let Some(event) = capture.next_event().await {
+//!     let event = event?;
+//!     println!("{:?} {:02x?}", event.kind, event.data);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use chrono::prelude::*;
+use chrono::TimeDelta;
+use futures_core::Stream;
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::time::{Instant, Sleep};
+use tokio_serial::SerialStream;
+
+use crate::capture::{FrameSplitter, MAX_PACKET_SIZE};
+use crate::portinfo::PortControlLines;
+use crate::state::{Direction, SerialEvent};
+
+/// A capture of one serial port, read asynchronously.
+///
+/// Created with `CaptureBuilder::open_async`. Only data is captured, control
+/// lines aren't watched and breaks and UART errors aren't recorded. The
+/// stream ends when the port does, or after the first error.
+///
+/// # Fields
+///
+/// * `port` - The serial port
+/// * `splitter` - Splits received data into frames
+/// * `frame_gap` - Time gap between frames
+/// * `char_time` - The time taken to receive a character
+/// * `buffer` - Data received since the last frame gap
+/// * `byte_times` - When the first and last bytes in `buffer` arrived
+/// * `last_byte_time` - When the last byte of the previous packet arrived
+/// * `gap` - Expires when the line has been idle for the frame gap
+/// * `idle` - Whether the framer has been told of the line going idle
+/// * `delayed_error` - A port error, returned after the data received before it
+/// * `done` - Set once the port has ended or failed
+pub struct AsyncCapture {
+    port: SerialStream,
+    splitter: FrameSplitter,
+    frame_gap: Duration,
+    char_time: TimeDelta,
+    direction: Direction,
+    buffer: Vec<u8>,
+    byte_times: Option<(DateTime<Utc>, DateTime<Utc>)>,
+    last_byte_time: DateTime<Utc>,
+    gap: Pin<Box<Sleep>>,
+    idle: bool,
+    delayed_error: Option<io::Error>,
+    done: bool,
+}
+
+impl AsyncCapture {
+    pub(crate) fn new(port: SerialStream, splitter: FrameSplitter, frame_gap: Duration, char_time: TimeDelta, direction: Direction) -> Self {
+        AsyncCapture {
+            port,
+            splitter,
+            frame_gap,
+            char_time,
+            direction,
+            buffer: Vec::with_capacity(MAX_PACKET_SIZE),
+            byte_times: None,
+            last_byte_time: DateTime::UNIX_EPOCH,
+            gap: Box::pin(tokio::time::sleep(frame_gap)),
+            idle: true,
+            delayed_error: None,
+            done: false,
+        }
+    }
+
+    /// Captures the next event, or `None` once the port has ended.
+    pub async fn next_event(&mut self) -> Option<io::Result<SerialEvent>> {
+        std::future::poll_fn(|cx| Pin::new(&mut *self).poll_next(cx)).await
+    }
+
+    /// Records the arrival of `read_len` more bytes, working back from now
+    /// to when the first of them arrived, as the blocking capture does.
+    fn received(&mut self, read_len: usize) {
+        let now = Utc::now();
+        let first = match self.byte_times {
+            Some((first, _)) => first,
+            None => (now - self.char_time * read_len as i32).max(self.last_byte_time),
+        };
+        self.last_byte_time = now;
+        self.byte_times = Some((first, now));
+    }
+
+    /// Passes the buffered data to the framer as one chunk.
+    fn flush(&mut self) {
+        if self.buffer.is_empty() {
+            return;
+        }
+        let data = std::mem::replace(&mut self.buffer, Vec::with_capacity(MAX_PACKET_SIZE));
+        let data_len = data.len();
+        let mut event = SerialEvent::new(data, data_len, PortControlLines::default(), self.direction);
+        if let Some((first, last)) = self.byte_times.take() {
+            event.timestamp = first;
+            event.end_timestamp = last;
+        }
+        self.splitter.push(event);
+    }
+
+    /// Tells the framer the line has gone idle.
+    fn go_idle(&mut self) {
+        self.idle = true;
+        self.splitter.push(SerialEvent::new(Vec::new(), 0, PortControlLines::default(), self.direction));
+    }
+
+    /// Reads whatever the port has ready into the buffer.
+    ///
+    /// Returns `Poll::Pending` once nothing more is ready, having arranged to
+    /// be woken when there is.
+    fn poll_port(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        loop {
+            let filled = self.buffer.len();
+            self.buffer.resize(MAX_PACKET_SIZE, 0);
+            let mut read_buf = ReadBuf::new(&mut self.buffer[filled..]);
+            let result = Pin::new(&mut self.port).poll_read(cx, &mut read_buf);
+            let read_len = read_buf.filled().len();
+            self.buffer.truncate(filled + read_len);
+            match result {
+                Poll::Ready(Ok(())) if read_len == 0 => {
+                    self.done = true;
+                    return Poll::Ready(());
+                },
+                Poll::Ready(Ok(())) => {
+                    self.received(read_len);
+                    self.idle = false;
+                    let deadline = Instant::now() + self.frame_gap;
+                    self.gap.as_mut().reset(deadline);
+                    if self.buffer.len() == MAX_PACKET_SIZE {
+                        self.flush();
+                        return Poll::Ready(());
+                    }
+                },
+                Poll::Ready(Err(e)) => {
+                    self.delayed_error = Some(e);
+                    self.done = true;
+                    return Poll::Ready(());
+                },
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl Stream for AsyncCapture {
+    type Item = io::Result<SerialEvent>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(event) = this.splitter.pop() {
+                if event.is_insignificant() {
+                    continue;
+                }
+                return Poll::Ready(Some(Ok(event)));
+            }
+            if this.done {
+                // Whatever was received before the port ended is still a frame
+                if !this.buffer.is_empty() || !this.idle {
+                    this.flush();
+                    this.go_idle();
+                    continue;
+                }
+                return Poll::Ready(this.delayed_error.take().map(Err));
+            }
+            if this.poll_port(cx).is_ready() {
+                continue;
+            }
+            if this.idle || this.gap.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            // The line has been quiet for the frame gap. Data before the gap
+            // is a frame, and a further gap without data is the line going idle.
+            if this.buffer.is_empty() {
+                this.go_idle();
+            } else {
+                this.flush();
+                let deadline = Instant::now() + this.frame_gap;
+                this.gap.as_mut().reset(deadline);
+            }
+        }
+    }
+}
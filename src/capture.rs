@@ -0,0 +1,1052 @@
+//! The capture engine.
+//!
+//! A `Capture` reads one serial port, splitting what it receives into
+//! `SerialEvent`s: frames of data, control line edges, breaks and UART
+//! errors. Captures are set up with a `CaptureBuilder`, then either iterated
+//! over directly or run together with `run`, which encapsulates the events
+//! and writes them to a `Sink` in timestamp order.
+//!
+//! ```no_run
+//! use serialpcap_rs::capture::Capture;
+//!
+//! let capture = Capture::builder("/dev/ttyUSB0").baud_rate(115200).open()?;
+//! for event in capture.take(10) {
+//!     let event = event?;
+//!     println!("{:?} {:02x?}", event.kind, event.data);
+//! }
+//! # Ok::<(), std::io::Error>(())
+//! ```
+
+use std::collections::VecDeque;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+use chrono::prelude::*;
+use chrono::TimeDelta;
+use pcap_file::DataLink;
+
+#[cfg(feature = "tokio")]
+use crate::async_capture::AsyncCapture;
+use crate::datalink;
+use crate::framing::{Framer, FramingMode};
+use crate::gpiopins::GpioPins;
+use crate::linemap::{LineOutput, LineRouter};
+use crate::merge::{PacketMerger, Unfinished};
+use crate::output::{CapturedPacket, InterfaceInfo};
+use crate::portinfo::{AdvancedSerialPort, AnySerialPort, PortControlLines};
+use crate::sink::Sink;
+use crate::state::{self, Direction, EventKind};
+use crate::tty;
+
+/// Represents the encapsulation mode used for the captured data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncapsulationMode {
+    Raw,
+    DatalinkType
+}
+
+pub const MAX_PACKET_SIZE: usize = 2048; // Maximum size for a packet in bytes
+const MERGE_SLACK_MS: i64 = 100; // Allowance for thread scheduling when merging, also how often the merger is polled
+
+/// Calculates the time taken to send `chars` characters.
+///
+/// Each character is a start bit, 8 data bits, an optional parity bit and the stop bits.
+/// Times too long to represent saturate rather than panicking.
+pub fn char_time(chars: f64, baud_rate: u32, parity: char, stopbits: u8) -> Duration {
+    let parity_bits = match parity {
+        'o' | 'e' => 1,
+        _ => 0,
+    };
+    let bits_per_char = (1 + 8 + parity_bits + stopbits as u32) as f64;
+    Duration::try_from_secs_f64(chars * bits_per_char / baud_rate.max(1) as f64).unwrap_or(Duration::MAX)
+}
+
+/// Parses an inter-frame gap in character times from a string.
+/// this is used in our clap argument parser.
+pub fn parse_gap_chars(gap_str: &str) -> Result<f64, clap::error::Error> {
+    match gap_str.parse::<f64>() {
+        Ok(chars) if chars.is_finite() && chars > 0.0 => Ok(chars),
+        _ => Err(clap::error::Error::raw(
+            clap::error::ErrorKind::InvalidValue,
+            format!("Invalid gap: {} (expected a number of character times above 0, e.g. 3.5)", gap_str),
+        )),
+    }
+}
+
+/// `char_time` as a `TimeDelta`, for working out when bytes arrived.
+pub(crate) fn char_delta(chars: usize, baud_rate: u32, parity: char, stopbits: u8) -> TimeDelta {
+    TimeDelta::from_std(char_time(chars as f64, baud_rate, parity, stopbits)).unwrap_or_default()
+}
+
+/// How long a write may block for, enough for the largest packet to go out.
+pub(crate) fn write_timeout(baud_rate: u32, parity: char, stopbits: u8) -> Duration {
+    char_time(MAX_PACKET_SIZE as f64, baud_rate, parity, stopbits) + Duration::from_secs(1)
+}
+
+/// Converts the frame gap into the serial port read timeout.
+///
+/// On Linux the port waits with `ppoll`, which has nanosecond resolution, so
+/// sub-millisecond gaps are used as is. Elsewhere the wait has millisecond
+/// resolution, so round up rather than truncating short gaps to zero.
+#[cfg(target_os = "linux")]
+fn port_timeout(frame_gap: Duration) -> Duration {
+    frame_gap
+}
+
+#[cfg(not(target_os = "linux"))]
+fn port_timeout(frame_gap: Duration) -> Duration {
+    let millis = frame_gap.as_nanos().div_ceil(1_000_000).max(1);
+    Duration::from_millis(millis as u64)
+}
+
+/// Opens a serial port, along with its tty, with any GPIO pins attached.
+pub(crate) fn open_port(builder: serialport::SerialPortBuilder, gpio_pins: &GpioPins) -> io::Result<(AnySerialPort, tty::Tty)> {
+    if gpio_pins.is_empty() {
+        let (port, tty) = tty::open(builder)?;
+        return Ok((AnySerialPort::Basic(port), tty));
+    }
+    attach_gpio_pins(builder, gpio_pins)
+}
+
+#[cfg(target_os = "linux")]
+fn attach_gpio_pins(builder: serialport::SerialPortBuilder, gpio_pins: &GpioPins) -> io::Result<(AnySerialPort, tty::Tty)> {
+    use crate::gpiopins::{CdevBackend, SysFsBackend};
+
+    let (port, tty) = tty::open_native(builder)?;
+    let port: Box<dyn AdvancedSerialPort> = match gpio_pins.chip.as_deref() {
+        Some(chip) => Box::new(gpio_pins.attach(port, &mut CdevBackend::open(chip)?)?),
+        None => Box::new(gpio_pins.attach(port, &mut SysFsBackend)?),
+    };
+    Ok((AnySerialPort::Advanced(port), tty))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn attach_gpio_pins(_builder: serialport::SerialPortBuilder, _gpio_pins: &GpioPins) -> io::Result<(AnySerialPort, tty::Tty)> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "GPIO pins are only supported on Linux"))
+}
+
+/// Sets up the port's speed and character format.
+///
+/// DTR is left alone on pseudo terminals, which have no control lines to set.
+pub(crate) fn port_builder(port_name: &str, baud_rate: u32, parity: char, stopbits: u8) -> serialport::SerialPortBuilder {
+    let builder = serialport::new(port_name, baud_rate)
+        .parity(match parity {
+            'o' => serialport::Parity::Odd,
+            'e' => serialport::Parity::Even,
+            _ => serialport::Parity::None,
+        })
+        .stop_bits(match stopbits {
+            1 => serialport::StopBits::One,
+            2 => serialport::StopBits::Two,
+            _ => serialport::StopBits::One,
+        });
+    if tty::is_pty(port_name) {
+        builder.preserve_dtr_on_open()
+    } else {
+        builder
+    }
+}
+
+/// Represents a serial port capture session with configurable parameters
+///
+/// Created with `Capture::builder`. Iterating over a capture blocks for each
+/// event, skipping the empty data events returned when the line is idle.
+/// 
+/// # Fields
+/// 
+/// * `port` - The serial port interface
+/// * `baud_rate` - The communication speed in bits per second
+/// * `parity` - Parity checking mode ('n' for none, 'e' for even, 'o' for odd)
+/// * `stopbits` - Number of stop bits (1 or 2)
+/// * `frame_gap` - Time gap between frames
+/// * `direction` - Which side of a tapped link this port listens to
+/// * `splitter` - Splits received data into frames, if not splitting on the frame gap alone
+/// * `control_lines` - The last seen state of the control lines
+/// * `has_control_lines` - Whether the port's control lines can be read
+/// * `uart_counters` - Source of break and UART error events, where supported
+/// * `error_marks` - Decodes errors marked in the received data, if enabled
+/// * `last_byte_time` - When the last byte of the previous packet arrived
+/// * `unfinished` - When the oldest packet being read, but not yet sent, started
+/// * `forward` - In a bridge, the port everything received is written to
+/// * `forward_lines` - Routes the control lines to the outputs of the `forward` port
+/// * `forward_marks` - Removes error marks from data before it is forwarded
+/// * `failed` - Set after a port error, which ends the iteration
+pub struct Capture {
+   port: AnySerialPort,
+   datalink: DataLink,
+   bus_name: String,
+   baud_rate: u32,
+   parity: char,
+   stopbits: u8,
+   frame_gap: Duration,
+   encap_mode: EncapsulationMode,
+   direction: Direction,
+   splitter: FrameSplitter,
+   control_lines: PortControlLines,
+   has_control_lines: bool,
+   line_events: VecDeque<state::SerialEvent>,
+   uart_counters: Option<tty::UartCounters>,
+   tty: tty::Tty,
+   error_marks: Option<tty::ErrorMarkDecoder>,
+   last_byte_time: DateTime<Utc>,
+   unfinished: Unfinished,
+   delayed_error: Option<io::Error>,
+   forward: Option<AnySerialPort>,
+   forward_lines: LineRouter,
+   forward_marks: Option<tty::ErrorMarkDecoder>,
+   failed: bool,
+}
+
+
+
+impl Capture {
+    /// Starts setting up a capture of the named port.
+    pub fn builder(port_name: &str) -> CaptureBuilder {
+        CaptureBuilder::new(port_name)
+    }
+
+    fn new(settings: &CaptureBuilder) -> io::Result<Self> {
+        let frame_gap = settings.gap();
+        let (mut port, tty) = open_port(port_builder(&settings.port_name, settings.baud_rate, settings.parity, settings.stopbits)
+            .timeout(port_timeout(frame_gap)), &settings.gpio_pins)?;
+        // Get initial control lines state. Ptys have no control lines, so
+        // are captured without control line events.
+        let (control_lines, has_control_lines) = match port.capture_control_lines() {
+            Ok(control_lines) => (control_lines, true),
+            Err(_) if tty::is_pty(&settings.port_name) => (PortControlLines::default(), false),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Capture {
+            port,
+            baud_rate: settings.baud_rate,
+            parity: settings.parity,
+            stopbits: settings.stopbits,
+            frame_gap,
+            datalink: settings.datalink,
+            bus_name: settings.port_name.clone(),
+            encap_mode: settings.encap_mode,
+            direction: Direction::Unknown,
+            splitter: FrameSplitter::new(None, TimeDelta::zero()),
+            control_lines,
+            has_control_lines,
+            line_events: VecDeque::new(),
+            uart_counters: tty.uart_counters(),
+            tty,
+            error_marks: None,
+            last_byte_time: DateTime::UNIX_EPOCH,
+            unfinished: Unfinished::new(),
+            delayed_error: None,
+            forward: None,
+            forward_lines: LineRouter::default(),
+            forward_marks: None,
+            failed: false,
+        })
+    }
+
+    /// Has the tty mark breaks and parity and framing errors in the data, so
+    /// each data event records the bytes they happened on.
+    fn mark_errors(&mut self) -> io::Result<()> {
+        self.tty.mark_errors()?;
+        self.error_marks = Some(tty::ErrorMarkDecoder::new());
+        Ok(())
+    }
+
+    /// Checks for control line edges, breaks and UART errors, queuing an event for each
+    fn poll_line_events(&mut self) -> io::Result<()> {
+        if self.has_control_lines {
+            let current_control_lines = self.port.capture_control_lines()?;
+            let changed_lines = current_control_lines.changed_lines(&self.control_lines);
+            if !changed_lines.is_empty() {
+                if let Some(forward) = self.forward.as_mut() {
+                    forward.reflect_control_lines(&current_control_lines, &mut self.forward_lines)?;
+                }
+            }
+            for line in changed_lines {
+                self.line_events.push_back(state::SerialEvent::line_event(
+                    EventKind::ControlLine(line), current_control_lines.clone(), self.direction));
+            }
+            self.control_lines = current_control_lines;
+        }
+
+        if let Some(counters) = self.uart_counters.as_mut() {
+            for kind in counters.poll()? {
+                if self.error_marks.as_mut().is_some_and(|marks| marks.counted(kind)) {
+                    continue;
+                }
+                self.line_events.push_back(state::SerialEvent::line_event(
+                    kind, self.control_lines.clone(), self.direction));
+            }
+        }
+        Ok(())
+    }
+
+    /// Captures a packet from the serial port
+    ///
+    /// A data packet ends at the inter-frame gap, when the buffer is full, or
+    /// when a control line changes or a UART error is seen. Those are then
+    /// returned as events of their own.
+    ///
+    /// # Returns
+    /// 
+    /// A `SerialEvent` containing the captured packet data, which is empty if no data is captured.
+    fn capture_packet(&mut self) -> Result<state::SerialEvent, io::Error> {
+
+        if let Some(err) = self.delayed_error.take() {
+            return Err(err);
+        }
+
+        if self.line_events.is_empty() {
+            self.poll_line_events()?;
+        }
+        if let Some(event) = self.line_events.pop_front() {
+            return Ok(event);
+        }
+
+        let mut buffer: Vec<u8> = vec![0; MAX_PACKET_SIZE];
+        let mut bytes_read = 0;
+
+        let control_lines_last = self.control_lines.clone();
+        let mut byte_times = None;
+        while match self.port.as_serial_port().read(&mut buffer[bytes_read..]) {
+
+            Ok(this_read_len) => {
+                if this_read_len > 0 {
+                    byte_times = Some(self.byte_times(byte_times, this_read_len));
+                    if let Err(e) = self.forward_data(&buffer[bytes_read..bytes_read + this_read_len]) {
+                        self.delayed_error = Some(e);
+                        return Ok(self.data_event(&buffer[..bytes_read + this_read_len], control_lines_last, byte_times))
+                    }
+                }
+                bytes_read += this_read_len;
+                bytes_read < buffer.len()
+            },
+            Err(e) => {
+                if e.kind() == io::ErrorKind::TimedOut {
+                    // Timeout is expected, but
+                    // indicates the end of a packet.
+                    false
+                } else {
+                    // Handle other errors
+                    if bytes_read == 0 {
+                        // If no bytes were read, return the error
+                        return Err(e);
+                    }
+                    self.delayed_error =  Some(e);
+                    return Ok(self.data_event(&buffer[..bytes_read], control_lines_last, byte_times))
+                }
+            },
+        }  {
+                self.poll_line_events()?;
+                if !self.line_events.is_empty() {
+                    // If control lines have changed, we consider this a new packet
+                    return Ok(self.data_event(&buffer[..bytes_read], control_lines_last, byte_times))
+                }
+        }
+        Ok(self.data_event(&buffer[..bytes_read], control_lines_last, byte_times))
+    }
+
+    /// In a bridge, writes received data on to the other port straight away,
+    /// without waiting for the end of the frame.
+    fn forward_data(&mut self, received: &[u8]) -> io::Result<()> {
+        let Some(forward) = self.forward.as_mut() else {
+            return Ok(());
+        };
+        let port = forward.as_serial_port();
+        match self.forward_marks.as_mut() {
+            Some(marks) => port.write_all(&marks.decode(received).0),
+            None => port.write_all(received),
+        }
+    }
+
+    /// A second handle on the port, to write to it while it is captured.
+    fn try_clone_port(&self) -> io::Result<AnySerialPort> {
+        let mut port = match &self.port {
+            AnySerialPort::Basic(port) => port.try_clone()?,
+            AnySerialPort::Advanced(port) => port.try_clone()?,
+        };
+        port.set_timeout(write_timeout(self.baud_rate, self.parity, self.stopbits))?;
+        Ok(AnySerialPort::Basic(port))
+    }
+
+    /// Makes this port one side of a bridge, writing everything it receives
+    /// to `port` and setting `port`'s outputs to follow its control lines,
+    /// as routed by `lines`.
+    ///
+    /// `port` is usually the other side's port, from `bridge`, but may be
+    /// wrapped to drive GPIO outputs too.
+    pub fn forward_to(&mut self, mut port: AnySerialPort, mut lines: LineRouter) -> io::Result<()> {
+        if self.has_control_lines {
+            port.reflect_control_lines(&self.control_lines, &mut lines)?;
+        }
+        self.forward_marks = self.error_marks.as_ref().map(|_| tty::ErrorMarkDecoder::new());
+        self.forward = Some(port);
+        self.forward_lines = lines;
+        Ok(())
+    }
+
+    /// The outputs the control lines are routed to which the port being
+    /// forwarded to doesn't have, so are left alone.
+    pub fn unrouted_outputs(&self) -> Vec<LineOutput> {
+        match self.forward.as_ref() {
+            Some(port) => self.forward_lines.unsupported_outputs(port),
+            None => Vec::new(),
+        }
+    }
+
+    /// The time taken to receive `chars` characters.
+    fn char_delta(&self, chars: usize) -> TimeDelta {
+        char_delta(chars, self.baud_rate, self.parity, self.stopbits)
+    }
+
+    /// The longest a packet split on the frame gap takes to reach the
+    /// merger after its first byte: the longest packet and the gap ending it.
+    ///
+    /// Frames a framer is still waiting for the end of hold back the other
+    /// ports' packets for no longer than this.
+    fn longest_packet(&self) -> TimeDelta {
+        TimeDelta::from_std(self.frame_gap).ok()
+            .and_then(|gap| gap.checked_add(&self.char_delta(MAX_PACKET_SIZE)))
+            .unwrap_or(TimeDelta::MAX)
+    }
+
+    /// Updates the arrival times of a packet's first and last bytes after a read of `read_len` bytes.
+    ///
+    /// The read returns some time after the first of its bytes arrived, so
+    /// the first byte's arrival is worked back from the number of bytes and
+    /// the baud rate. It can't be before the end of the previous packet.
+    fn byte_times(&mut self, times: Option<(DateTime<Utc>, DateTime<Utc>)>, read_len: usize) -> (DateTime<Utc>, DateTime<Utc>) {
+        let now = Utc::now();
+        let first = match times {
+            Some((first, _)) => first,
+            None => {
+                let first = (now - self.char_delta(read_len)).max(self.last_byte_time);
+                self.unfinished.start(first);
+                first
+            },
+        };
+        self.last_byte_time = now;
+        (first, now)
+    }
+
+    /// When the byte at `offset` in a data event arrived, assuming its bytes
+    /// arrived back to back.
+    fn byte_time(&self, event: &state::SerialEvent, offset: usize) -> DateTime<Utc> {
+        (event.timestamp + self.char_delta(offset)).min(event.end_timestamp)
+    }
+
+    /// Builds the event for received data, decoding any error marks in it.
+    fn data_event(&mut self, received: &[u8], control_lines: PortControlLines, byte_times: Option<(DateTime<Utc>, DateTime<Utc>)>) -> state::SerialEvent {
+        let mut event = match self.error_marks.as_mut() {
+            Some(marks) => {
+                let (data, errors) = marks.decode(received);
+                let data_len = data.len();
+                let mut event = state::SerialEvent::new(data, data_len, control_lines, self.direction);
+                event.errors = errors;
+                event
+            },
+            None => state::SerialEvent::new(received.to_vec(), received.len(), control_lines, self.direction),
+        };
+        if let Some((first, last)) = byte_times {
+            event.timestamp = first;
+            event.end_timestamp = last;
+        }
+        event
+    }
+
+    /// Captures the next event, using the framer (if any) to split the data into frames
+    ///
+    /// Returns an empty data event if the line was idle for the frame gap.
+    pub fn next_event(&mut self) -> Result<state::SerialEvent, io::Error> {
+        loop {
+            if let Some(event) = self.splitter.pop() {
+                return Ok(event);
+            }
+            let chunk = self.capture_packet()?;
+            if chunk.kind != EventKind::Data || !self.splitter.has_framer() {
+                return Ok(chunk);
+            }
+            self.splitter.push(chunk);
+        }
+    }
+
+    /// Whether control line and UART error events can be written, they need
+    /// an encapsulation which records them.
+    fn records_line_events(&self) -> bool {
+        match self.encap_mode {
+            EncapsulationMode::Raw => false,
+            EncapsulationMode::DatalinkType => datalink::records_line_events(&self.datalink),
+        }
+    }
+
+    /// Encapsulates an event for the datalink type.
+    fn encapsulate(&self, event: state::SerialEvent) -> io::Result<Vec<u8>> {
+        datalink::get_encapsulated_data(event, &self.bus_name, &self.datalink)
+            .map_err(|e| io::Error::new(io::ErrorKind::Unsupported, e))
+    }
+
+    /// The name of the port captured.
+    pub fn port_name(&self) -> &str {
+        &self.bus_name
+    }
+
+    /// Describes this port for the capture file header.
+    pub fn interface_info(&self) -> InterfaceInfo {
+        let parity = match self.parity {
+            'o' => "odd",
+            'e' => "even",
+            _ => "none",
+        };
+        InterfaceInfo {
+            name: self.bus_name.clone(),
+            description: format!("{} baud, parity {}, {} stop bits, {:.3}ms frame gap",
+                self.baud_rate, parity, self.stopbits, self.frame_gap.as_secs_f64() * 1000.0),
+            datalink: self.datalink,
+            speed: self.baud_rate as u64,
+        }
+    }
+
+    /// Captures packets from the serial port and sends them to the writer
+    /// 
+    /// Runs until the port fails, `stop` is set, or the receiving end of
+    /// `sender` is dropped. A port failure is sent down the channel before returning.
+    ///
+    /// # Arguments
+    ///     
+    /// * `interface_id` - The capture file interface this port is written as
+    /// * `sender` - Channel to the thread writing the capture file
+    /// * `stop` - Set when the capture is to stop
+    fn send_packets(&mut self, interface_id: u32, sender: mpsc::Sender<io::Result<CapturedPacket>>, stop: &AtomicBool) {
+        while !stop.load(Ordering::Relaxed) {
+            // Everything taken so far has been sent, only data the framer holds is unfinished
+            self.unfinished.set(self.splitter.pending_since());
+            let packet = match self.next_event() {
+                Ok(packet) => packet,
+                Err(e) => {
+                    let _ = sender.send(Err(e));
+                    return;
+                }
+            };
+            if packet.is_insignificant() {
+                continue;
+            }
+            if packet.kind != EventKind::Data && !self.records_line_events() {
+                continue;
+            }
+
+            // The encapsulation has event records for the errors, each at the
+            // time of the byte it happened on, so written after the data
+            let error_events: Vec<state::SerialEvent> = if self.records_line_events() {
+                packet.errors.iter().map(|error| {
+                    let mut event = state::SerialEvent::line_event(
+                        error.kind.event_kind(), packet.control_lines.clone(), packet.direction);
+                    event.timestamp = self.byte_time(&packet, error.offset);
+                    event.end_timestamp = event.timestamp;
+                    event
+                }).collect()
+            } else {
+                Vec::new()
+            };
+
+            let kind = packet.kind;
+            let timestamp = packet.timestamp;
+            let end_timestamp = packet.end_timestamp;
+            let direction = packet.direction;
+            let captured_len = packet.data.len();
+            let frame_error = packet.frame_error;
+            let errors = packet.errors.clone();
+            // Encapsulate the packet data for the datalink type/force raw
+            let encap_packet = match self.encap_mode {
+                EncapsulationMode::Raw => Ok(packet.data.clone()),
+                EncapsulationMode::DatalinkType => self.encapsulate(packet),
+            };
+            let failed = encap_packet.is_err();
+            let sent = sender.send(encap_packet.map(|encap_packet| CapturedPacket {
+                interface_id,
+                kind,
+                timestamp,
+                end_timestamp,
+                direction,
+                frame_error,
+                errors,
+                captured_len,
+                data: encap_packet,
+            }));
+            if sent.is_err() || failed {
+                return;
+            }
+
+            for event in error_events {
+                let kind = event.kind;
+                let timestamp = event.timestamp;
+                let direction = event.direction;
+                let encapsulated = self.encapsulate(event);
+                let failed = encapsulated.is_err();
+                let sent = sender.send(encapsulated.map(|data| CapturedPacket {
+                    interface_id,
+                    kind,
+                    timestamp,
+                    end_timestamp: timestamp,
+                    direction,
+                    frame_error: None,
+                    errors: vec![],
+                    captured_len: 0,
+                    data,
+                }));
+                if sent.is_err() || failed {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+impl Iterator for Capture {
+    type Item = io::Result<state::SerialEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        loop {
+            match self.next_event() {
+                Ok(event) if event.is_insignificant() => continue,
+                Ok(event) => return Some(Ok(event)),
+                Err(e) => {
+                    self.failed = true;
+                    return Some(Err(e));
+                },
+            }
+        }
+    }
+}
+
+/// Splits received data into frames with a framer, working out the errors
+/// and arrival times of each frame from those of the data it came from.
+///
+/// Errors and arrival times are tracked by their position in the received
+/// byte stream until the frame holding them is found.
+///
+/// # Fields
+///
+/// * `framer` - The framer, if not splitting on the frame gap alone
+/// * `char_time` - The time taken to receive a character
+/// * `framed` - Frames found but not yet taken
+/// * `framed_errors` - Errors in data given to the framer, by position in the received byte stream
+/// * `chunk_times` - When data given to the framer arrived, by position in the received byte stream
+pub(crate) struct FrameSplitter {
+    framer: Option<Box<dyn Framer>>,
+    char_time: TimeDelta,
+    framed: VecDeque<state::SerialEvent>,
+    framed_errors: VecDeque<(usize, state::LineError)>,
+    chunk_times: VecDeque<(usize, DateTime<Utc>, DateTime<Utc>)>,
+    received: usize,     // Bytes given to the framer
+    framed_len: usize,   // Bytes the framer has used up
+}
+
+impl FrameSplitter {
+    pub(crate) fn new(framer: Option<Box<dyn Framer>>, char_time: TimeDelta) -> Self {
+        FrameSplitter {
+            framer,
+            char_time,
+            framed: VecDeque::new(),
+            framed_errors: VecDeque::new(),
+            chunk_times: VecDeque::new(),
+            received: 0,
+            framed_len: 0,
+        }
+    }
+
+    /// Whether there is a framer, without one data events are used as they are.
+    pub(crate) fn has_framer(&self) -> bool {
+        self.framer.is_some()
+    }
+
+    /// When the oldest data given to the framer, and not yet taken as part
+    /// of a frame, started arriving.
+    pub(crate) fn pending_since(&self) -> Option<DateTime<Utc>> {
+        let framed = self.framed.front().map(|frame| frame.timestamp);
+        let unframed = if self.received > self.framed_len {
+            self.stream_byte_time(self.framed_len, false)
+        } else {
+            None
+        };
+        framed.into_iter().chain(unframed).min()
+    }
+
+    /// Takes the next frame found.
+    pub(crate) fn pop(&mut self) -> Option<state::SerialEvent> {
+        self.framed.pop_front()
+    }
+
+    /// Gives a data event to the framer, an empty one meaning the line went idle.
+    pub(crate) fn push(&mut self, chunk: state::SerialEvent) {
+        let Some(framer) = self.framer.as_mut() else {
+            self.framed.push_back(chunk);
+            return;
+        };
+        let frames = if chunk.data.is_empty() {
+            framer.idle()
+        } else {
+            framer.push(&chunk.data)
+        };
+        self.framed_errors.extend(chunk.errors.iter().map(|error| (self.received + error.offset, error.kind)));
+        if !chunk.data.is_empty() {
+            self.chunk_times.push_back((self.received, chunk.timestamp, chunk.end_timestamp));
+        }
+        self.received += chunk.data.len();
+        for frame in frames {
+            let frame_start = self.framed_len;
+            self.framed_len += frame.wire_len;
+            while self.chunk_times.get(1).is_some_and(|(position, _, _)| *position <= frame_start) {
+                self.chunk_times.pop_front();
+            }
+            let timestamp = self.stream_byte_time(frame_start, false).unwrap_or(chunk.timestamp);
+            let end_timestamp = self.stream_byte_time(self.framed_len.saturating_sub(1), true).unwrap_or(chunk.end_timestamp);
+            let mut errors = Vec::new();
+            while let Some((position, kind)) = self.framed_errors.front().copied().filter(|(position, _)| *position < self.framed_len) {
+                self.framed_errors.pop_front();
+                // Offsets are into the wire bytes, which a decoded frame may be shorter than.
+                errors.push(state::ByteError { offset: (position - frame_start).min(frame.data.len()), kind });
+            }
+            self.framed.push_back(state::SerialEvent {
+                kind: EventKind::Data,
+                timestamp,
+                end_timestamp,
+                data: frame.data,
+                control_lines: chunk.control_lines.clone(),
+                direction: chunk.direction,
+                frame_error: frame.error,
+                errors,
+            });
+        }
+    }
+
+    /// Estimates when the byte at `position` in the received byte stream
+    /// started, or if `end` finished, arriving. The bytes of each chunk are
+    /// assumed to have arrived back to back.
+    fn stream_byte_time(&self, position: usize, end: bool) -> Option<DateTime<Utc>> {
+        let (start, first, last) = self.chunk_times.iter().rev().find(|(start, _, _)| *start <= position)?;
+        let chars = (position - start + usize::from(end)) as i32;
+        Some((*first + self.char_time * chars).min(*last))
+    }
+}
+
+/// Sets up a `Capture`.
+///
+/// The defaults match the command line's: 9600 baud, no parity, 1 stop bit,
+/// a 10ms frame gap, gap based framing and the USER0 datalink type.
+#[derive(Debug, Clone)]
+pub struct CaptureBuilder {
+    port_name: String,
+    baud_rate: u32,
+    parity: char,
+    stopbits: u8,
+    frame_gap: Duration,
+    gap_chars: Option<f64>,
+    datalink: DataLink,
+    encap_mode: EncapsulationMode,
+    framing: FramingMode,
+    decode: bool,
+    mark_errors: bool,
+    direction: Direction,
+    gpio_pins: GpioPins,
+}
+
+impl CaptureBuilder {
+    pub fn new(port_name: &str) -> Self {
+        CaptureBuilder {
+            port_name: port_name.to_string(),
+            baud_rate: 9600,
+            parity: 'n',
+            stopbits: 1,
+            frame_gap: Duration::from_millis(10),
+            gap_chars: None,
+            datalink: DataLink::USER0,
+            encap_mode: EncapsulationMode::DatalinkType,
+            framing: FramingMode::Gap,
+            decode: false,
+            mark_errors: false,
+            direction: Direction::Unknown,
+            gpio_pins: GpioPins::default(),
+        }
+    }
+
+    /// Sets the communication speed in bits per second.
+    pub fn baud_rate(mut self, baud_rate: u32) -> Self {
+        self.baud_rate = baud_rate;
+        self
+    }
+
+    /// Sets the parity, 'n' for none, 'e' for even or 'o' for odd.
+    pub fn parity(mut self, parity: char) -> Self {
+        self.parity = parity;
+        self
+    }
+
+    /// Sets the number of stop bits, 1 or 2.
+    pub fn stopbits(mut self, stopbits: u8) -> Self {
+        self.stopbits = stopbits;
+        self
+    }
+
+    /// Sets the inter frame gap.
+    pub fn frame_gap(mut self, frame_gap: Duration) -> Self {
+        self.frame_gap = frame_gap;
+        self.gap_chars = None;
+        self
+    }
+
+    /// Sets the inter frame gap in character times, e.g. 3.5 for Modbus RTU.
+    pub fn gap_chars(mut self, gap_chars: f64) -> Self {
+        self.gap_chars = Some(gap_chars);
+        self
+    }
+
+    /// Sets the datalink type the captured data is encapsulated for.
+    pub fn datalink(mut self, datalink: DataLink) -> Self {
+        self.datalink = datalink;
+        self
+    }
+
+    /// Sets whether the data is encapsulated for the datalink type, or written raw.
+    pub fn encapsulation(mut self, encap_mode: EncapsulationMode) -> Self {
+        self.encap_mode = encap_mode;
+        self
+    }
+
+    /// Sets how the received data is split into frames, and whether delimited
+    /// frames are decoded to their payload.
+    pub fn framing(mut self, framing: FramingMode, decode: bool) -> Self {
+        self.framing = framing;
+        self.decode = decode;
+        self
+    }
+
+    /// Sets whether breaks and parity and framing errors are recorded at the
+    /// bytes they happened on. Only supported on Linux.
+    pub fn mark_errors(mut self, mark_errors: bool) -> Self {
+        self.mark_errors = mark_errors;
+        self
+    }
+
+    /// Sets which side of a tapped link the port listens to.
+    pub fn direction(mut self, direction: Direction) -> Self {
+        self.direction = direction;
+        self
+    }
+
+    /// Sets the GPIO pins attached to the port, its RI and CD outputs, and
+    /// control lines read from GPIO inputs. Only supported on Linux.
+    pub fn gpio_pins(mut self, gpio_pins: GpioPins) -> Self {
+        self.gpio_pins = gpio_pins;
+        self
+    }
+
+    /// The frame gap, worked out from the character time if given in characters.
+    fn gap(&self) -> Duration {
+        match self.gap_chars {
+            Some(gap_chars) => char_time(gap_chars, self.baud_rate, self.parity, self.stopbits),
+            None => self.frame_gap,
+        }
+    }
+
+    /// Opens the port and starts the capture.
+    ///
+    /// Fails if the data is to be encapsulated for a datalink type that
+    /// `datalink::get_encapsulated_data` doesn't support.
+    pub fn open(self) -> io::Result<Capture> {
+        if self.encap_mode == EncapsulationMode::DatalinkType && !datalink::SUPPORTED_DATALINKS.contains(&self.datalink) {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, format!("Unsupported datalink type: {:?}", self.datalink)));
+        }
+        let mut capture = Capture::new(&self)?;
+        capture.splitter = FrameSplitter::new(self.framing.framer(self.decode, MAX_PACKET_SIZE), capture.char_delta(1));
+        capture.direction = self.direction;
+        if self.mark_errors {
+            capture.mark_errors()?;
+        }
+        Ok(capture)
+    }
+
+    /// Opens the port for an async capture, which must be done within a tokio runtime.
+    ///
+    /// Async captures only record data, control lines aren't watched and
+    /// errors aren't marked, so `mark_errors` and `gpio_pins` can't be used with them.
+    #[cfg(feature = "tokio")]
+    pub fn open_async(self) -> io::Result<AsyncCapture> {
+        if self.mark_errors {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "errors can't be marked in an async capture"));
+        }
+        if !self.gpio_pins.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "GPIO pins can't be attached in an async capture"));
+        }
+        let frame_gap = self.gap();
+        let port = tokio_serial::SerialStream::open(&port_builder(&self.port_name, self.baud_rate, self.parity, self.stopbits))?;
+        let char_time = char_delta(1, self.baud_rate, self.parity, self.stopbits);
+        let splitter = FrameSplitter::new(self.framing.framer(self.decode, MAX_PACKET_SIZE), char_time);
+        Ok(AsyncCapture::new(port, splitter, frame_gap, char_time, self.direction))
+    }
+}
+
+/// Splices two ports into a link, each forwarding what it receives to the
+/// other, with the control lines of each reflected on the other's outputs.
+/// `to_dce` routes the DTE side's lines to the DCE side's outputs, and
+/// `to_dte` the other way.
+///
+/// Both ports are captured as usual, so the bridge runs as they are read.
+pub fn bridge(dte: &mut Capture, dce: &mut Capture, to_dce: LineRouter, to_dte: LineRouter) -> io::Result<()> {
+    let dce_port = dce.try_clone_port()?;
+    let dte_port = dte.try_clone_port()?;
+    dte.forward_to(dce_port, to_dce)?;
+    dce.forward_to(dte_port, to_dte)
+}
+
+/// Describes a bridged link, made of the ports on each side, for the capture file header.
+pub fn bridge_interface_info(dte: &Capture, dce: &Capture) -> InterfaceInfo {
+    let mut interface = dte.interface_info();
+    interface.name = format!("{}+{}", dte.bus_name, dce.bus_name);
+    interface.description = format!("Bridge of DTE {} and DCE {}, {}", dte.bus_name, dce.bus_name, interface.description);
+    interface
+}
+
+/// Describes a tapped link, made of the ports hearing each side, for the capture file header.
+pub fn tap_interface_info(dte: &Capture, dce: &Capture) -> InterfaceInfo {
+    let mut interface = dte.interface_info();
+    interface.name = format!("{}+{}", dte.bus_name, dce.bus_name);
+    interface.description = format!("Tap of DTE {} and DCE {}, {}", dte.bus_name, dce.bus_name, interface.description);
+    interface
+}
+
+/// Totals of what was captured.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CaptureStats {
+    pub packets: u64,
+    pub bytes: u64,
+    pub control_line_events: u64,
+    pub uart_events: u64,   // Breaks and UART errors
+}
+
+impl CaptureStats {
+    fn count(&mut self, packet: &CapturedPacket) {
+        match packet.kind {
+            EventKind::Data => {
+                self.packets += 1;
+                self.bytes += packet.captured_len as u64;
+            },
+            EventKind::ControlLine(_) => self.control_line_events += 1,
+            _ => self.uart_events += 1,
+        }
+    }
+}
+
+/// Optional limits which end the capture.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StopConditions {
+    pub duration: Option<Duration>,
+    pub packet_count: Option<u64>,
+    pub byte_count: Option<u64>,
+}
+
+impl StopConditions {
+    fn reached(&self, stats: &CaptureStats, elapsed: Duration) -> bool {
+        self.duration.is_some_and(|duration| elapsed >= duration) || self.count_reached(stats)
+    }
+
+    fn count_reached(&self, stats: &CaptureStats) -> bool {
+        self.packet_count.is_some_and(|count| stats.packets >= count)
+            || self.byte_count.is_some_and(|count| stats.bytes >= count)
+    }
+}
+
+/// Captures data from all of the serial ports and writes it to a single sink
+///
+/// Each port is captured on its own thread. With several ports, packets
+/// are held back until no port is still reading an older one, so they are
+/// written in timestamp order. A single port's are written straight away.
+/// Runs until a port fails, a stop condition is reached or `stop` is set,
+/// then waits for the port threads to finish, so the ports are closed when
+/// it returns, writes any packets still held back and finishes the sink.
+///
+/// # Arguments
+///
+/// * `buses` - The ports to capture, each paired with the index of the interface it is written as
+/// * `sink` - Where the captured packets are written
+/// * `limits` - Conditions which end the capture
+/// * `stop` - Set, for instance by a signal handler, to end the capture
+pub fn run<S: Sink>(buses: Vec<(u32, Capture)>, mut sink: S, limits: &StopConditions, stop: Arc<AtomicBool>) -> io::Result<CaptureStats> {
+    let start_time = Utc::now();
+    let mut stats = CaptureStats::default();
+
+    let merge = buses.len() > 1;
+    let longest_packet = buses.iter().map(|(_, bus)| bus.longest_packet()).max().unwrap_or_default();
+    let unfinished: Vec<Unfinished> = if merge {
+        buses.iter().map(|(_, bus)| bus.unfinished.clone()).collect()
+    } else {
+        Vec::new()
+    };
+    let (sender, receiver) = mpsc::channel();
+    let threads: Vec<thread::JoinHandle<()>> = buses.into_iter().map(|(interface_id, mut bus)| {
+        let sender = sender.clone();
+        let stop = stop.clone();
+        thread::spawn(move || bus.send_packets(interface_id, sender, &stop))
+    }).collect();
+    drop(sender);
+
+    let mut merger = PacketMerger::new(if merge { TimeDelta::milliseconds(MERGE_SLACK_MS) } else { TimeDelta::zero() });
+    let oldest_unfinished = |now: DateTime<Utc>| -> Option<DateTime<Utc>> {
+        let since = unfinished.iter().filter_map(Unfinished::since).min()?;
+        Some(now.checked_sub_signed(longest_packet).map_or(since, |limit| since.max(limit)))
+    };
+    let write_packets = |sink: &mut S, stats: &mut CaptureStats, packets: Vec<CapturedPacket>| -> io::Result<()> {
+        for packet in packets {
+            if limits.count_reached(stats) {
+                // Packets past the packet or byte count aren't written
+                break;
+            }
+            sink.write(&packet)?;
+            stats.count(&packet);
+        }
+        Ok(())
+    };
+    let result = loop {
+        match receiver.recv_timeout(Duration::from_millis(MERGE_SLACK_MS as u64)) {
+            Ok(Ok(packet)) => merger.push(packet),
+            Ok(Err(e)) => break Err(e),
+            Err(mpsc::RecvTimeoutError::Timeout) => {},
+            Err(mpsc::RecvTimeoutError::Disconnected) => break Ok(()),
+        }
+        let now = Utc::now();
+        if let Err(e) = write_packets(&mut sink, &mut stats, merger.pop_ready(now, oldest_unfinished(now))) {
+            break Err(e);
+        }
+        let elapsed = (Utc::now() - start_time).to_std().unwrap_or_default();
+        if stop.load(Ordering::Relaxed) || limits.reached(&stats, elapsed) {
+            break Ok(());
+        }
+        // Ticks come even when nothing is captured, for time based file rotation
+        if let Err(e) = sink.tick(Utc::now()) {
+            break Err(e);
+        }
+    };
+    stop.store(true, Ordering::Relaxed);
+    // Each thread stops by the end of its current read, dropping its port
+    let joined: Vec<thread::Result<()>> = threads.into_iter().map(thread::JoinHandle::join).collect();
+    let panicked = joined.iter().any(Result::is_err);
+    let result = match result {
+        Ok(()) if panicked => Err(io::Error::other("a capture thread panicked")),
+        result => result,
+    };
+    // Packets already received are written, even if a port failed
+    while let Ok(Ok(packet)) = receiver.try_recv() {
+        merger.push(packet);
+    }
+    write_packets(&mut sink, &mut stats, merger.drain())?;
+    sink.finish()?;
+    result.map(|_| stats)
+}
+
+
+
@@ -1,12 +1,11 @@
-use chrono::{Timelike, Utc};
+use chrono::{DateTime, Utc};
 use pcap_file::DataLink;
 use clap::error::Error;
 use std::sync::OnceLock;
 use std::collections::HashMap;
 
-use crate::datalink;
 use crate::portinfo::PortControlLines;
-use crate::state;
+use crate::state::{self, Direction, EventKind};
 
 const MAX_DATALINK_TYPES: u32 = 512;
 
@@ -44,12 +43,21 @@ pub fn get_datalink_types() -> &'static HashMap<String, DataLink> {
 pub fn parse_datalink(datalink_str:  &str) -> Result<DataLink, Error> {
     let datalink_types = get_datalink_types();
     if let Some(datalink) = datalink_types.get(&datalink_str.to_uppercase()) {
-        Ok(datalink.clone())
+        Ok(*datalink)
     } else {
         Err(Error::raw(clap::error::ErrorKind::InvalidValue,format!("Unknown datalink type: {}", datalink_str)))
     }
 }
 
+/// The datalink types `get_encapsulated_data` can encapsulate data for.
+pub const SUPPORTED_DATALINKS: [DataLink; 23] = [
+    DataLink::USER0, DataLink::USER1, DataLink::USER2, DataLink::USER3,
+    DataLink::USER4, DataLink::USER5, DataLink::USER6, DataLink::USER7,
+    DataLink::USER8, DataLink::USER9, DataLink::USER10, DataLink::USER11,
+    DataLink::USER12, DataLink::USER13, DataLink::USER14, DataLink::USER15,
+    DataLink::RAW, DataLink::PPP, DataLink::PPP_HDLC, DataLink::C_HDLC,
+    DataLink::PPP_WITH_DIR, DataLink::C_HDLC_WITH_DIR, DataLink::RTAC_SERIAL,
+];
 
 fn raw_encapsulate(data: &[u8]) -> Vec<u8> {
     // This function is the null encapsulation function.
@@ -59,28 +67,171 @@ fn raw_encapsulate(data: &[u8]) -> Vec<u8> {
     data.to_vec()
 }
 
-fn rtac_encapsulate(new_state: state::SerialEvent, _bus_name: &str) -> Vec<u8> {
+// RTAC_SERIAL event types
+const RTAC_EVENT_STATUS_CHANGE: u8 = 0x00;
+const RTAC_EVENT_DATA_TX_START: u8 = 0x01;
+const RTAC_EVENT_DATA_RX_START: u8 = 0x02;
+const RTAC_EVENT_CAPTURE_DATA_LOST: u8 = 0x05;
+const RTAC_EVENT_FRAMING_ERROR: u8 = 0x07;
+const RTAC_EVENT_PARITY_ERROR: u8 = 0x08;
+const RTAC_EVENT_BREAK: u8 = 0x09;
+
+fn rtac_event_type(new_state: &state::SerialEvent) -> u8 {
+    match new_state.kind {
+        EventKind::Data => match new_state.direction {
+            Direction::Inbound => RTAC_EVENT_DATA_RX_START,
+            Direction::Outbound | Direction::Unknown => RTAC_EVENT_DATA_TX_START,
+        },
+        EventKind::ControlLine(_) => RTAC_EVENT_STATUS_CHANGE,
+        EventKind::Break => RTAC_EVENT_BREAK,
+        EventKind::FramingError => RTAC_EVENT_FRAMING_ERROR,
+        EventKind::ParityError => RTAC_EVENT_PARITY_ERROR,
+        EventKind::Overrun => RTAC_EVENT_CAPTURE_DATA_LOST,
+    }
+}
+
+/// Whether the datalink type can record control line changes and UART
+/// errors, as well as data.
+pub fn records_line_events(datalink: &DataLink) -> bool {
+    matches!(datalink, DataLink::RTAC_SERIAL)
+}
+
+/// Whether the datalink type records which side of a link sent the data.
+pub fn records_direction(datalink: &DataLink) -> bool {
+    matches!(datalink, DataLink::RTAC_SERIAL | DataLink::PPP_WITH_DIR | DataLink::C_HDLC_WITH_DIR)
+}
+
+// RTAC_SERIAL control line state bits
+const RTAC_LINE_CTS: u8 = 0x01;
+const RTAC_LINE_CD: u8 = 0x02;
+const RTAC_LINE_DSR: u8 = 0x04;
+const RTAC_LINE_RTS: u8 = 0x08;
+const RTAC_LINE_DTR: u8 = 0x10;
+const RTAC_LINE_RI: u8 = 0x20;
+
+pub const RTAC_HEADER_LEN: usize = 12;
+
+/// A LINKTYPE_RTAC_SERIAL record.
+/// docs: https://www.tcpdump.org/linktypes/LINKTYPE_RTAC_SERIAL.html
+///
+/// The 12 byte header is, all fields big-endian:
+///
+/// * 4 bytes - timestamp, seconds since the epoch
+/// * 4 bytes - timestamp, microseconds within the second
+/// * 1 byte - event type
+/// * 1 byte - UART control line state
+/// * 2 bytes - footer, the UART (port) identifier
+///
+/// followed by the payload.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RtacRecord {
+    pub timestamp: DateTime<Utc>,
+    pub event_type: u8,
+    pub control_lines: PortControlLines,
+    pub port_id: u16,
+    pub data: Vec<u8>,
+}
+
+impl RtacRecord {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut encapsulated_data = Vec::with_capacity(RTAC_HEADER_LEN + self.data.len());
+        encapsulated_data.extend_from_slice(&(self.timestamp.timestamp() as u32).to_be_bytes()); // 4 bytes for seconds
+        encapsulated_data.extend_from_slice(&self.timestamp.timestamp_subsec_micros().to_be_bytes()); // 4 bytes for microseconds
+        encapsulated_data.push(self.event_type); // 1 byte for event type
+        encapsulated_data.push(rtac_line_bits(&self.control_lines)); // 1 byte for port control lines
+        encapsulated_data.extend_from_slice(&self.port_id.to_be_bytes()); // 2 byte footer, the port identifier
+        encapsulated_data.extend_from_slice(&self.data);
+        encapsulated_data
+    }
+
+    pub fn decode(encapsulated_data: &[u8]) -> Result<Self, String> {
+        if encapsulated_data.len() < RTAC_HEADER_LEN {
+            return Err(format!("RTAC_SERIAL record too short: {} bytes", encapsulated_data.len()));
+        }
+        let field = |offset: usize| -> [u8; 4] {
+            [encapsulated_data[offset], encapsulated_data[offset + 1], encapsulated_data[offset + 2], encapsulated_data[offset + 3]]
+        };
+        let seconds = u32::from_be_bytes(field(0));
+        let micros = u32::from_be_bytes(field(4));
+        if micros >= 1_000_000 {
+            return Err(format!("RTAC_SERIAL microseconds out of range: {}", micros));
+        }
+        let timestamp = DateTime::from_timestamp(seconds as i64, micros * 1000)
+            .ok_or_else(|| format!("RTAC_SERIAL timestamp out of range: {}", seconds))?;
+        Ok(RtacRecord {
+            timestamp,
+            event_type: encapsulated_data[8],
+            control_lines: rtac_lines_from_bits(encapsulated_data[9]),
+            port_id: u16::from_be_bytes([encapsulated_data[10], encapsulated_data[11]]),
+            data: encapsulated_data[RTAC_HEADER_LEN..].to_vec(),
+        })
+    }
+
+    /// The direction of a data record, from its event type.
+    pub fn direction(&self) -> Direction {
+        match self.event_type {
+            RTAC_EVENT_DATA_TX_START => Direction::Outbound,
+            RTAC_EVENT_DATA_RX_START => Direction::Inbound,
+            _ => Direction::Unknown,
+        }
+    }
+}
+
+fn rtac_line_bits(control_lines: &PortControlLines) -> u8 {
+    let mut flags = 0u8;
+    if control_lines.cts { flags |= RTAC_LINE_CTS; }
+    if control_lines.cd { flags |= RTAC_LINE_CD; }
+    if control_lines.dsr { flags |= RTAC_LINE_DSR; }
+    if control_lines.rts { flags |= RTAC_LINE_RTS; }
+    if control_lines.dtr { flags |= RTAC_LINE_DTR; }
+    if control_lines.ri { flags |= RTAC_LINE_RI; }
+    flags
+}
+
+fn rtac_lines_from_bits(flags: u8) -> PortControlLines {
+    PortControlLines {
+        cts: flags & RTAC_LINE_CTS != 0,
+        cd: flags & RTAC_LINE_CD != 0,
+        dsr: flags & RTAC_LINE_DSR != 0,
+        rts: flags & RTAC_LINE_RTS != 0,
+        dtr: flags & RTAC_LINE_DTR != 0,
+        ri: flags & RTAC_LINE_RI != 0,
+    }
+}
+
+/// Derives the RTAC_SERIAL port identifier from the port name.
+/// This is the number the name ends in, e.g. 3 for `/dev/ttyUSB3` or `COM3`,
+/// or 0 if it doesn't end in a number.
+pub fn rtac_port_id(bus_name: &str) -> u16 {
+    let digits_start = bus_name.trim_end_matches(|c: char| c.is_ascii_digit()).len();
+    bus_name[digits_start..].parse().unwrap_or(0)
+}
+
+fn rtac_encapsulate(new_state: state::SerialEvent, bus_name: &str) -> Vec<u8> {
     // This function encapsulates the data for the RTAC_SERIAL datalink type.
-    // It adds the timestamp, bus name, and port control lines to the data.
-    // docs: https://www.tcpdump.org/linktypes/LINKTYPE_RTAC_SERIAL.html
-
-    let mut encapsulated_data = Vec::new();
-    let event_type :u8 = 0x01; // Event type for RTAC_SERIAL
-    let timestamp = &new_state.timestamp;
-    encapsulated_data.extend_from_slice(&timestamp.timestamp().to_le_bytes()[..4]); // 4 bytes for seconds
-    encapsulated_data.extend_from_slice(&timestamp.timestamp_micros().to_le_bytes()[..4]); // 4 bytes for microseconds
-    encapsulated_data.extend_from_slice(&event_type.to_le_bytes()); // 1 byte for event type
-    encapsulated_data.extend_from_slice(&(
-        { let mut flags = 0u8;
-        if new_state.control_lines.cts { flags |= 0x01; } // CTS
-        if new_state.control_lines.cd { flags |= 0x02; } // CD
-        if new_state.control_lines.dsr { flags |= 0x04; } // DSR
-        if new_state.control_lines.rts { flags |= 0x08; } // RTS
-        if new_state.control_lines.dtr { flags |= 0x10 } // DTR
-        if new_state.control_lines.ri { flags |= 0x20; } // RI
-        flags
-    }).to_le_bytes()); // 1 byte for port control lines .
-    encapsulated_data.extend_from_slice(&[0x00, 0x00]); //  2 byte footer (reserved for future use)
+    // It adds the timestamp, event type, port control lines and port identifier to the data.
+    RtacRecord {
+        timestamp: new_state.timestamp,
+        event_type: rtac_event_type(&new_state),
+        control_lines: new_state.control_lines,
+        port_id: rtac_port_id(bus_name),
+        data: new_state.data,
+    }.encode()
+}
+
+fn with_dir_encapsulate(new_state: &state::SerialEvent) -> Vec<u8> {
+    // This function encapsulates the data for the PPP_WITH_DIR and C_HDLC_WITH_DIR
+    // datalink types, which prefix the frame with a direction byte.
+    // docs: https://www.tcpdump.org/linktypes/LINKTYPE_PPP_WITH_DIR.html
+    //
+    // 0x00 is received by the capturing host, 0x01 sent by it. We take the DTE
+    // to be the capturing host.
+    let direction: u8 = match new_state.direction {
+        Direction::Outbound => 0x01,
+        Direction::Inbound | Direction::Unknown => 0x00,
+    };
+    let mut encapsulated_data = Vec::with_capacity(new_state.data.len() + 1);
+    encapsulated_data.push(direction);
     encapsulated_data.extend_from_slice(&new_state.data);
     encapsulated_data
 }
@@ -93,6 +244,10 @@ pub fn get_encapsulated_data(new_state: state::SerialEvent, bus_name: &str, data
         DataLink::USER9 | DataLink::USER10 | DataLink::USER11 |
         DataLink::USER12 | DataLink::USER13 | DataLink::USER14 |
         DataLink::USER15 | DataLink::RAW => Ok(raw_encapsulate(&new_state.data)),
+        // HDLC framed PPP and Cisco HDLC, the framer has already removed
+        // the flags, escaping and FCS.
+        DataLink::PPP | DataLink::PPP_HDLC | DataLink::C_HDLC => Ok(raw_encapsulate(&new_state.data)),
+        DataLink::PPP_WITH_DIR | DataLink::C_HDLC_WITH_DIR => Ok(with_dir_encapsulate(&new_state)),
         DataLink::RTAC_SERIAL => {
             // RTAC_SERIAL is a special case where we encapsulate the data
             // with the timestamp and bus name and port control lines.
@@ -101,3 +256,139 @@ pub fn get_encapsulated_data(new_state: state::SerialEvent, bus_name: &str, data
         _ => Err(format!("Unsupported datalink type: {:?}", datalink)),
     }
 }
+
+/// A captured record with its encapsulation removed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Decapsulated {
+    pub data: Vec<u8>,                              // The serial data, empty for events without any
+    pub control_lines: Option<PortControlLines>,    // The control line state, if the datalink type records it
+    pub direction: Direction,                       // Unknown unless the datalink type records it
+}
+
+/// Removes the encapsulation added by `get_encapsulated_data`, for replay.
+///
+/// Only the datalink types which keep the serial data as it was received are
+/// supported. The HDLC based types have had their framing removed.
+pub fn get_decapsulated_data(encapsulated_data: &[u8], datalink: &DataLink) -> Result<Decapsulated, String> {
+    match datalink {
+        DataLink::USER0 | DataLink::USER1 | DataLink::USER2 |
+        DataLink::USER3 | DataLink::USER4 | DataLink::USER5 |
+        DataLink::USER6 | DataLink::USER7 | DataLink::USER8 |
+        DataLink::USER9 | DataLink::USER10 | DataLink::USER11 |
+        DataLink::USER12 | DataLink::USER13 | DataLink::USER14 |
+        DataLink::USER15 | DataLink::RAW => Ok(Decapsulated {
+            data: encapsulated_data.to_vec(),
+            control_lines: None,
+            direction: Direction::Unknown,
+        }),
+        DataLink::RTAC_SERIAL => {
+            let record = RtacRecord::decode(encapsulated_data)?;
+            let direction = record.direction();
+            Ok(Decapsulated { data: record.data, control_lines: Some(record.control_lines), direction })
+        },
+        _ => Err(format!("Unsupported datalink type for replay: {:?}", datalink)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    // 2024-01-02T03:04:05.123456Z, received data on port 3 with CTS and DTR asserted.
+    const GOLDEN: [u8; 14] = [
+        0x65, 0x93, 0x7d, 0x25, // seconds
+        0x00, 0x01, 0xe2, 0x40, // microseconds
+        0x02, // event type, data received
+        0x11, // control lines, CTS and DTR
+        0x00, 0x03, // footer, port 3
+        0xaa, 0xbb, // payload
+    ];
+
+    fn golden_record() -> RtacRecord {
+        RtacRecord {
+            timestamp: Utc.with_ymd_and_hms(2024, 1, 2, 3, 4, 5).unwrap() + chrono::TimeDelta::microseconds(123_456),
+            event_type: RTAC_EVENT_DATA_RX_START,
+            control_lines: PortControlLines { cts: true, dtr: true, ..Default::default() },
+            port_id: 3,
+            data: vec![0xaa, 0xbb],
+        }
+    }
+
+    #[test]
+    fn encodes_golden_vector() {
+        assert_eq!(golden_record().encode(), GOLDEN);
+    }
+
+    #[test]
+    fn decodes_golden_vector() {
+        assert_eq!(RtacRecord::decode(&GOLDEN), Ok(golden_record()));
+    }
+
+    #[test]
+    fn round_trips_all_control_lines() {
+        let record = RtacRecord {
+            event_type: RTAC_EVENT_STATUS_CHANGE,
+            control_lines: PortControlLines { cts: true, dsr: true, cd: true, ri: true, rts: true, dtr: true },
+            data: vec![],
+            ..golden_record()
+        };
+        let encoded = record.encode();
+        assert_eq!(encoded[9], 0x3f);
+        assert_eq!(RtacRecord::decode(&encoded), Ok(record));
+    }
+
+    #[test]
+    fn microseconds_are_within_the_second() {
+        let mut event = state::SerialEvent::new(vec![0x55], 1, PortControlLines::default(), Direction::Outbound);
+        event.timestamp = golden_record().timestamp;
+        let encoded = get_encapsulated_data(event, "/dev/ttyUSB3", &DataLink::RTAC_SERIAL).unwrap();
+        assert_eq!(encoded[..12], [0x65, 0x93, 0x7d, 0x25, 0x00, 0x01, 0xe2, 0x40, 0x01, 0x00, 0x00, 0x03]);
+        assert_eq!(encoded[12..], [0x55]);
+    }
+
+    #[test]
+    fn port_id_from_bus_name() {
+        assert_eq!(rtac_port_id("/dev/ttyUSB3"), 3);
+        assert_eq!(rtac_port_id("COM12"), 12);
+        assert_eq!(rtac_port_id("/dev/serial0"), 0);
+        assert_eq!(rtac_port_id("/dev/ttyAMA"), 0);
+        assert_eq!(rtac_port_id("dte+dce"), 0);
+        assert_eq!(rtac_port_id("/dev/tty99999"), 0);
+    }
+
+    #[test]
+    fn rejects_short_and_invalid_records() {
+        assert!(RtacRecord::decode(&GOLDEN[..11]).is_err());
+        let mut bad_micros = GOLDEN;
+        bad_micros[4..8].copy_from_slice(&1_000_000u32.to_be_bytes());
+        assert!(RtacRecord::decode(&bad_micros).is_err());
+    }
+
+    #[test]
+    fn decapsulates_rtac_records() {
+        let decapsulated = get_decapsulated_data(&GOLDEN, &DataLink::RTAC_SERIAL).unwrap();
+        assert_eq!(decapsulated.data, [0xaa, 0xbb]);
+        assert_eq!(decapsulated.control_lines, Some(golden_record().control_lines));
+        assert_eq!(decapsulated.direction, Direction::Inbound);
+    }
+
+    #[test]
+    fn rtac_records_keep_the_data_direction() {
+        for (event_type, direction) in [
+            (RTAC_EVENT_DATA_TX_START, Direction::Outbound),
+            (RTAC_EVENT_DATA_RX_START, Direction::Inbound),
+            (RTAC_EVENT_STATUS_CHANGE, Direction::Unknown),
+        ] {
+            let record = RtacRecord { event_type, ..golden_record() };
+            assert_eq!(RtacRecord::decode(&record.encode()).unwrap().direction(), direction);
+        }
+    }
+
+    #[test]
+    fn decapsulates_user_records_as_is() {
+        let decapsulated = get_decapsulated_data(&[0x01, 0x02], &DataLink::USER3).unwrap();
+        assert_eq!(decapsulated, Decapsulated { data: vec![0x01, 0x02], control_lines: None, direction: Direction::Unknown });
+        assert!(get_decapsulated_data(&[0x01], &DataLink::PPP).is_err());
+    }
+}
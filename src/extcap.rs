@@ -0,0 +1,130 @@
+//! Wireshark extcap interface.
+//!
+//! Wireshark runs extcap programs to list their interfaces and the options
+//! each takes, then to capture into a fifo it reads from. This lets serial
+//! ports be captured straight from Wireshark's interface list.
+//!
+//! docs: https://www.wireshark.org/docs/wsdg_html_chunked/ChCaptureExtcap.html
+
+use std::fmt::Write;
+use std::io;
+
+use pcap_file::DataLink;
+
+use crate::datalink::SUPPORTED_DATALINKS;
+
+const HELP_URL: &str = "https://github.com/rgammans/serialpcap-rs";
+
+/// Lists the serial ports as extcap interfaces, for `--extcap-interfaces`.
+pub fn interfaces() -> io::Result<String> {
+    Ok(format_interfaces(&serialport::available_ports()?))
+}
+
+fn format_interfaces(ports: &[serialport::SerialPortInfo]) -> String {
+    let mut out = format!("extcap {{version={}}}{{help={}}}\n", env!("CARGO_PKG_VERSION"), HELP_URL);
+    for port in ports {
+        let display = match &port.port_type {
+            serialport::SerialPortType::UsbPort(usb) => match &usb.product {
+                Some(product) => format!("Serial port {} ({})", port.port_name, product),
+                None => format!("Serial port {} (USB {:04x}:{:04x})", port.port_name, usb.vid, usb.pid),
+            },
+            _ => format!("Serial port {}", port.port_name),
+        };
+        writeln!(out, "interface {{value={}}}{{display={}}}", port.port_name, display).unwrap();
+    }
+    out
+}
+
+/// Describes the datalink type captured, for `--extcap-dlts`.
+pub fn dlts(datalink: DataLink) -> String {
+    format!("dlt {{number={}}}{{name={:?}}}{{display=Serial data ({:?})}}\n", u32::from(datalink), datalink, datalink)
+}
+
+/// Describes the capture options, for `--extcap-config`.
+///
+/// Each option is passed back on the command line, so `call` must name one of our arguments.
+pub fn config() -> String {
+    let mut out = String::new();
+    writeln!(out, "arg {{number=0}}{{call=--baud}}{{display=Baud rate}}{{type=unsigned}}{{default=9600}}{{tooltip=Serial port speed}}").unwrap();
+
+    writeln!(out, "arg {{number=1}}{{call=--parity}}{{display=Parity}}{{type=selector}}{{tooltip=Serial port parity}}").unwrap();
+    for (value, display) in [("n", "None"), ("e", "Even"), ("o", "Odd")] {
+        writeln!(out, "value {{arg=1}}{{value={}}}{{display={}}}{{default={}}}", value, display, value == "n").unwrap();
+    }
+
+    writeln!(out, "arg {{number=2}}{{call=--stopbits}}{{display=Stop bits}}{{type=selector}}{{tooltip=Serial port stop bits}}").unwrap();
+    for value in ["1", "2"] {
+        writeln!(out, "value {{arg=2}}{{value={}}}{{display={}}}{{default={}}}", value, value, value == "1").unwrap();
+    }
+
+    writeln!(out, "arg {{number=3}}{{call=--gap}}{{display=Frame gap (ms)}}{{type=unsigned}}{{default=10}}{{tooltip=Inter frame gap in milliseconds}}").unwrap();
+
+    writeln!(out, "arg {{number=4}}{{call=--datalinktype}}{{display=Datalink type}}{{type=selector}}{{tooltip=Datalink type the captured data is written as}}").unwrap();
+    for datalink in SUPPORTED_DATALINKS {
+        writeln!(out, "value {{arg=4}}{{value={:?}}}{{display={:?}}}{{default={}}}", datalink, datalink, datalink == DataLink::USER0).unwrap();
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serialport::{SerialPortInfo, SerialPortType, UsbPortInfo};
+
+    fn usb_port(port_name: &str, product: Option<&str>) -> SerialPortInfo {
+        SerialPortInfo {
+            port_name: port_name.to_string(),
+            port_type: SerialPortType::UsbPort(UsbPortInfo {
+                vid: 0x0403,
+                pid: 0x6001,
+                serial_number: None,
+                manufacturer: None,
+                product: product.map(str::to_string),
+            }),
+        }
+    }
+
+    #[test]
+    fn interfaces_output() {
+        let ports = [
+            usb_port("/dev/ttyUSB0", Some("FT232R USB UART")),
+            usb_port("/dev/ttyUSB1", None),
+            SerialPortInfo { port_name: "/dev/ttyS0".to_string(), port_type: SerialPortType::Unknown },
+        ];
+        assert_eq!(format_interfaces(&ports), format!(concat!(
+            "extcap {{version={}}}{{help=https://github.com/rgammans/serialpcap-rs}}\n",
+            "interface {{value=/dev/ttyUSB0}}{{display=Serial port /dev/ttyUSB0 (FT232R USB UART)}}\n",
+            "interface {{value=/dev/ttyUSB1}}{{display=Serial port /dev/ttyUSB1 (USB 0403:6001)}}\n",
+            "interface {{value=/dev/ttyS0}}{{display=Serial port /dev/ttyS0}}\n",
+        ), env!("CARGO_PKG_VERSION")));
+    }
+
+    #[test]
+    fn dlts_output() {
+        assert_eq!(dlts(DataLink::USER0), "dlt {number=147}{name=USER0}{display=Serial data (USER0)}\n");
+        assert_eq!(dlts(DataLink::RTAC_SERIAL), "dlt {number=250}{name=RTAC_SERIAL}{display=Serial data (RTAC_SERIAL)}\n");
+    }
+
+    #[test]
+    fn config_output() {
+        let config = config();
+        let lines: Vec<&str> = config.lines().collect();
+        assert_eq!(lines[..10], [
+            "arg {number=0}{call=--baud}{display=Baud rate}{type=unsigned}{default=9600}{tooltip=Serial port speed}",
+            "arg {number=1}{call=--parity}{display=Parity}{type=selector}{tooltip=Serial port parity}",
+            "value {arg=1}{value=n}{display=None}{default=true}",
+            "value {arg=1}{value=e}{display=Even}{default=false}",
+            "value {arg=1}{value=o}{display=Odd}{default=false}",
+            "arg {number=2}{call=--stopbits}{display=Stop bits}{type=selector}{tooltip=Serial port stop bits}",
+            "value {arg=2}{value=1}{display=1}{default=true}",
+            "value {arg=2}{value=2}{display=2}{default=false}",
+            "arg {number=3}{call=--gap}{display=Frame gap (ms)}{type=unsigned}{default=10}{tooltip=Inter frame gap in milliseconds}",
+            "arg {number=4}{call=--datalinktype}{display=Datalink type}{type=selector}{tooltip=Datalink type the captured data is written as}",
+        ]);
+        assert_eq!(lines[10], "value {arg=4}{value=USER0}{display=USER0}{default=true}");
+        assert_eq!(lines[11], "value {arg=4}{value=USER1}{display=USER1}{default=false}");
+        assert_eq!(lines.last(), Some(&"value {arg=4}{value=RTAC_SERIAL}{display=RTAC_SERIAL}{default=false}"));
+        assert_eq!(lines.len(), 10 + SUPPORTED_DATALINKS.len());
+        assert!(config.ends_with('\n'));
+    }
+}
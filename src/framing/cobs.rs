@@ -0,0 +1,81 @@
+//! Consistent Overhead Byte Stuffing decoding.
+
+pub const DELIMITER: u8 = 0x00;
+
+/// Decodes a COBS frame, without its zero delimiter.
+///
+/// Returns `None` if a code byte points past the end of the frame or the
+/// frame contains a zero.
+pub fn decode(frame: &[u8]) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(frame.len());
+    let mut index = 0;
+    while index < frame.len() {
+        let code = frame[index] as usize;
+        if code == 0 || index + code > frame.len() {
+            return None;
+        }
+        let block = &frame[index + 1..index + code];
+        if block.contains(&DELIMITER) {
+            return None;
+        }
+        decoded.extend_from_slice(block);
+        index += code;
+        // A full block (0xFF) carries no implied zero, nor does the last block.
+        if code < 0xFF && index < frame.len() {
+            decoded.push(0);
+        }
+    }
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(data: &[u8]) -> Vec<u8> {
+        let mut encoded = vec![0];
+        let mut code_index = 0;
+        for byte in data {
+            if *byte == 0 {
+                encoded[code_index] = (encoded.len() - code_index) as u8;
+                code_index = encoded.len();
+                encoded.push(0);
+            } else {
+                encoded.push(*byte);
+                if encoded.len() - code_index == 0xFF {
+                    encoded[code_index] = 0xFF;
+                    code_index = encoded.len();
+                    encoded.push(0);
+                }
+            }
+        }
+        encoded[code_index] = (encoded.len() - code_index) as u8;
+        encoded
+    }
+
+    #[test]
+    fn decodes_known_frames() {
+        assert_eq!(decode(&[0x01, 0x01]), Some(vec![0x00]));
+        assert_eq!(decode(&[0x03, 0x11, 0x22, 0x02, 0x33]), Some(vec![0x11, 0x22, 0x00, 0x33]));
+        assert_eq!(decode(&[0x01]), Some(vec![]));
+    }
+
+    #[test]
+    fn round_trips() {
+        let long: Vec<u8> = (1..=255).cycle().take(600).collect();
+        let cases: [&[u8]; 5] = [b"", &[0x00, 0x00], &[0x11, 0x00, 0x22, 0x00], &long[..254], &long];
+        for data in cases {
+            assert_eq!(decode(&encode(data)).as_deref(), Some(data), "{:02x?}", data);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_frames() {
+        // A zero inside a block
+        assert_eq!(decode(&[0x03, 0x11, 0x00]), None);
+        // A code byte of zero
+        assert_eq!(decode(&[0x00, 0x11]), None);
+        // A code byte pointing past the end of the frame
+        assert_eq!(decode(&[0x05, 0x11, 0x22]), None);
+    }
+}
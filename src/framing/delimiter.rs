@@ -0,0 +1,175 @@
+//! Delimiter based framing.
+//!
+//! Frames end at a terminator byte sequence, so pauses by the sender in the
+//! middle of a frame don't split it. Used for newline terminated text and
+//! for the SLIP and COBS byte stuffed encodings.
+
+use super::{cobs, slip, Frame, Framer};
+use crate::state::FrameError;
+
+/// How the bytes between delimiters are encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Encoding {
+    Plain,
+    Slip,
+    Cobs,
+}
+
+/// Splits the byte stream at each occurrence of a delimiter.
+///
+/// Emitted frames hold the wire bytes, including the delimiter, unless
+/// decoding is enabled in which case they hold the payload only. Frames that
+/// fail to decode are emitted as wire bytes flagged with an encoding error.
+pub struct DelimiterFramer {
+    delimiter: Vec<u8>,
+    encoding: Encoding,
+    decode: bool,
+    max_len: usize,
+    buffer: Vec<u8>,
+    skipped: usize,
+}
+
+impl DelimiterFramer {
+    /// Creates a framer for frames ending with `delimiter`.
+    pub fn new(delimiter: Vec<u8>, decode: bool, max_len: usize) -> Self {
+        Self::with_encoding(delimiter, Encoding::Plain, decode, max_len)
+    }
+
+    /// Creates a framer for SLIP encoded frames.
+    pub fn slip(decode: bool, max_len: usize) -> Self {
+        Self::with_encoding(vec![slip::END], Encoding::Slip, decode, max_len)
+    }
+
+    /// Creates a framer for COBS encoded frames.
+    pub fn cobs(decode: bool, max_len: usize) -> Self {
+        Self::with_encoding(vec![cobs::DELIMITER], Encoding::Cobs, decode, max_len)
+    }
+
+    fn with_encoding(delimiter: Vec<u8>, encoding: Encoding, decode: bool, max_len: usize) -> Self {
+        DelimiterFramer {
+            delimiter,
+            encoding,
+            decode,
+            max_len: max_len.max(1),
+            buffer: Vec::new(),
+            skipped: 0,
+        }
+    }
+
+    fn find_delimiter(&self) -> Option<usize> {
+        self.buffer.windows(self.delimiter.len()).position(|window| window == self.delimiter.as_slice())
+    }
+
+    /// Builds a frame from the wire bytes, `payload_len` of which precede the delimiter.
+    fn frame(&mut self, wire: Vec<u8>, payload_len: usize) -> Option<Frame> {
+        let payload = &wire[..payload_len];
+        if payload.is_empty() && self.encoding != Encoding::Plain {
+            // SLIP and COBS senders often put a delimiter both before and
+            // after each frame, there's no frame between them.
+            self.skipped += wire.len();
+            return None;
+        }
+        let wire_len = std::mem::take(&mut self.skipped) + wire.len();
+        if !self.decode {
+            return Some(Frame::new(wire).consumed(wire_len));
+        }
+        let decoded = match self.encoding {
+            Encoding::Plain => Some(payload.to_vec()),
+            Encoding::Slip => slip::decode(payload),
+            Encoding::Cobs => cobs::decode(payload),
+        };
+        Some(match decoded {
+            Some(data) => Frame::new(data),
+            None => Frame::with_error(FrameError::Encoding, wire),
+        }.consumed(wire_len))
+    }
+}
+
+impl Framer for DelimiterFramer {
+    fn push(&mut self, data: &[u8]) -> Vec<Frame> {
+        self.buffer.extend_from_slice(data);
+        let mut frames = Vec::new();
+        loop {
+            if let Some(position) = self.find_delimiter().filter(|position| position + self.delimiter.len() <= self.max_len) {
+                let wire: Vec<u8> = self.buffer.drain(..position + self.delimiter.len()).collect();
+                frames.extend(self.frame(wire, position));
+            } else if self.buffer.len() >= self.max_len {
+                // Too long to be a frame, split it as the gap based capture would.
+                let wire: Vec<u8> = self.buffer.drain(..self.max_len).collect();
+                let wire_len = std::mem::take(&mut self.skipped) + wire.len();
+                frames.push(match self.encoding {
+                    Encoding::Plain => Frame::new(wire),
+                    Encoding::Slip | Encoding::Cobs => Frame::with_error(FrameError::Encoding, wire),
+                }.consumed(wire_len));
+            } else {
+                break;
+            }
+        }
+        frames
+    }
+
+    fn idle(&mut self) -> Vec<Frame> {
+        // Frames only end at a delimiter, however long the sender pauses.
+        vec![]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delimiter_split_across_reads() {
+        let mut framer = DelimiterFramer::new(b"\r\n".to_vec(), false, 64);
+        assert!(framer.push(b"abc\r").is_empty());
+        assert_eq!(framer.push(b"\ndef\r\nxy"), [Frame::new(b"abc\r\n".to_vec()), Frame::new(b"def\r\n".to_vec())]);
+        assert!(framer.idle().is_empty());
+        assert_eq!(framer.push(b"\r\n"), [Frame::new(b"xy\r\n".to_vec())]);
+    }
+
+    #[test]
+    fn decoded_lines_drop_the_delimiter() {
+        let mut framer = DelimiterFramer::new(b"\n".to_vec(), true, 64);
+        assert_eq!(framer.push(b"one\n\ntwo\n"), [
+            Frame::new(b"one".to_vec()).consumed(4),
+            Frame::new(vec![]).consumed(1),
+            Frame::new(b"two".to_vec()).consumed(4),
+        ]);
+    }
+
+    #[test]
+    fn long_frames_are_split() {
+        let mut framer = DelimiterFramer::new(b"\n".to_vec(), false, 4);
+        assert_eq!(framer.push(b"abcdef\n"), [Frame::new(b"abcd".to_vec()), Frame::new(b"ef\n".to_vec())]);
+    }
+
+    #[test]
+    fn slip_frames_skip_empty_frames() {
+        let mut framer = DelimiterFramer::slip(true, 64);
+        let frames = framer.push(&[slip::END, 0x01, slip::ESC, slip::ESC_END, slip::END]);
+        assert_eq!(frames, [Frame::new(vec![0x01, slip::END]).consumed(5)]);
+    }
+
+    #[test]
+    fn slip_bad_escape_is_flagged() {
+        let mut framer = DelimiterFramer::slip(true, 64);
+        let wire = vec![0x01, slip::ESC, 0x41, slip::END];
+        assert_eq!(framer.push(&wire), [Frame::with_error(FrameError::Encoding, wire)]);
+    }
+
+    #[test]
+    fn cobs_frames_decode() {
+        let mut framer = DelimiterFramer::cobs(true, 64);
+        let frames = framer.push(&[0x03, 0x11, 0x22, 0x02, 0x33, 0x00, 0x03, 0x11, 0x00, 0x00]);
+        assert_eq!(frames, [
+            Frame::new(vec![0x11, 0x22, 0x00, 0x33]).consumed(6),
+            Frame::with_error(FrameError::Encoding, vec![0x03, 0x11, 0x00]),
+        ]);
+    }
+
+    #[test]
+    fn cobs_wire_bytes_when_not_decoding() {
+        let mut framer = DelimiterFramer::cobs(false, 64);
+        assert_eq!(framer.push(&[0x02, 0x11, 0x00]), [Frame::new(vec![0x02, 0x11, 0x00])]);
+    }
+}
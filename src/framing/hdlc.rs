@@ -0,0 +1,235 @@
+//! HDLC-like asynchronous framing (RFC 1662).
+//!
+//! Frames are delimited by 0x7E flags, with 0x7D escaping the following byte
+//! (XOR 0x20). The frame check sequence is validated and removed, leaving
+//! the address, control and information fields as expected by the PPP_HDLC
+//! and C_HDLC datalink types.
+
+use super::{Frame, Framer};
+use crate::state::FrameError;
+
+pub const FLAG: u8 = 0x7E;
+pub const ESCAPE: u8 = 0x7D;
+const ESCAPE_XOR: u8 = 0x20;
+
+const FCS16_INIT: u16 = 0xFFFF;
+const FCS16_GOOD: u16 = 0xF0B8;
+const FCS32_INIT: u32 = 0xFFFF_FFFF;
+const FCS32_GOOD: u32 = 0xDEBB_20E3;
+
+/// The frame check sequence appended to each frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fcs {
+    Fcs16,
+    Fcs32,
+}
+
+impl Fcs {
+    fn len(&self) -> usize {
+        match self {
+            Fcs::Fcs16 => 2,
+            Fcs::Fcs32 => 4,
+        }
+    }
+
+    /// Checks the FCS of a frame which still has its FCS attached.
+    fn check(&self, frame: &[u8]) -> bool {
+        match self {
+            Fcs::Fcs16 => fcs16(FCS16_INIT, frame) == FCS16_GOOD,
+            Fcs::Fcs32 => fcs32(FCS32_INIT, frame) == FCS32_GOOD,
+        }
+    }
+}
+
+/// Updates a 16 bit FCS (RFC 1662 appendix C.2).
+pub fn fcs16(mut fcs: u16, data: &[u8]) -> u16 {
+    for byte in data {
+        fcs ^= *byte as u16;
+        for _ in 0..8 {
+            fcs = if fcs & 1 != 0 { (fcs >> 1) ^ 0x8408 } else { fcs >> 1 };
+        }
+    }
+    fcs
+}
+
+/// Updates a 32 bit FCS (RFC 1662 appendix C.3).
+pub fn fcs32(mut fcs: u32, data: &[u8]) -> u32 {
+    for byte in data {
+        fcs ^= *byte as u32;
+        for _ in 0..8 {
+            fcs = if fcs & 1 != 0 { (fcs >> 1) ^ 0xEDB8_8320 } else { fcs >> 1 };
+        }
+    }
+    fcs
+}
+
+/// Un-stuffs HDLC-like frames and validates their FCS.
+///
+/// Frames are emitted without flags or FCS. Frames with a bad FCS are still
+/// emitted, flagged with a checksum error, and frames too short to hold an
+/// FCS, or ended by an abort sequence, are flagged with an encoding error.
+pub struct HdlcFramer {
+    fcs: Fcs,
+    max_len: usize,
+    buffer: Vec<u8>,
+    escaped: bool,
+    aborted: bool,
+    wire_len: usize,
+}
+
+impl HdlcFramer {
+    pub fn new(fcs: Fcs, max_len: usize) -> Self {
+        HdlcFramer {
+            fcs,
+            max_len: max_len.max(fcs.len() + 1),
+            buffer: Vec::new(),
+            escaped: false,
+            aborted: false,
+            wire_len: 0,
+        }
+    }
+
+    /// Ends the frame in the buffer at a flag.
+    fn end_frame(&mut self) -> Option<Frame> {
+        let mut data = std::mem::take(&mut self.buffer);
+        let aborted = self.escaped || self.aborted;
+        self.escaped = false;
+        self.aborted = false;
+        if data.is_empty() {
+            // Back to back flags, there's no frame between them.
+            return None;
+        }
+        let wire_len = std::mem::take(&mut self.wire_len);
+        if aborted || data.len() <= self.fcs.len() {
+            return Some(Frame::with_error(FrameError::Encoding, data).consumed(wire_len));
+        }
+        let good = self.fcs.check(&data);
+        data.truncate(data.len() - self.fcs.len());
+        Some(if good {
+            Frame::new(data)
+        } else {
+            Frame::with_error(FrameError::Checksum, data)
+        }.consumed(wire_len))
+    }
+}
+
+impl Framer for HdlcFramer {
+    fn push(&mut self, data: &[u8]) -> Vec<Frame> {
+        let mut frames = Vec::new();
+        for byte in data {
+            self.wire_len += 1;
+            match *byte {
+                FLAG => frames.extend(self.end_frame()),
+                ESCAPE if !self.escaped => self.escaped = true,
+                byte => {
+                    if self.escaped {
+                        self.buffer.push(byte ^ ESCAPE_XOR);
+                        self.escaped = false;
+                    } else {
+                        self.buffer.push(byte);
+                    }
+                    if self.buffer.len() >= self.max_len {
+                        // Too long to be a frame, emit what we have. The
+                        // rest, up to the next flag, is flagged too.
+                        frames.push(Frame::with_error(FrameError::Encoding, std::mem::take(&mut self.buffer))
+                            .consumed(std::mem::take(&mut self.wire_len)));
+                        self.aborted = true;
+                    }
+                },
+            }
+        }
+        frames
+    }
+
+    fn idle(&mut self) -> Vec<Frame> {
+        // Frames only end at a flag, however long the sender pauses.
+        vec![]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An LCP configure request with a flag and an escape byte in its data.
+    const PAYLOAD: [u8; 10] = [0xff, 0x03, 0xc0, 0x21, 0x01, 0x01, 0x00, 0x04, 0x7e, 0x7d];
+    const FCS16: [u8; 2] = [0x34, 0x69];
+    const FCS32: [u8; 4] = [0x88, 0xfd, 0x11, 0x46];
+
+    /// Byte stuffs a frame and wraps it in flags, escaping control characters as with the default ACCM.
+    fn wire(frame: &[u8]) -> Vec<u8> {
+        let mut wire = vec![FLAG];
+        for byte in frame {
+            if *byte == FLAG || *byte == ESCAPE || *byte < 0x20 {
+                wire.extend([ESCAPE, byte ^ ESCAPE_XOR]);
+            } else {
+                wire.push(*byte);
+            }
+        }
+        wire.push(FLAG);
+        wire
+    }
+
+    #[test]
+    fn fcs_check_values() {
+        assert_eq!(!fcs16(FCS16_INIT, b"123456789"), 0x906e);
+        assert_eq!(!fcs32(FCS32_INIT, b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn good_fcs16_frame() {
+        let wire = wire(&[&PAYLOAD[..], &FCS16].concat());
+        let frames = HdlcFramer::new(Fcs::Fcs16, 64).push(&wire);
+        assert_eq!(frames, [Frame::new(PAYLOAD.to_vec()).consumed(wire.len())]);
+    }
+
+    #[test]
+    fn good_fcs32_frame() {
+        let wire = wire(&[&PAYLOAD[..], &FCS32].concat());
+        let frames = HdlcFramer::new(Fcs::Fcs32, 64).push(&wire);
+        assert_eq!(frames, [Frame::new(PAYLOAD.to_vec()).consumed(wire.len())]);
+    }
+
+    #[test]
+    fn corrupted_fcs_is_flagged() {
+        for (fcs, trailer) in [(Fcs::Fcs16, &FCS16[..]), (Fcs::Fcs32, &FCS32[..])] {
+            let mut frame = [&PAYLOAD[..], trailer].concat();
+            *frame.last_mut().unwrap() ^= 0x01;
+            let wire = wire(&frame);
+            let frames = HdlcFramer::new(fcs, 64).push(&wire);
+            assert_eq!(frames, [Frame::with_error(FrameError::Checksum, PAYLOAD.to_vec()).consumed(wire.len())], "{:?}", fcs);
+        }
+    }
+
+    #[test]
+    fn unstuffs_across_reads() {
+        let wire = wire(&[&PAYLOAD[..], &FCS16].concat());
+        // Split between an escape and the byte it escapes
+        let split = wire.iter().position(|byte| *byte == ESCAPE).unwrap() + 1;
+        let mut framer = HdlcFramer::new(Fcs::Fcs16, 64);
+        assert!(framer.push(&wire[..split]).is_empty());
+        assert_eq!(framer.push(&wire[split..]), [Frame::new(PAYLOAD.to_vec()).consumed(wire.len())]);
+    }
+
+    #[test]
+    fn back_to_back_flags_are_not_frames() {
+        let wire = [&[FLAG, FLAG][..], &wire(&[&PAYLOAD[..], &FCS16].concat())].concat();
+        let frames = HdlcFramer::new(Fcs::Fcs16, 64).push(&wire);
+        assert_eq!(frames, [Frame::new(PAYLOAD.to_vec()).consumed(wire.len())]);
+    }
+
+    #[test]
+    fn abort_sequence_is_flagged() {
+        let wire = [FLAG, 0xff, 0x03, 0xc0, ESCAPE, FLAG];
+        let frames = HdlcFramer::new(Fcs::Fcs16, 64).push(&wire);
+        assert_eq!(frames, [Frame::with_error(FrameError::Encoding, vec![0xff, 0x03, 0xc0]).consumed(wire.len())]);
+    }
+
+    #[test]
+    fn short_frames_are_flagged() {
+        let wire = [FLAG, 0x01, 0x02, FLAG];
+        assert_eq!(HdlcFramer::new(Fcs::Fcs16, 64).push(&wire), [Frame::with_error(FrameError::Encoding, vec![0x01, 0x02]).consumed(4)]);
+        let wire = [FLAG, 0x01, 0x02, 0x03, 0x04, FLAG];
+        assert_eq!(HdlcFramer::new(Fcs::Fcs32, 64).push(&wire), [Frame::with_error(FrameError::Encoding, vec![0x01, 0x02, 0x03, 0x04]).consumed(6)]);
+    }
+}
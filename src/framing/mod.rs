@@ -0,0 +1,113 @@
+//! Pluggable framing engines.
+//!
+//! By default a packet ends when the serial port read times out (the inter
+//! frame gap). A `Framer` instead looks at the received bytes themselves to
+//! find frame boundaries, so frames are split correctly even when OS
+//! scheduling jitter makes the gap timing unreliable.
+
+use clap::error::Error;
+
+use crate::state::FrameError;
+
+pub mod cobs;
+pub mod delimiter;
+pub mod hdlc;
+pub mod modbus;
+pub mod slip;
+
+/// A frame found by a `Framer`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub data: Vec<u8>,
+    pub error: Option<FrameError>,
+    pub wire_len: usize,    // Received bytes used up by this frame, since the end of the previous one
+}
+
+impl Frame {
+    pub fn new(data: Vec<u8>) -> Self {
+        Frame { wire_len: data.len(), data, error: None }
+    }
+
+    pub fn with_error(error: FrameError, data: Vec<u8>) -> Self {
+        Frame { wire_len: data.len(), data, error: Some(error) }
+    }
+
+    /// Sets the number of received bytes used up, for frames which aren't the wire bytes.
+    pub fn consumed(mut self, wire_len: usize) -> Self {
+        self.wire_len = wire_len;
+        self
+    }
+}
+
+/// Splits a stream of received bytes into frames.
+pub trait Framer: Send {
+    /// Feeds received bytes to the framer, returning any frames they complete.
+    fn push(&mut self, data: &[u8]) -> Vec<Frame>;
+
+    /// Tells the framer a whole inter-frame gap passed without any data,
+    /// returning any frames this completes.
+    fn idle(&mut self) -> Vec<Frame>;
+}
+
+/// The framing engines selectable from the command line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FramingMode {
+    Gap,
+    ModbusRtu,
+    Delimiter(Vec<u8>),
+    Slip,
+    Cobs,
+    Hdlc(hdlc::Fcs),
+}
+
+impl FramingMode {
+    /// Creates the framer for this mode, `None` means frames are split on the inter-frame gap alone.
+    ///
+    /// # Arguments
+    ///
+    /// * `decode` - Emit the decoded payload rather than the wire bytes, where the framing has an encoding
+    /// * `max_len` - Longest frame to emit, longer frames are split
+    pub fn framer(&self, decode: bool, max_len: usize) -> Option<Box<dyn Framer>> {
+        match self {
+            FramingMode::Gap => None,
+            FramingMode::ModbusRtu => Some(Box::new(modbus::ModbusRtuFramer::new())),
+            FramingMode::Delimiter(delimiter) => Some(Box::new(delimiter::DelimiterFramer::new(delimiter.clone(), decode, max_len))),
+            FramingMode::Slip => Some(Box::new(delimiter::DelimiterFramer::slip(decode, max_len))),
+            FramingMode::Cobs => Some(Box::new(delimiter::DelimiterFramer::cobs(decode, max_len))),
+            FramingMode::Hdlc(fcs) => Some(Box::new(hdlc::HdlcFramer::new(*fcs, max_len))),
+        }
+    }
+}
+
+/// Parses a hex string such as `0d0a` into bytes.
+fn parse_hex(hex_str: &str) -> Option<Vec<u8>> {
+    if hex_str.is_empty() || !hex_str.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex_str.len()).step_by(2)
+        .map(|i| u8::from_str_radix(hex_str.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Parses a framing mode from a string.
+/// this is used in our clap argument parser.
+///
+/// A custom terminator is given as hex bytes, e.g. `delimiter:0d0a`.
+pub fn parse_framing(framing_str: &str) -> Result<FramingMode, Error> {
+    let lower = framing_str.to_lowercase();
+    if let Some(hex) = lower.strip_prefix("delimiter:") {
+        return parse_hex(hex).map(FramingMode::Delimiter).ok_or_else(|| {
+            Error::raw(clap::error::ErrorKind::InvalidValue, format!("Invalid delimiter bytes: {}", hex))
+        });
+    }
+    match lower.as_str() {
+        "gap" => Ok(FramingMode::Gap),
+        "modbus-rtu" | "modbus" => Ok(FramingMode::ModbusRtu),
+        "line" => Ok(FramingMode::Delimiter(vec![b'\n'])),
+        "slip" => Ok(FramingMode::Slip),
+        "cobs" => Ok(FramingMode::Cobs),
+        "hdlc" | "hdlc16" => Ok(FramingMode::Hdlc(hdlc::Fcs::Fcs16)),
+        "hdlc32" => Ok(FramingMode::Hdlc(hdlc::Fcs::Fcs32)),
+        _ => Err(Error::raw(clap::error::ErrorKind::InvalidValue, format!("Unknown framing mode: {}", framing_str))),
+    }
+}
@@ -0,0 +1,319 @@
+//! Modbus RTU framing.
+//!
+//! Frame boundaries are found from the function code and the lengths it
+//! implies, confirmed with the CRC-16 trailer. As the same function code is
+//! used by both requests and responses both lengths are tried, and for
+//! function codes without a fixed layout any CRC valid prefix is accepted.
+//! When resynchronising after bad data only function codes with a known
+//! layout are looked for, so each offset costs at most a few CRCs.
+
+use super::{Frame, Framer};
+use crate::state::FrameError;
+
+const MIN_ADU_SIZE: usize = 4; // Address, function code and CRC
+const MAX_ADU_SIZE: usize = 256;
+const EXCEPTION_ADU_SIZE: usize = 5;
+const EXCEPTION_FLAG: u8 = 0x80;
+
+/// Calculates the Modbus CRC-16 (polynomial 0xA001 reflected, initial value 0xFFFF).
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for byte in data {
+        crc ^= *byte as u16;
+        for _ in 0..8 {
+            if crc & 0x0001 != 0 {
+                crc = (crc >> 1) ^ 0xA001;
+            } else {
+                crc >>= 1;
+            }
+        }
+    }
+    crc
+}
+
+/// Checks the CRC trailer (sent low byte first) of a complete frame.
+fn crc_ok(frame: &[u8]) -> bool {
+    if frame.len() < MIN_ADU_SIZE {
+        return false;
+    }
+    let (body, crc) = frame.split_at(frame.len() - 2);
+    crc16(body) == u16::from_le_bytes([crc[0], crc[1]])
+}
+
+/// The possible lengths of the frame at the start of a buffer.
+struct Lengths {
+    known: Vec<usize>,
+    // Some lengths depend on a byte count which hasn't been received yet
+    pending: bool,
+}
+
+impl Lengths {
+    fn new() -> Self {
+        Lengths { known: vec![], pending: false }
+    }
+
+    fn fixed(mut self, len: usize) -> Self {
+        self.known.push(len);
+        self
+    }
+
+    // Length given by a byte count field at `index`, plus `base` bytes of overhead.
+    fn counted(mut self, buf: &[u8], index: usize, base: usize) -> Self {
+        match buf.get(index) {
+            Some(count) => self.known.push(base + *count as usize),
+            None => self.pending = true,
+        }
+        self
+    }
+
+    // Length given by a big-endian 16 bit byte count field at `index`.
+    fn counted16(mut self, buf: &[u8], index: usize, base: usize) -> Self {
+        match buf.get(index..index + 2) {
+            Some(count) => self.known.push(base + u16::from_be_bytes([count[0], count[1]]) as usize),
+            None => self.pending = true,
+        }
+        self
+    }
+
+    /// The shortest known length with a valid CRC.
+    fn crc_valid(&mut self, buf: &[u8]) -> Option<usize> {
+        self.known.sort_unstable();
+        self.known.iter().copied().find(|len| buf.len() >= *len && crc_ok(&buf[..*len]))
+    }
+}
+
+/// Returns the request and response lengths implied by the function code,
+/// or `None` if the function code doesn't define them.
+fn frame_lengths(buf: &[u8]) -> Option<Lengths> {
+    let function = buf[1];
+    if function & EXCEPTION_FLAG != 0 {
+        return Some(Lengths::new().fixed(EXCEPTION_ADU_SIZE));
+    }
+    let lengths = Lengths::new();
+    Some(match function {
+        // Read coils, discrete inputs, holding registers, input registers
+        0x01..=0x04 => lengths.fixed(8).counted(buf, 2, 5),
+        // Write single coil, write single register, diagnostics
+        0x05 | 0x06 | 0x08 => lengths.fixed(8),
+        // Read exception status
+        0x07 => lengths.fixed(4).fixed(5),
+        // Get comm event counter
+        0x0B => lengths.fixed(4).fixed(8),
+        // Get comm event log, report server id
+        0x0C | 0x11 => lengths.fixed(4).counted(buf, 2, 5),
+        // Write multiple coils, write multiple registers
+        0x0F | 0x10 => lengths.counted(buf, 6, 9).fixed(8),
+        // Read file record, write file record
+        0x14 | 0x15 => lengths.counted(buf, 2, 5),
+        // Mask write register
+        0x16 => lengths.fixed(10),
+        // Read/write multiple registers
+        0x17 => lengths.counted(buf, 10, 13).counted(buf, 2, 5),
+        // Read FIFO queue
+        0x18 => lengths.fixed(6).counted16(buf, 2, 6),
+        _ => return None,
+    })
+}
+
+/// Result of looking for a frame at the start of a buffer.
+enum Search {
+    Found(usize),
+    // More data is needed to decide
+    Incomplete,
+    // The data can't be the start of a valid frame
+    Invalid,
+}
+
+fn find_frame(buf: &[u8]) -> Search {
+    if buf.len() < 2 {
+        return Search::Incomplete;
+    }
+    match frame_lengths(buf) {
+        Some(mut lengths) => {
+            if let Some(len) = lengths.crc_valid(buf) {
+                return Search::Found(len);
+            }
+            if lengths.pending || lengths.known.iter().any(|len| *len > buf.len()) {
+                Search::Incomplete
+            } else {
+                Search::Invalid
+            }
+        },
+        None => {
+            // Unknown layout, accept the shortest CRC valid prefix.
+            let limit = buf.len().min(MAX_ADU_SIZE);
+            match (MIN_ADU_SIZE..=limit).find(|len| crc_ok(&buf[..*len])) {
+                Some(len) => Search::Found(len),
+                None if buf.len() < MAX_ADU_SIZE => Search::Incomplete,
+                None => Search::Invalid,
+            }
+        },
+    }
+}
+
+/// Frames a Modbus RTU byte stream using function codes and CRCs.
+///
+/// Frames which fail the CRC are still emitted, flagged with a checksum
+/// error, either when the framer resynchronises on a following valid frame
+/// or when the line goes idle.
+pub struct ModbusRtuFramer {
+    buffer: Vec<u8>,
+}
+
+impl ModbusRtuFramer {
+    pub fn new() -> Self {
+        ModbusRtuFramer { buffer: Vec::new() }
+    }
+
+    /// Finds the next valid frame starting after the start of the buffer,
+    /// only trying the lengths implied by each candidate function code.
+    fn resync_offset(&self) -> Option<usize> {
+        (1..self.buffer.len().saturating_sub(1)).find(|offset| {
+            let candidate = &self.buffer[*offset..];
+            frame_lengths(candidate).and_then(|mut lengths| lengths.crc_valid(candidate)).is_some()
+        })
+    }
+}
+
+impl Default for ModbusRtuFramer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Framer for ModbusRtuFramer {
+    fn push(&mut self, data: &[u8]) -> Vec<Frame> {
+        self.buffer.extend_from_slice(data);
+        let mut frames = Vec::new();
+        loop {
+            match find_frame(&self.buffer) {
+                Search::Found(len) => {
+                    frames.push(Frame::new(self.buffer.drain(..len).collect()));
+                },
+                Search::Incomplete if self.buffer.len() < MAX_ADU_SIZE => break,
+                Search::Incomplete | Search::Invalid => {
+                    // Emit everything up to the next good frame as a bad one.
+                    match self.resync_offset() {
+                        Some(offset) => frames.push(Frame::with_error(FrameError::Checksum, self.buffer.drain(..offset).collect())),
+                        None if self.buffer.len() >= MAX_ADU_SIZE => {
+                            frames.push(Frame::with_error(FrameError::Checksum, self.buffer.drain(..MAX_ADU_SIZE).collect()));
+                        },
+                        None => break,
+                    }
+                },
+            }
+        }
+        frames
+    }
+
+    fn idle(&mut self) -> Vec<Frame> {
+        if self.buffer.is_empty() {
+            return vec![];
+        }
+        let data: Vec<u8> = std::mem::take(&mut self.buffer);
+        if crc_ok(&data) {
+            vec![Frame::new(data)]
+        } else {
+            vec![Frame::with_error(FrameError::Checksum, data)]
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Read 10 holding registers from server 1, and a two register response.
+    const READ_REQUEST: [u8; 8] = [0x01, 0x03, 0x00, 0x00, 0x00, 0x0a, 0xc5, 0xcd];
+    const READ_RESPONSE: [u8; 7] = [0x01, 0x03, 0x02, 0x00, 0x01, 0x79, 0x84];
+    // Write single register 1 to 3.
+    const WRITE_SINGLE: [u8; 8] = [0x01, 0x06, 0x00, 0x01, 0x00, 0x03, 0x98, 0x0b];
+    // Write two registers starting at 1, and its response.
+    const WRITE_MULTIPLE: [u8; 13] = [0x01, 0x10, 0x00, 0x01, 0x00, 0x02, 0x04, 0x00, 0x0a, 0x01, 0x02, 0x92, 0x30];
+    const WRITE_MULTIPLE_RESPONSE: [u8; 8] = [0x01, 0x10, 0x00, 0x01, 0x00, 0x02, 0x10, 0x08];
+    // Illegal data address exception to a read holding registers.
+    const EXCEPTION: [u8; 5] = [0x01, 0x83, 0x02, 0xc0, 0xf1];
+
+    fn push_all(framer: &mut ModbusRtuFramer, data: &[u8]) -> Vec<Frame> {
+        let mut frames = framer.push(data);
+        frames.extend(framer.idle());
+        frames
+    }
+
+    #[test]
+    fn crc_matches_known_values() {
+        assert_eq!(crc16(b"123456789"), 0x4b37);
+        assert_eq!(crc16(&READ_REQUEST[..6]), 0xcdc5);
+        assert_eq!(crc16(&EXCEPTION[..3]), 0xf1c0);
+        assert!(crc_ok(&WRITE_MULTIPLE_RESPONSE));
+    }
+
+    #[test]
+    fn lengths_follow_the_function_code() {
+        let cases: [(&[u8], &[usize], bool); 6] = [
+            (&READ_RESPONSE, &[8, 7], false),
+            (&READ_REQUEST[..2], &[8], true),
+            (&WRITE_SINGLE, &[8], false),
+            (&WRITE_MULTIPLE, &[13, 8], false),
+            (&[0x01, 0x07], &[4, 5], false),
+            (&EXCEPTION, &[5], false),
+        ];
+        for (buf, known, pending) in cases {
+            let lengths = frame_lengths(buf).unwrap();
+            assert_eq!(lengths.known, known, "{:02x?}", buf);
+            assert_eq!(lengths.pending, pending, "{:02x?}", buf);
+        }
+        assert!(frame_lengths(&[0x01, 0x41]).is_none());
+    }
+
+    #[test]
+    fn splits_back_to_back_frames() {
+        let stream = [&READ_REQUEST[..], &READ_RESPONSE, &WRITE_MULTIPLE, &WRITE_MULTIPLE_RESPONSE, &WRITE_SINGLE].concat();
+        let frames = ModbusRtuFramer::new().push(&stream);
+        let data: Vec<&[u8]> = frames.iter().map(|frame| frame.data.as_slice()).collect();
+        assert_eq!(data, [&READ_REQUEST[..], &READ_RESPONSE, &WRITE_MULTIPLE, &WRITE_MULTIPLE_RESPONSE, &WRITE_SINGLE]);
+        assert!(frames.iter().all(|frame| frame.error.is_none()));
+    }
+
+    #[test]
+    fn frames_split_across_reads() {
+        let mut framer = ModbusRtuFramer::new();
+        assert!(framer.push(&READ_REQUEST[..3]).is_empty());
+        assert_eq!(framer.push(&READ_REQUEST[3..]), [Frame::new(READ_REQUEST.to_vec())]);
+    }
+
+    #[test]
+    fn exception_responses() {
+        let stream = [&EXCEPTION[..], &READ_REQUEST].concat();
+        let frames = ModbusRtuFramer::new().push(&stream);
+        assert_eq!(frames, [Frame::new(EXCEPTION.to_vec()), Frame::new(READ_REQUEST.to_vec())]);
+    }
+
+    #[test]
+    fn bad_crc_is_flagged() {
+        let mut corrupt = WRITE_SINGLE;
+        corrupt[7] ^= 0xff;
+        let frames = push_all(&mut ModbusRtuFramer::new(), &corrupt);
+        assert_eq!(frames, [Frame::with_error(FrameError::Checksum, corrupt.to_vec())]);
+    }
+
+    #[test]
+    fn resyncs_after_garbage() {
+        let garbage = [0x55, 0xaa, 0x00, 0x13];
+        let stream = [&garbage[..], &READ_REQUEST, &EXCEPTION].concat();
+        let frames = ModbusRtuFramer::new().push(&stream);
+        assert_eq!(frames, [
+            Frame::with_error(FrameError::Checksum, garbage.to_vec()),
+            Frame::new(READ_REQUEST.to_vec()),
+            Frame::new(EXCEPTION.to_vec()),
+        ]);
+    }
+
+    #[test]
+    fn unframeable_garbage_is_flushed() {
+        let garbage = vec![0x00; MAX_ADU_SIZE + 10];
+        let frames = push_all(&mut ModbusRtuFramer::new(), &garbage);
+        assert_eq!(frames.iter().map(|frame| frame.data.len()).sum::<usize>(), garbage.len());
+        assert!(frames.iter().all(|frame| frame.error == Some(FrameError::Checksum)));
+    }
+}
@@ -0,0 +1,60 @@
+//! SLIP (RFC 1055) decoding.
+
+pub const END: u8 = 0xC0;
+pub const ESC: u8 = 0xDB;
+pub const ESC_END: u8 = 0xDC;
+pub const ESC_ESC: u8 = 0xDD;
+
+/// Decodes a SLIP frame, without its END delimiters.
+///
+/// Returns `None` if the frame contains an invalid escape sequence.
+pub fn decode(frame: &[u8]) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(frame.len());
+    let mut bytes = frame.iter();
+    while let Some(byte) = bytes.next() {
+        match *byte {
+            ESC => match bytes.next() {
+                Some(&ESC_END) => decoded.push(END),
+                Some(&ESC_ESC) => decoded.push(ESC),
+                _ => return None,
+            },
+            _ => decoded.push(*byte),
+        }
+    }
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(data: &[u8]) -> Vec<u8> {
+        data.iter().flat_map(|byte| match *byte {
+            END => vec![ESC, ESC_END],
+            ESC => vec![ESC, ESC_ESC],
+            _ => vec![*byte],
+        }).collect()
+    }
+
+    #[test]
+    fn decodes_escapes() {
+        assert_eq!(decode(&[0x01, ESC, ESC_END, 0x02, ESC, ESC_ESC]), Some(vec![0x01, END, 0x02, ESC]));
+        assert_eq!(decode(&[]), Some(vec![]));
+    }
+
+    #[test]
+    fn round_trips() {
+        let cases: [&[u8]; 4] = [b"plain", &[END], &[ESC, ESC_END, ESC_ESC], &[END, ESC, END, 0x00]];
+        for data in cases {
+            assert_eq!(decode(&encode(data)).as_deref(), Some(data), "{:02x?}", data);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_escapes() {
+        assert_eq!(decode(&[ESC, 0x41]), None);
+        assert_eq!(decode(&[ESC, END]), None);
+        // ESC as the last byte of the frame
+        assert_eq!(decode(&[0x41, ESC]), None);
+    }
+}
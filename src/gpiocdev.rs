@@ -0,0 +1,342 @@
+//! GPIO lines through the Linux GPIO character device, `/dev/gpiochipN`.
+//!
+//! This is the GPIO v2 uAPI, from `<linux/gpio.h>`, which replaces the
+//! deprecated sysfs interface. Lines are requested from a `GpioChip` by
+//! offset, or found by name, and are configured as active-low and with a
+//! pull-up or pull-down bias as asked. Inputs have the kernel report both
+//! their edges.
+
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::{self, Read};
+use std::mem::size_of;
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use gpio::GpioOut;
+
+use crate::gpiopins::{Bias, GpioLine, LineId};
+use crate::linewatch::EdgeInput;
+
+const GPIO_MAX_NAME_SIZE: usize = 32;
+const GPIO_V2_LINES_MAX: usize = 64;
+const GPIO_V2_LINE_NUM_ATTRS_MAX: usize = 10;
+
+const GPIO_V2_LINE_FLAG_ACTIVE_LOW: u64 = 1 << 1;
+const GPIO_V2_LINE_FLAG_INPUT: u64 = 1 << 2;
+const GPIO_V2_LINE_FLAG_OUTPUT: u64 = 1 << 3;
+const GPIO_V2_LINE_FLAG_EDGE_RISING: u64 = 1 << 4;
+const GPIO_V2_LINE_FLAG_EDGE_FALLING: u64 = 1 << 5;
+const GPIO_V2_LINE_FLAG_BIAS_PULL_UP: u64 = 1 << 8;
+const GPIO_V2_LINE_FLAG_BIAS_PULL_DOWN: u64 = 1 << 9;
+const GPIO_V2_LINE_FLAG_BIAS_DISABLED: u64 = 1 << 10;
+
+const GPIO_V2_LINE_ATTR_ID_OUTPUT_VALUES: u32 = 2;
+const GPIO_V2_LINE_EVENT_RISING_EDGE: u32 = 1;
+
+/// The name lines are requested under, shown as their consumer.
+const CONSUMER: &[u8] = b"serialpcap-rs";
+
+/// `struct gpiochip_info`.
+#[repr(C)]
+struct GpioChipInfo {
+    name: [u8; GPIO_MAX_NAME_SIZE],
+    label: [u8; GPIO_MAX_NAME_SIZE],
+    lines: u32,
+}
+
+/// `struct gpio_v2_line_attribute`, whose value is a union of the flags,
+/// output values and debounce period.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct LineAttribute {
+    id: u32,
+    padding: u32,
+    value: u64,
+}
+
+/// `struct gpio_v2_line_config_attribute`.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct LineConfigAttribute {
+    attr: LineAttribute,
+    mask: u64,
+}
+
+/// `struct gpio_v2_line_config`.
+#[repr(C)]
+#[derive(Default)]
+struct LineConfig {
+    flags: u64,
+    num_attrs: u32,
+    padding: [u32; 5],
+    attrs: [LineConfigAttribute; GPIO_V2_LINE_NUM_ATTRS_MAX],
+}
+
+/// `struct gpio_v2_line_request`.
+#[repr(C)]
+struct LineRequest {
+    offsets: [u32; GPIO_V2_LINES_MAX],
+    consumer: [u8; GPIO_MAX_NAME_SIZE],
+    config: LineConfig,
+    num_lines: u32,
+    event_buffer_size: u32,
+    padding: [u32; 5],
+    fd: i32,
+}
+
+/// `struct gpio_v2_line_info`.
+#[repr(C)]
+struct LineInfo {
+    name: [u8; GPIO_MAX_NAME_SIZE],
+    consumer: [u8; GPIO_MAX_NAME_SIZE],
+    offset: u32,
+    num_attrs: u32,
+    flags: u64,
+    attrs: [LineAttribute; GPIO_V2_LINE_NUM_ATTRS_MAX],
+    padding: [u32; 4],
+}
+
+/// `struct gpio_v2_line_values`.
+#[repr(C)]
+#[derive(Default)]
+struct LineValues {
+    bits: u64,
+    mask: u64,
+}
+
+/// `struct gpio_v2_line_event`.
+#[repr(C)]
+#[derive(Default)]
+struct LineEvent {
+    timestamp_ns: u64,
+    id: u32,
+    offset: u32,
+    seqno: u32,
+    line_seqno: u32,
+    padding: [u32; 6],
+}
+
+// The sizes the kernel expects, as the ioctl numbers encode them
+const _: () = assert!(size_of::<GpioChipInfo>() == 68);
+const _: () = assert!(size_of::<LineConfig>() == 272);
+const _: () = assert!(size_of::<LineRequest>() == 592);
+const _: () = assert!(size_of::<LineInfo>() == 256);
+const _: () = assert!(size_of::<LineValues>() == 16);
+const _: () = assert!(size_of::<LineEvent>() == 48);
+
+// The ioctl numbers, whose layout differs between architectures
+const GPIO_IOCTL_TYPE: u32 = 0xB4;
+const GPIO_GET_CHIPINFO_IOCTL: u32 = libc::_IOR::<GpioChipInfo>(GPIO_IOCTL_TYPE, 0x01) as u32;
+const GPIO_V2_GET_LINEINFO_IOCTL: u32 = libc::_IOWR::<LineInfo>(GPIO_IOCTL_TYPE, 0x05) as u32;
+const GPIO_V2_GET_LINE_IOCTL: u32 = libc::_IOWR::<LineRequest>(GPIO_IOCTL_TYPE, 0x07) as u32;
+const GPIO_V2_LINE_GET_VALUES_IOCTL: u32 = libc::_IOWR::<LineValues>(GPIO_IOCTL_TYPE, 0x0E) as u32;
+const GPIO_V2_LINE_SET_VALUES_IOCTL: u32 = libc::_IOWR::<LineValues>(GPIO_IOCTL_TYPE, 0x0F) as u32;
+
+/// Makes the ioctl `request` on `fd` with `arg`, which must be the struct it takes.
+fn ioctl<T>(fd: RawFd, request: u32, arg: &mut T) -> io::Result<()> {
+    // SAFETY: the callers pass the struct matching the request, whose size the request encodes.
+    let result = unsafe { libc::ioctl(fd, request as _, arg as *mut T) };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// A NUL terminated name from the kernel.
+fn name(bytes: &[u8]) -> String {
+    CStr::from_bytes_until_nul(bytes).map(|name| name.to_string_lossy().into_owned()).unwrap_or_default()
+}
+
+/// The line flags for a line's settings.
+fn line_flags(line: &GpioLine) -> u64 {
+    let active_low = if line.active_low { GPIO_V2_LINE_FLAG_ACTIVE_LOW } else { 0 };
+    let bias = match line.bias {
+        Some(Bias::PullUp) => GPIO_V2_LINE_FLAG_BIAS_PULL_UP,
+        Some(Bias::PullDown) => GPIO_V2_LINE_FLAG_BIAS_PULL_DOWN,
+        Some(Bias::Disabled) => GPIO_V2_LINE_FLAG_BIAS_DISABLED,
+        None => 0,
+    };
+    active_low | bias
+}
+
+/// A GPIO chip, opened through its character device.
+#[derive(Debug)]
+pub struct GpioChip {
+    file: File,
+    path: PathBuf,
+}
+
+impl GpioChip {
+    /// Opens a chip by its device name, e.g. `gpiochip0`, its number, or its path.
+    pub fn open(chip: &str) -> io::Result<Self> {
+        let path = if chip.starts_with('/') {
+            PathBuf::from(chip)
+        } else if chip.chars().all(|c| c.is_ascii_digit()) {
+            PathBuf::from(format!("/dev/gpiochip{}", chip))
+        } else {
+            PathBuf::from("/dev").join(chip)
+        };
+        let file = File::open(&path)?;
+        Ok(GpioChip { file, path })
+    }
+
+    /// The chip's label, and how many lines it has.
+    pub fn info(&self) -> io::Result<(String, u32)> {
+        let mut info = GpioChipInfo { name: [0; GPIO_MAX_NAME_SIZE], label: [0; GPIO_MAX_NAME_SIZE], lines: 0 };
+        ioctl(self.file.as_raw_fd(), GPIO_GET_CHIPINFO_IOCTL, &mut info)?;
+        Ok((name(&info.label), info.lines))
+    }
+
+    /// The name of the line at `offset`, empty if it has none.
+    pub fn line_name(&self, offset: u32) -> io::Result<String> {
+        // SAFETY: LineInfo is plain data, for which all zeroes is valid.
+        let mut info: LineInfo = unsafe { std::mem::zeroed() };
+        info.offset = offset;
+        ioctl(self.file.as_raw_fd(), GPIO_V2_GET_LINEINFO_IOCTL, &mut info)?;
+        Ok(name(&info.name))
+    }
+
+    /// The offset of a line, looking it up by name if need be.
+    pub fn offset(&self, id: &LineId) -> io::Result<u32> {
+        match id {
+            LineId::Number(offset) => Ok(*offset),
+            LineId::Name(line_name) => {
+                let (_, lines) = self.info()?;
+                for offset in 0..lines {
+                    if self.line_name(offset)? == *line_name {
+                        return Ok(offset);
+                    }
+                }
+                Err(io::Error::new(io::ErrorKind::NotFound, format!("no GPIO line {} on {}", line_name, self.path.display())))
+            },
+        }
+    }
+
+    /// Requests one line with the given flags, returning the file of the request.
+    fn request(&self, line: &GpioLine, flags: u64, output_high: bool) -> io::Result<File> {
+        // SAFETY: LineRequest is plain data, for which all zeroes is valid.
+        let mut request: LineRequest = unsafe { std::mem::zeroed() };
+        request.offsets[0] = self.offset(&line.id)?;
+        request.num_lines = 1;
+        request.consumer[..CONSUMER.len()].copy_from_slice(CONSUMER);
+        request.config.flags = flags | line_flags(line);
+        if flags & GPIO_V2_LINE_FLAG_OUTPUT != 0 {
+            request.config.num_attrs = 1;
+            request.config.attrs[0] = LineConfigAttribute {
+                attr: LineAttribute { id: GPIO_V2_LINE_ATTR_ID_OUTPUT_VALUES, padding: 0, value: output_high as u64 },
+                mask: 1,
+            };
+        }
+        ioctl(self.file.as_raw_fd(), GPIO_V2_GET_LINE_IOCTL, &mut request)
+            .map_err(|e| io::Error::new(e.kind(), format!("Failed to request GPIO line {} on {}: {}", line, self.path.display(), e)))?;
+        // SAFETY: the kernel returned the request's new file descriptor, which we now own.
+        Ok(unsafe { File::from_raw_fd(request.fd) })
+    }
+
+    /// Requests a line as an output, starting low.
+    pub fn request_output(&self, line: &GpioLine) -> io::Result<CdevOutput> {
+        Ok(CdevOutput { request: self.request(line, GPIO_V2_LINE_FLAG_OUTPUT, false)? })
+    }
+
+    /// Requests a line as an input, reporting both edges.
+    pub fn request_input(&self, line: &GpioLine) -> io::Result<CdevInput> {
+        let flags = GPIO_V2_LINE_FLAG_INPUT | GPIO_V2_LINE_FLAG_EDGE_RISING | GPIO_V2_LINE_FLAG_EDGE_FALLING;
+        Ok(CdevInput { request: self.request(line, flags, false)? })
+    }
+}
+
+/// Reads the value of the single line in a request.
+fn read_value(request: &File) -> io::Result<bool> {
+    let mut values = LineValues { bits: 0, mask: 1 };
+    ioctl(request.as_raw_fd(), GPIO_V2_LINE_GET_VALUES_IOCTL, &mut values)?;
+    Ok(values.bits & 1 != 0)
+}
+
+/// A line requested as an output, released when dropped.
+#[derive(Debug)]
+pub struct CdevOutput {
+    request: File,
+}
+
+impl CdevOutput {
+    fn set(&mut self, level: bool) -> io::Result<()> {
+        let mut values = LineValues { bits: level as u64, mask: 1 };
+        ioctl(self.request.as_raw_fd(), GPIO_V2_LINE_SET_VALUES_IOCTL, &mut values)
+    }
+}
+
+impl GpioOut for CdevOutput {
+    type Error = io::Error;
+
+    fn set_low(&mut self) -> io::Result<()> {
+        self.set(false)
+    }
+
+    fn set_high(&mut self) -> io::Result<()> {
+        self.set(true)
+    }
+}
+
+/// A line requested as an input with edge detection, released when dropped.
+#[derive(Debug)]
+pub struct CdevInput {
+    request: File,
+}
+
+impl EdgeInput for CdevInput {
+    fn read_level(&mut self) -> io::Result<bool> {
+        read_value(&self.request)
+    }
+
+    fn wait_for_edge(&mut self, timeout: Duration) -> io::Result<Option<bool>> {
+        let mut poll_fd = libc::pollfd { fd: self.request.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+        let timeout_ms = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+        // SAFETY: `poll_fd` is a valid pollfd, and the count of one matches it.
+        let result = unsafe { libc::poll(&mut poll_fd, 1, timeout_ms) };
+        if result < 0 {
+            let e = io::Error::last_os_error();
+            return if e.kind() == io::ErrorKind::Interrupted { Ok(None) } else { Err(e) };
+        }
+        if result == 0 {
+            return Ok(None);
+        }
+        // The kernel hands out whole events, one per read of this size
+        let mut event = LineEvent::default();
+        // SAFETY: LineEvent is plain data, so any bytes read into it are a valid value.
+        let buffer = unsafe { std::slice::from_raw_parts_mut(&mut event as *mut LineEvent as *mut u8, size_of::<LineEvent>()) };
+        self.request.read_exact(buffer)?;
+        Ok(Some(event.id == GPIO_V2_LINE_EVENT_RISING_EDGE))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // As <linux/gpio.h> gives them with the generic ioctl number layout
+    #[test]
+    #[cfg(not(any(target_arch = "mips", target_arch = "mips64", target_arch = "powerpc",
+        target_arch = "powerpc64", target_arch = "sparc", target_arch = "sparc64")))]
+    fn ioctl_numbers_match_the_kernel() {
+        assert_eq!(GPIO_GET_CHIPINFO_IOCTL, 0x8044_B401);
+        assert_eq!(GPIO_V2_GET_LINEINFO_IOCTL, 0xC100_B405);
+        assert_eq!(GPIO_V2_GET_LINE_IOCTL, 0xC250_B407);
+        assert_eq!(GPIO_V2_LINE_GET_VALUES_IOCTL, 0xC010_B40E);
+        assert_eq!(GPIO_V2_LINE_SET_VALUES_IOCTL, 0xC010_B40F);
+    }
+
+    #[test]
+    fn line_settings_become_flags() {
+        let line = GpioLine { active_low: true, bias: Some(Bias::PullUp), ..GpioLine::number(4) };
+        assert_eq!(line_flags(&line), GPIO_V2_LINE_FLAG_ACTIVE_LOW | GPIO_V2_LINE_FLAG_BIAS_PULL_UP);
+        assert_eq!(line_flags(&GpioLine { bias: Some(Bias::Disabled), ..GpioLine::number(4) }), GPIO_V2_LINE_FLAG_BIAS_DISABLED);
+        assert_eq!(line_flags(&GpioLine::number(4)), 0);
+    }
+
+    #[test]
+    fn missing_chip_fails_to_open() {
+        assert!(GpioChip::open("gpiochip-none").is_err());
+    }
+}
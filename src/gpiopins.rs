@@ -0,0 +1,450 @@
+//! GPIO pins attached to a serial port.
+//!
+//! A port can have its RI and CD outputs, which serial adapters don't have,
+//! driven on GPIO outputs, and any of its control lines read from GPIO
+//! inputs. The pins are given on the command line, or in a config file of
+//! the same options, one per line:
+//!
+//! ```text
+//! # Raspberry Pi header pins
+//! gpio-chip = gpiochip0
+//! ri-out-gpio = 17
+//! cd-out-gpio = GPIO27,active-low
+//! gpio-in = ri:22,pull-up
+//! gpio-in = cd:23
+//! ```
+//!
+//! A pin is a line offset on the `gpio-chip`, opened through the GPIO
+//! character device, or a line name on it. It may be followed by the
+//! settings `active-low` and a bias of `pull-up`, `pull-down` or
+//! `bias-disabled`. Without a chip, pins are numbers in the deprecated sysfs
+//! interface, which can't set the bias.
+//!
+//! How the lines are opened is a `GpioBackend`, so ports with GPIOs can be
+//! tested against mock lines.
+
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+use clap::error::Error;
+use gpio::GpioOut;
+use serialport::SerialPort;
+
+use crate::linewatch::{EdgeInput, LineWatcher};
+use crate::portinfo::SerialPortWithGpios;
+use crate::state::ControlLine;
+
+/// Which line on a chip.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LineId {
+    Number(u32),    // The offset on the chip, or the sysfs GPIO number
+    Name(String),   // The line's name, found on the chip
+}
+
+impl fmt::Display for LineId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LineId::Number(number) => write!(f, "{}", number),
+            LineId::Name(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+/// The pull on a GPIO line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bias {
+    PullUp,
+    PullDown,
+    Disabled,
+}
+
+/// A GPIO line and its settings.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GpioLine {
+    pub id: LineId,
+    pub active_low: bool,   // The line reads and is set high when it is low
+    pub bias: Option<Bias>, // Left as it is if not given
+}
+
+impl GpioLine {
+    /// The numbered line, active-high with its bias left as it is.
+    pub fn number(number: u32) -> Self {
+        GpioLine { id: LineId::Number(number), active_low: false, bias: None }
+    }
+}
+
+impl fmt::Display for GpioLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.id)?;
+        if self.active_low {
+            write!(f, ",active-low")?;
+        }
+        match self.bias {
+            Some(Bias::PullUp) => write!(f, ",pull-up"),
+            Some(Bias::PullDown) => write!(f, ",pull-down"),
+            Some(Bias::Disabled) => write!(f, ",bias-disabled"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Parses a pin, as its number or name followed by any settings, e.g. `17,active-low,pull-up`.
+fn parse_line_spec(spec: &str) -> Result<GpioLine, String> {
+    let mut parts = spec.split(',').map(str::trim);
+    let id = match parts.next() {
+        Some(id) if !id.is_empty() && id.chars().all(|c| c.is_ascii_digit()) => {
+            LineId::Number(id.parse().map_err(|_| format!("GPIO {} out of range", id))?)
+        },
+        Some(id) if !id.is_empty() => LineId::Name(id.to_string()),
+        _ => return Err("missing GPIO pin".to_string()),
+    };
+    let mut line = GpioLine { id, active_low: false, bias: None };
+    for setting in parts {
+        let bias = match setting.to_lowercase().as_str() {
+            "active-low" => {
+                line.active_low = true;
+                continue;
+            },
+            "pull-up" => Bias::PullUp,
+            "pull-down" => Bias::PullDown,
+            "bias-disabled" => Bias::Disabled,
+            _ => return Err(format!("unknown GPIO setting: {} (expected active-low, pull-up, pull-down or bias-disabled)", setting)),
+        };
+        if line.bias.replace(bias).is_some() {
+            return Err("more than one GPIO bias".to_string());
+        }
+    }
+    Ok(line)
+}
+
+/// Parses a GPIO pin from a string, e.g. `17` or `GPIO17,active-low`.
+/// this is used in our clap argument parser.
+pub fn parse_gpio_line(line_str: &str) -> Result<GpioLine, Error> {
+    parse_line_spec(line_str).map_err(|e| Error::raw(
+        clap::error::ErrorKind::InvalidValue,
+        format!("Invalid GPIO pin: {} ({})", line_str, e),
+    ))
+}
+
+fn parse_control_line(line: &str) -> Option<ControlLine> {
+    match line.to_lowercase().as_str() {
+        "cts" => Some(ControlLine::Cts),
+        "dsr" => Some(ControlLine::Dsr),
+        "cd" | "dcd" => Some(ControlLine::Cd),
+        "ri" => Some(ControlLine::Ri),
+        "rts" => Some(ControlLine::Rts),
+        "dtr" => Some(ControlLine::Dtr),
+        _ => None,
+    }
+}
+
+/// Parses a GPIO input, as `line:pin`.
+fn parse_input_spec(input_str: &str) -> Result<(ControlLine, GpioLine), String> {
+    let (line, pin) = input_str.split_once(':')
+        .ok_or_else(|| "expected line:pin, e.g. ri:22".to_string())?;
+    let line = parse_control_line(line.trim())
+        .ok_or_else(|| format!("unknown control line {}, expected cts, dsr, cd, ri, rts or dtr", line))?;
+    Ok((line, parse_line_spec(pin)?))
+}
+
+/// Parses a GPIO input from a string, as `line:pin`, e.g. `ri:22,pull-up`.
+/// this is used in our clap argument parser.
+pub fn parse_gpio_input(input_str: &str) -> Result<(ControlLine, GpioLine), Error> {
+    parse_input_spec(input_str).map_err(|e| Error::raw(
+        clap::error::ErrorKind::InvalidValue,
+        format!("Invalid GPIO input: {} ({})", input_str, e),
+    ))
+}
+
+/// Opens GPIO lines.
+pub trait GpioBackend {
+    type Output: GpioOut + Send + Sync + 'static;
+    type Input: EdgeInput + 'static;
+
+    /// Opens a line as an output, starting low.
+    fn open_output(&mut self, line: &GpioLine) -> io::Result<Self::Output>;
+
+    /// Opens a line as an input, which reports its edges.
+    fn open_input(&mut self, line: &GpioLine) -> io::Result<Self::Input>;
+}
+
+/// The GPIO pins attached to a port.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GpioPins {
+    pub chip: Option<String>,                   // The chip the pins are on, or sysfs if not given
+    pub ri_out: Option<GpioLine>,               // Drives the port's RI output
+    pub cd_out: Option<GpioLine>,               // Drives the port's CD output
+    pub inputs: Vec<(ControlLine, GpioLine)>,   // Control lines read from GPIOs rather than the port
+}
+
+impl GpioPins {
+    /// Whether no pins are attached, so the port is used as is.
+    pub fn is_empty(&self) -> bool {
+        self.ri_out.is_none() && self.cd_out.is_none() && self.inputs.is_empty()
+    }
+
+    /// Reads `line` from the GPIO `pin`, in place of any pin given before.
+    pub fn add_input(&mut self, line: ControlLine, pin: GpioLine) {
+        self.inputs.retain(|(existing, _)| *existing != line);
+        self.inputs.push((line, pin));
+    }
+
+    /// Parses a config file's contents.
+    pub fn parse_config(config: &str) -> Result<Self, String> {
+        let mut pins = GpioPins::default();
+        for (number, line) in config.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = |message: String| format!("line {}: {}", number + 1, message);
+            let Some((key, value)) = line.split_once('=') else {
+                return Err(invalid(format!("expected option = value, not {}", line)));
+            };
+            let value = value.trim();
+            let pin = || parse_line_spec(value).map_err(|e| invalid(format!("invalid GPIO pin: {} ({})", value, e)));
+            match key.trim() {
+                "gpio-chip" => pins.chip = Some(value.to_string()),
+                "ri-out-gpio" => pins.ri_out = Some(pin()?),
+                "cd-out-gpio" => pins.cd_out = Some(pin()?),
+                "gpio-in" => {
+                    let (line, pin) = parse_input_spec(value).map_err(|e| invalid(format!("invalid GPIO input: {} ({})", value, e)))?;
+                    pins.add_input(line, pin);
+                },
+                key => return Err(invalid(format!("unknown option: {}", key))),
+            }
+        }
+        Ok(pins)
+    }
+
+    /// Reads a config file.
+    pub fn read_config<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let config = std::fs::read_to_string(path)?;
+        Self::parse_config(&config).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// These pins, with any not given taken from `defaults`.
+    pub fn or(self, defaults: GpioPins) -> Self {
+        let mut pins = GpioPins {
+            chip: self.chip.or(defaults.chip),
+            ri_out: self.ri_out.or(defaults.ri_out),
+            cd_out: self.cd_out.or(defaults.cd_out),
+            inputs: defaults.inputs,
+        };
+        for (line, pin) in self.inputs {
+            pins.add_input(line, pin);
+        }
+        pins
+    }
+
+    /// Attaches the pins to a port, opening them with `backend`.
+    pub fn attach<T, B>(&self, port: T, backend: &mut B) -> io::Result<SerialPortWithGpios<T, B::Output>>
+    where
+        T: SerialPort,
+        B: GpioBackend,
+    {
+        let ri_out = self.ri_out.as_ref().map(|line| backend.open_output(line)).transpose()?;
+        let cd_out = self.cd_out.as_ref().map(|line| backend.open_output(line)).transpose()?;
+        let mut port = SerialPortWithGpios::new(port, ri_out, cd_out);
+        for (control_line, line) in &self.inputs {
+            port = port.with_input(*control_line, LineWatcher::start(backend.open_input(line)?)?);
+        }
+        Ok(port)
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::io;
+
+    use gpio::sysfs::SysFsGpioOutput;
+
+    use super::{GpioBackend, GpioLine, LineId};
+    use crate::gpiocdev::{CdevInput, CdevOutput, GpioChip};
+    use crate::linewatch::SysFsEdgeInput;
+
+    /// Lines on a chip, through the GPIO character device.
+    #[derive(Debug)]
+    pub struct CdevBackend {
+        chip: GpioChip,
+    }
+
+    impl CdevBackend {
+        /// Opens the chip by its device name, e.g. `gpiochip0`, its number, or its path.
+        pub fn open(chip: &str) -> io::Result<Self> {
+            Ok(CdevBackend { chip: GpioChip::open(chip)? })
+        }
+    }
+
+    impl GpioBackend for CdevBackend {
+        type Output = CdevOutput;
+        type Input = CdevInput;
+
+        fn open_output(&mut self, line: &GpioLine) -> io::Result<CdevOutput> {
+            self.chip.request_output(line)
+        }
+
+        fn open_input(&mut self, line: &GpioLine) -> io::Result<CdevInput> {
+            self.chip.request_input(line)
+        }
+    }
+
+    /// Lines by their global numbers, through the deprecated sysfs interface.
+    ///
+    /// Lines can't be found by name, and their bias can't be set.
+    #[derive(Debug, Default)]
+    pub struct SysFsBackend;
+
+    impl SysFsBackend {
+        /// The line's sysfs number, once checked that its settings can be made through sysfs.
+        fn number(line: &GpioLine) -> io::Result<u16> {
+            let unsupported = |message: String| io::Error::new(io::ErrorKind::Unsupported, message);
+            if line.bias.is_some() {
+                return Err(unsupported(format!("GPIO {}: the bias can't be set through sysfs, give a --gpio-chip", line)));
+            }
+            match &line.id {
+                LineId::Number(number) => u16::try_from(*number)
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("GPIO {} out of range", number))),
+                LineId::Name(name) => Err(unsupported(format!("GPIO {}: lines can't be found by name through sysfs, give a --gpio-chip", name))),
+            }
+        }
+
+        /// Sets whether the exported line is active-low, which sysfs applies to both its value and edges.
+        fn set_active_low(number: u16, active_low: bool) -> io::Result<()> {
+            std::fs::write(format!("/sys/class/gpio/gpio{}/active_low", number), if active_low { "1" } else { "0" })
+        }
+    }
+
+    impl GpioBackend for SysFsBackend {
+        type Output = SysFsGpioOutput;
+        type Input = SysFsEdgeInput;
+
+        fn open_output(&mut self, line: &GpioLine) -> io::Result<SysFsGpioOutput> {
+            let number = Self::number(line)?;
+            let output = SysFsGpioOutput::open(number)?;
+            Self::set_active_low(number, line.active_low)?;
+            Ok(output)
+        }
+
+        fn open_input(&mut self, line: &GpioLine) -> io::Result<SysFsEdgeInput> {
+            let number = Self::number(line)?;
+            let input = SysFsEdgeInput::open(number)?;
+            Self::set_active_low(number, line.active_low)?;
+            Ok(input)
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub use linux::{CdevBackend, SysFsBackend};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::portinfo::AdvancedSerialPort;
+    use crate::testing::{MockGpioBackend, MockSerialPort};
+    use gpio::GpioValue;
+
+    fn named(name: &str) -> GpioLine {
+        GpioLine { id: LineId::Name(name.to_string()), active_low: false, bias: None }
+    }
+
+    #[test]
+    fn parses_pins() {
+        assert_eq!(parse_gpio_line("17").unwrap(), GpioLine::number(17));
+        assert_eq!(parse_gpio_line("GPIO17, active-low,Pull-Up").unwrap(),
+            GpioLine { active_low: true, bias: Some(Bias::PullUp), ..named("GPIO17") });
+        assert_eq!(parse_gpio_line("4,bias-disabled").unwrap(), GpioLine { bias: Some(Bias::Disabled), ..GpioLine::number(4) });
+        for pin in ["", ",active-low", "4,sideways", "4,pull-up,pull-down", "99999999999"] {
+            assert!(parse_gpio_line(pin).is_err(), "{:?} accepted", pin);
+        }
+        let pin = "GPIO5,active-low,pull-down";
+        assert_eq!(parse_gpio_line(pin).unwrap().to_string(), pin);
+    }
+
+    #[test]
+    fn parses_inputs() {
+        assert_eq!(parse_gpio_input("ri:22").unwrap(), (ControlLine::Ri, GpioLine::number(22)));
+        assert_eq!(parse_gpio_input("DCD: 5,active-low").unwrap(), (ControlLine::Cd, GpioLine { active_low: true, ..GpioLine::number(5) }));
+        assert_eq!(parse_gpio_input("cts:RING_IN").unwrap(), (ControlLine::Cts, named("RING_IN")));
+        for input in ["ri", "ri:", "ri:4,loud", "tx:4", ":4"] {
+            assert!(parse_gpio_input(input).is_err(), "{:?} accepted", input);
+        }
+    }
+
+    #[test]
+    fn parses_config() {
+        let config = "# Pi header\n\ngpio-chip = gpiochip0\nri-out-gpio = 17\n  cd-out-gpio=GPIO27,active-low\ngpio-in = ri:22\ngpio-in = cd:23\ngpio-in = ri:24,pull-up\n";
+        let pins = GpioPins::parse_config(config).unwrap();
+        assert_eq!(pins, GpioPins {
+            chip: Some("gpiochip0".to_string()),
+            ri_out: Some(GpioLine::number(17)),
+            cd_out: Some(GpioLine { active_low: true, ..named("GPIO27") }),
+            inputs: vec![(ControlLine::Cd, GpioLine::number(23)), (ControlLine::Ri, GpioLine { bias: Some(Bias::PullUp), ..GpioLine::number(24) })],
+        });
+        assert!(!pins.is_empty());
+        assert!(GpioPins::parse_config("# nothing\n").unwrap().is_empty());
+    }
+
+    #[test]
+    fn config_errors_give_line() {
+        for (config, line) in [("ri-out-gpio = 4,sideways", 1), ("\nri-out-gpio 17", 2), ("gpio-in = tx:1", 1), ("\n\nled = 4", 3)] {
+            let error = GpioPins::parse_config(config).unwrap_err();
+            assert!(error.starts_with(&format!("line {}:", line)), "{:?} gave {}", config, error);
+        }
+    }
+
+    #[test]
+    fn config_input_errors_give_reason() {
+        for (config, reason) in [
+            ("gpio-in = ri:22,loud", "unknown GPIO setting: loud"),
+            ("gpio-in = ri:22,pull-up,pull-down", "more than one GPIO bias"),
+            ("gpio-in = 22", "expected line:pin"),
+        ] {
+            let error = GpioPins::parse_config(config).unwrap_err();
+            assert!(error.contains(reason), "{:?} gave {}", config, error);
+        }
+    }
+
+    #[test]
+    fn options_override_config() {
+        let config = GpioPins::parse_config("gpio-chip = gpiochip0\nri-out-gpio = 17\ngpio-in = ri:22\ngpio-in = cd:23").unwrap();
+        let mut options = GpioPins { ri_out: Some(GpioLine::number(5)), ..Default::default() };
+        options.add_input(ControlLine::Cd, GpioLine::number(6));
+        assert_eq!(options.or(config), GpioPins {
+            chip: Some("gpiochip0".to_string()),
+            ri_out: Some(GpioLine::number(5)),
+            cd_out: None,
+            inputs: vec![(ControlLine::Ri, GpioLine::number(22)), (ControlLine::Cd, GpioLine::number(6))],
+        });
+    }
+
+    #[test]
+    fn attaches_through_backend() {
+        let pins = GpioPins::parse_config("ri-out-gpio = 17,active-low\ngpio-in = cd:GPIO22,pull-up").unwrap();
+        let mut backend = MockGpioBackend::new();
+        let mut port = pins.attach(MockSerialPort::new(), &mut backend).unwrap();
+        assert!(port.can_set_ring_indicator());
+        assert!(!port.can_set_carrier_detect());
+
+        let (line, ri) = backend.output(&LineId::Number(17)).unwrap();
+        assert!(line.active_low);
+        port.set_ring_indicator(true).unwrap();
+        assert_eq!(ri.values(), [GpioValue::High]);
+
+        let (line, cd) = backend.input(&LineId::Name("GPIO22".to_string())).unwrap();
+        assert_eq!(line.bias, Some(Bias::PullUp));
+        assert!(!port.read_carrier_detect().unwrap());
+        cd.set(true);
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        assert!(port.read_carrier_detect().unwrap());
+    }
+
+    #[test]
+    fn backend_failure_is_reported() {
+        let pins = GpioPins::parse_config("gpio-in = cd:MISSING").unwrap();
+        let mut backend = MockGpioBackend::new().without_line("MISSING");
+        assert!(pins.attach(MockSerialPort::new(), &mut backend).is_err());
+    }
+}
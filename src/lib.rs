@@ -0,0 +1,47 @@
+//! Serial port capture to PCAP and PCAPNG files.
+//!
+//! This is the engine behind the `serialpcap-rs` command, for embedding
+//! serial capture in other programs such as test harnesses. A
+//! `capture::Capture` reads a serial port and yields `state::SerialEvent`s,
+//! and `capture::run` writes the captures of one or more ports to a
+//! `sink::Sink`, such as a capture file. With the `tokio` feature, ports
+//! can also be captured asynchronously as an `async_capture::AsyncCapture`
+//! stream.
+//!
+//! ```no_run
+//! use std::sync::atomic::AtomicBool;
+//! use std::sync::Arc;
+//! use std::time::Duration;
+//!
+//! use serialpcap_rs::capture::{self, Capture, StopConditions};
+//! use serialpcap_rs::output::CapturedPacket;
+//!
+//! let port = Capture::builder("/dev/ttyUSB0").baud_rate(115200).open()?;
+//! let limits = StopConditions { duration: Some(Duration::from_secs(5)), ..Default::default() };
+//! let mut packets: Vec<CapturedPacket> = Vec::new();
+//! capture::run(vec![(0, port)], &mut packets, &limits, Arc::new(AtomicBool::new(false)))?;
+//! println!("captured {} packets", packets.len());
+//! # Ok::<(), std::io::Error>(())
+//! ```
+
+#[cfg(feature = "tokio")]
+pub mod async_capture;
+pub mod capture;
+pub mod datalink;
+pub mod extcap;
+pub mod framing;
+#[cfg(target_os = "linux")]
+pub mod gpiocdev;
+pub mod gpiopins;
+pub mod linemap;
+pub mod linewatch;
+pub mod merge;
+pub mod output;
+pub mod portinfo;
+pub mod replay;
+pub mod rotate;
+pub mod sink;
+pub mod state;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+mod tty;
@@ -0,0 +1,491 @@
+//! Routing of control lines onto the outputs of another port.
+//!
+//! In a bridge, and when replaying, the control lines seen on one port set
+//! the outputs of another. A `LineMap` says which input drives which
+//! output, and whether it is inverted on the way, for example
+//! `cts->rts,dsr->!dtr,cd->gpio:17`. Presets cover the common cables.
+//!
+//! A `LineRouter` applies a map, holding the GPIO outputs it drives, which
+//! are opened through a `GpioBackend` as a port's GPIO pins are. The port's
+//! own outputs are set with `AnySerialPort::reflect_control_lines`.
+
+use std::fmt;
+use std::io;
+
+use clap::error::Error;
+use gpio::GpioOut;
+
+use crate::gpiopins::{GpioBackend, GpioLine};
+use crate::portinfo::{AnySerialPort, PortControlLines};
+use crate::state::ControlLine;
+
+/// An output a control line can be routed to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineOutput {
+    Rts,
+    Dtr,
+    Cd,         // Only on ports with a Carrier Detect output
+    Ri,         // Only on ports with a Ring Indicator output
+    Gpio(u16),  // A GPIO line, by its offset on the GPIO chip, or its sysfs number without one
+}
+
+impl fmt::Display for LineOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LineOutput::Rts => write!(f, "rts"),
+            LineOutput::Dtr => write!(f, "dtr"),
+            LineOutput::Cd => write!(f, "cd"),
+            LineOutput::Ri => write!(f, "ri"),
+            LineOutput::Gpio(pin) => write!(f, "gpio:{}", pin),
+        }
+    }
+}
+
+/// One input driving one output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineRoute {
+    pub input: ControlLine,     // One of CTS, DSR, CD or RI
+    pub output: LineOutput,
+    pub inverted: bool,         // The output is high while the input is low
+}
+
+impl LineRoute {
+    pub fn new(input: ControlLine, output: LineOutput) -> Self {
+        LineRoute { input, output, inverted: false }
+    }
+
+    /// The level of the output for the given control lines.
+    pub fn level(&self, lines: &PortControlLines) -> bool {
+        let level = match self.input {
+            ControlLine::Cts => lines.cts,
+            ControlLine::Dsr => lines.dsr,
+            ControlLine::Cd => lines.cd,
+            ControlLine::Ri => lines.ri,
+            ControlLine::Rts => lines.rts,
+            ControlLine::Dtr => lines.dtr,
+        };
+        level != self.inverted
+    }
+}
+
+/// Which inputs drive which outputs.
+///
+/// An input may drive several outputs, but each output has one input.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineMap {
+    routes: Vec<LineRoute>,
+}
+
+impl LineMap {
+    /// A map of the given routes, which must each have a different output.
+    pub fn new(routes: Vec<LineRoute>) -> Result<Self, String> {
+        for (i, route) in routes.iter().enumerate() {
+            if routes[..i].iter().any(|earlier| earlier.output == route.output) {
+                return Err(format!("{} is driven by more than one line", route.output));
+            }
+        }
+        Ok(LineMap { routes })
+    }
+
+    /// As if the two DTE connectors were joined by a straight-through
+    /// cable, which joins each pin to the same pin:
+    ///
+    /// * CD -> CD, where the port has a CD output
+    /// * RI -> RI, where the port has an RI output
+    ///
+    /// CTS and DSR aren't carried, as a DTE port can't drive them.
+    pub fn straight_through() -> Self {
+        LineMap {
+            routes: vec![
+                LineRoute::new(ControlLine::Cd, LineOutput::Cd),
+                LineRoute::new(ControlLine::Ri, LineOutput::Ri),
+            ],
+        }
+    }
+
+    /// As if the two DTE connectors were joined by a full handshake
+    /// null-modem cable, which crosses RTS to CTS and DTR to DSR and CD:
+    ///
+    /// * CTS -> RTS
+    /// * DSR -> DTR
+    ///
+    /// CD is tied to DSR on the same connector so drives nothing more, and
+    /// RI isn't connected.
+    pub fn null_modem() -> Self {
+        LineMap {
+            routes: vec![
+                LineRoute::new(ControlLine::Cts, LineOutput::Rts),
+                LineRoute::new(ControlLine::Dsr, LineOutput::Dtr),
+            ],
+        }
+    }
+
+    pub fn routes(&self) -> &[LineRoute] {
+        &self.routes
+    }
+
+    /// The GPIO pins the map drives.
+    pub fn gpio_pins(&self) -> Vec<u16> {
+        self.routes.iter().filter_map(|route| match route.output {
+            LineOutput::Gpio(pin) => Some(pin),
+            _ => None,
+        }).collect()
+    }
+}
+
+impl Default for LineMap {
+    /// How lines were always reflected, the null-modem handshake routes
+    /// along with the straight-through CD and RI routes:
+    ///
+    /// * CTS -> RTS
+    /// * DSR -> DTR
+    /// * CD -> CD, where the port has a CD output
+    /// * RI -> RI, where the port has an RI output
+    fn default() -> Self {
+        LineMap {
+            routes: [Self::null_modem().routes, Self::straight_through().routes].concat(),
+        }
+    }
+}
+
+fn parse_input(input: &str) -> Option<ControlLine> {
+    match input {
+        "cts" => Some(ControlLine::Cts),
+        "dsr" => Some(ControlLine::Dsr),
+        "cd" | "dcd" => Some(ControlLine::Cd),
+        "ri" => Some(ControlLine::Ri),
+        _ => None,
+    }
+}
+
+fn parse_output(output: &str) -> Option<LineOutput> {
+    if let Some(pin) = output.strip_prefix("gpio:") {
+        return pin.parse().ok().map(LineOutput::Gpio);
+    }
+    match output {
+        "rts" => Some(LineOutput::Rts),
+        "dtr" => Some(LineOutput::Dtr),
+        "cd" | "dcd" => Some(LineOutput::Cd),
+        "ri" => Some(LineOutput::Ri),
+        _ => None,
+    }
+}
+
+/// Parses a control line map from a string.
+/// this is used in our clap argument parser.
+///
+/// The map is either a preset, `straight-through` or `null-modem`, or a
+/// comma separated list of `input->output` routes. Inputs are `cts`,
+/// `dsr`, `cd` and `ri`, outputs are `rts`, `dtr`, `cd`, `ri` and
+/// `gpio:<N>`, and a `!` before the output inverts it.
+pub fn parse_line_map(map_str: &str) -> Result<LineMap, Error> {
+    let invalid = |message: String| Error::raw(clap::error::ErrorKind::InvalidValue, message);
+    let lower = map_str.to_lowercase();
+    match lower.as_str() {
+        "straight-through" | "straight" => return Ok(LineMap::straight_through()),
+        "null-modem" => return Ok(LineMap::null_modem()),
+        _ => {},
+    }
+    let mut routes = Vec::new();
+    for route in lower.split(',') {
+        let Some((input, output)) = route.split_once("->") else {
+            return Err(invalid(format!("Invalid control line route: {} (expected input->output, e.g. cts->rts)", route)));
+        };
+        let (output, inverted) = match output.trim().strip_prefix('!') {
+            Some(output) => (output, true),
+            None => (output.trim(), false),
+        };
+        let input = parse_input(input.trim())
+            .ok_or_else(|| invalid(format!("Unknown control line input: {} (expected cts, dsr, cd or ri)", input)))?;
+        let output = parse_output(output)
+            .ok_or_else(|| invalid(format!("Unknown control line output: {} (expected rts, dtr, cd, ri or gpio:<N>)", output)))?;
+        routes.push(LineRoute { input, output, inverted });
+    }
+    LineMap::new(routes).map_err(invalid)
+}
+
+/// A GPIO output of any type, as routes drive them.
+trait OutputPin: Send {
+    fn set_level(&mut self, level: bool) -> io::Result<()>;
+}
+
+impl<G> OutputPin for G
+where
+    G: GpioOut + Send,
+    G::Error: fmt::Debug,
+{
+    fn set_level(&mut self, level: bool) -> io::Result<()> {
+        self.set_value(level).map_err(|e| io::Error::other(format!("{:?}", e)))
+    }
+}
+
+/// Applies a `LineMap`, driving the GPIO outputs it routes lines to.
+pub struct LineRouter {
+    map: LineMap,
+    gpios: Vec<(u16, Box<dyn OutputPin>)>,
+}
+
+impl LineRouter {
+    /// A router for the map, with no GPIOs. Any the map drives are added with `with_gpio`.
+    pub fn new(map: LineMap) -> Self {
+        LineRouter { map, gpios: Vec::new() }
+    }
+
+    /// A router for the map, opening the GPIOs it drives on `chip` through
+    /// the GPIO character device, or through sysfs without a chip.
+    #[cfg(target_os = "linux")]
+    pub fn open(map: LineMap, chip: Option<&str>) -> io::Result<Self> {
+        use crate::gpiopins::{CdevBackend, SysFsBackend};
+
+        if map.gpio_pins().is_empty() {
+            return Ok(LineRouter::new(map));
+        }
+        match chip {
+            Some(chip) => LineRouter::open_with(map, &mut CdevBackend::open(chip)?),
+            None => LineRouter::open_with(map, &mut SysFsBackend),
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn open(map: LineMap, _chip: Option<&str>) -> io::Result<Self> {
+        if !map.gpio_pins().is_empty() {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "GPIO outputs are only supported on Linux"));
+        }
+        Ok(LineRouter::new(map))
+    }
+
+    /// A router for the map, opening the GPIOs it drives with `backend`.
+    pub fn open_with<B>(map: LineMap, backend: &mut B) -> io::Result<Self>
+    where
+        B: GpioBackend,
+        <B::Output as GpioOut>::Error: fmt::Debug,
+    {
+        let mut router = LineRouter::new(map);
+        for pin in router.map.gpio_pins() {
+            router = router.with_gpio(pin, backend.open_output(&GpioLine::number(pin.into()))?);
+        }
+        Ok(router)
+    }
+
+    /// Adds the GPIO output driven as `gpio:<pin>`.
+    pub fn with_gpio<G>(mut self, pin: u16, gpio: G) -> Self
+    where
+        G: GpioOut + Send + 'static,
+        G::Error: fmt::Debug,
+    {
+        self.gpios.retain(|(existing, _)| *existing != pin);
+        self.gpios.push((pin, Box::new(gpio)));
+        self
+    }
+
+    pub fn map(&self) -> &LineMap {
+        &self.map
+    }
+
+    /// Sets a GPIO output the map routes a line to.
+    pub(crate) fn set_gpio(&mut self, pin: u16, level: bool) -> serialport::Result<()> {
+        let Some((_, gpio)) = self.gpios.iter_mut().find(|(existing, _)| *existing == pin) else {
+            return Err(serialport::Error::new(
+                serialport::ErrorKind::Unknown,
+                format!("GPIO {} output not opened", pin),
+            ));
+        };
+        gpio.set_level(level).map_err(|e| {
+            serialport::Error::new(
+                serialport::ErrorKind::Unknown,
+                format!("Failed to set GPIO {}: {}", pin, e),
+            )
+        })
+    }
+
+    /// The outputs of the map which `port` doesn't have, and so are left alone.
+    pub fn unsupported_outputs(&self, port: &AnySerialPort) -> Vec<LineOutput> {
+        self.map.routes.iter().map(|route| route.output).filter(|output| match output {
+            LineOutput::Rts | LineOutput::Dtr => false,
+            LineOutput::Cd | LineOutput::Ri => !port.has_output(*output),
+            LineOutput::Gpio(pin) => !self.gpios.iter().any(|(existing, _)| existing == pin),
+        }).collect()
+    }
+}
+
+impl Default for LineRouter {
+    fn default() -> Self {
+        LineRouter::new(LineMap::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::portinfo::SerialPortWithGpios;
+    use crate::gpiopins::LineId;
+    use crate::testing::{MockGpio, MockGpioBackend, MockSerialPort};
+    use gpio::GpioValue;
+
+    const INPUTS: [ControlLine; 4] = [ControlLine::Cts, ControlLine::Dsr, ControlLine::Cd, ControlLine::Ri];
+
+    /// Control lines with only `input` high.
+    fn only(input: ControlLine) -> PortControlLines {
+        PortControlLines {
+            cts: input == ControlLine::Cts,
+            dsr: input == ControlLine::Dsr,
+            cd: input == ControlLine::Cd,
+            ri: input == ControlLine::Ri,
+            ..Default::default()
+        }
+    }
+
+    /// A port with every kind of output, and the mocks behind them.
+    fn advanced_port() -> (AnySerialPort, MockSerialPort, MockGpio, MockGpio) {
+        let mock = MockSerialPort::new();
+        let (ri, cd) = (MockGpio::new(), MockGpio::new());
+        let port = SerialPortWithGpios::new(mock.clone(), Some(ri.clone()), Some(cd.clone()));
+        (AnySerialPort::Advanced(Box::new(port)), mock, ri, cd)
+    }
+
+    fn high(gpio: &MockGpio) -> bool {
+        gpio.value() == Some(GpioValue::High)
+    }
+
+    #[test]
+    fn parses_routes() {
+        let map = parse_line_map("cts->rts, DSR->!dtr,cd->gpio:17,ri->ri").unwrap();
+        assert_eq!(map.routes(), [
+            LineRoute::new(ControlLine::Cts, LineOutput::Rts),
+            LineRoute { input: ControlLine::Dsr, output: LineOutput::Dtr, inverted: true },
+            LineRoute::new(ControlLine::Cd, LineOutput::Gpio(17)),
+            LineRoute::new(ControlLine::Ri, LineOutput::Ri),
+        ]);
+        assert_eq!(map.gpio_pins(), [17]);
+    }
+
+    #[test]
+    fn parses_presets() {
+        assert_eq!(parse_line_map("straight-through").unwrap(), LineMap::straight_through());
+        assert_eq!(parse_line_map("null-modem").unwrap(), LineMap::null_modem());
+    }
+
+    #[test]
+    fn rejects_bad_maps() {
+        for map in ["cts", "cts->", "rts->rts", "cts->cts", "cts->gpio:x", "cts->rts,dsr->rts", ""] {
+            assert!(parse_line_map(map).is_err(), "{:?} accepted", map);
+        }
+    }
+
+    #[test]
+    fn every_input_drives_port_outputs() {
+        for input in INPUTS {
+            for output in [LineOutput::Rts, LineOutput::Dtr, LineOutput::Cd, LineOutput::Ri] {
+                for inverted in [false, true] {
+                    let (mut port, mock, ri, cd) = advanced_port();
+                    let mut router = LineRouter::new(LineMap::new(vec![LineRoute { input, output, inverted }]).unwrap());
+                    for level in [true, false] {
+                        let lines = if level { only(input) } else { PortControlLines::default() };
+                        port.reflect_control_lines(&lines, &mut router).unwrap();
+                        let set = match output {
+                            LineOutput::Rts => mock.current_control_lines().rts,
+                            LineOutput::Dtr => mock.current_control_lines().dtr,
+                            LineOutput::Cd => high(&cd),
+                            LineOutput::Ri => high(&ri),
+                            LineOutput::Gpio(_) => unreachable!(),
+                        };
+                        assert_eq!(set, level != inverted, "{:?} -> {}{}", input, if inverted { "!" } else { "" }, output);
+                    }
+                    // Only the routed output is touched
+                    if output != LineOutput::Cd {
+                        assert!(cd.values().is_empty());
+                    }
+                    if output != LineOutput::Ri {
+                        assert!(ri.values().is_empty());
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn every_input_drives_gpio() {
+        for input in INPUTS {
+            for inverted in [false, true] {
+                let gpio = MockGpio::new();
+                let map = LineMap::new(vec![LineRoute { input, output: LineOutput::Gpio(17), inverted }]).unwrap();
+                let mut router = LineRouter::new(map).with_gpio(17, gpio.clone());
+                let mock = MockSerialPort::new();
+                let mut port = AnySerialPort::Basic(Box::new(mock.clone()));
+                port.reflect_control_lines(&only(input), &mut router).unwrap();
+                port.reflect_control_lines(&PortControlLines::default(), &mut router).unwrap();
+                let expected = if inverted { [GpioValue::Low, GpioValue::High] } else { [GpioValue::High, GpioValue::Low] };
+                assert_eq!(gpio.values(), expected, "{:?} inverted {}", input, inverted);
+                assert_eq!(mock.current_control_lines(), PortControlLines::default());
+            }
+        }
+    }
+
+    #[test]
+    fn presets_have_documented_routes() {
+        assert_eq!(LineMap::straight_through().routes(), [
+            LineRoute::new(ControlLine::Cd, LineOutput::Cd),
+            LineRoute::new(ControlLine::Ri, LineOutput::Ri),
+        ]);
+        assert_eq!(LineMap::null_modem().routes(), [
+            LineRoute::new(ControlLine::Cts, LineOutput::Rts),
+            LineRoute::new(ControlLine::Dsr, LineOutput::Dtr),
+        ]);
+        assert_eq!(LineMap::default().routes(), [
+            LineRoute::new(ControlLine::Cts, LineOutput::Rts),
+            LineRoute::new(ControlLine::Dsr, LineOutput::Dtr),
+            LineRoute::new(ControlLine::Cd, LineOutput::Cd),
+            LineRoute::new(ControlLine::Ri, LineOutput::Ri),
+        ]);
+    }
+
+    #[test]
+    fn presets_route_as_documented() {
+        let lines = PortControlLines { cts: true, dsr: true, cd: true, ri: true, ..Default::default() };
+        let (mut port, mock, ri, cd) = advanced_port();
+        port.reflect_control_lines(&lines, &mut LineRouter::new(LineMap::straight_through())).unwrap();
+        assert_eq!(mock.current_control_lines(), PortControlLines::default());
+        assert!(high(&cd) && high(&ri));
+
+        let (mut port, mock, ri, cd) = advanced_port();
+        port.reflect_control_lines(&lines, &mut LineRouter::new(LineMap::null_modem())).unwrap();
+        assert!(mock.current_control_lines().rts && mock.current_control_lines().dtr);
+        assert!(cd.values().is_empty() && ri.values().is_empty());
+    }
+
+    #[test]
+    fn basic_port_lacks_cd_and_ri_outputs() {
+        let mock = MockSerialPort::new();
+        let mut port = AnySerialPort::Basic(Box::new(mock.clone()));
+        let mut router = LineRouter::new(parse_line_map("cts->rts,cd->cd,ri->ri,dsr->gpio:4").unwrap());
+        assert_eq!(router.unsupported_outputs(&port), [LineOutput::Cd, LineOutput::Ri, LineOutput::Gpio(4)]);
+        // The missing port outputs are left alone, but the GPIO was asked for
+        assert!(port.reflect_control_lines(&PortControlLines { cts: true, ..Default::default() }, &mut router).is_err());
+        assert!(mock.current_control_lines().rts);
+
+        let (port, ..) = advanced_port();
+        assert!(LineRouter::default().unsupported_outputs(&port).is_empty());
+    }
+
+    #[test]
+    fn opens_gpios_through_the_backend() {
+        let backend = MockGpioBackend::new();
+        let map = parse_line_map("cts->gpio:4,dsr->!gpio:5,cd->rts").unwrap();
+        let mut router = LineRouter::open_with(map, &mut backend.clone()).unwrap();
+        let mut port = AnySerialPort::Basic(Box::new(MockSerialPort::new()));
+        port.reflect_control_lines(&PortControlLines { cts: true, ..Default::default() }, &mut router).unwrap();
+        let (line, gpio) = backend.output(&LineId::Number(4)).unwrap();
+        assert_eq!(line, GpioLine::number(4));
+        assert_eq!(gpio.values(), [GpioValue::High]);
+        assert_eq!(backend.output(&LineId::Number(5)).unwrap().1.values(), [GpioValue::High]);
+        assert!(router.unsupported_outputs(&port).is_empty());
+    }
+
+    #[test]
+    fn gpio_failure_is_reported() {
+        let map = parse_line_map("cts->gpio:4").unwrap();
+        let mut router = LineRouter::new(map).with_gpio(4, MockGpio::failing());
+        let mut port = AnySerialPort::Basic(Box::new(MockSerialPort::new()));
+        assert!(port.reflect_control_lines(&PortControlLines::default(), &mut router).is_err());
+    }
+}
@@ -0,0 +1,284 @@
+//! Control lines read from GPIO inputs.
+//!
+//! Adapters which don't bring out CD or RI, or any other control line, can
+//! have the signal wired to a GPIO input instead. A `LineWatcher` waits for
+//! the edges of the input on a thread of its own and queues them, so a pulse
+//! shorter than the time between two reads of the control lines, such as a
+//! ring, is still seen.
+//!
+//! Inputs are `EdgeInput`s. On Linux, `gpiocdev::CdevInput` and
+//! `SysFsEdgeInput` wait for the edges of a GPIO character device line and a
+//! sysfs GPIO, while `PolledInput` samples any `gpio::GpioIn`.
+
+use std::collections::VecDeque;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use gpio::{GpioIn, GpioValue};
+
+/// How long a wait for an edge lasts before checking whether to stop.
+const WATCH_TIMEOUT: Duration = Duration::from_millis(100);
+/// How many edges are held for reading, the oldest are dropped beyond this.
+const MAX_QUEUED_EDGES: usize = 64;
+
+/// A GPIO input which can wait for its level to change.
+pub trait EdgeInput: Send {
+    /// Reads the current level.
+    fn read_level(&mut self) -> io::Result<bool>;
+
+    /// Waits up to `timeout` for an edge, returning the new level, or `None` if there wasn't one.
+    fn wait_for_edge(&mut self, timeout: Duration) -> io::Result<Option<bool>>;
+}
+
+/// Edges on any `GpioIn`, found by sampling it.
+///
+/// For GPIOs without edge detection. Pulses shorter than `interval` may be missed.
+pub struct PolledInput<G> {
+    gpio: G,
+    interval: Duration,
+    last: Option<bool>,
+}
+
+impl<G: GpioIn> PolledInput<G> {
+    pub fn new(gpio: G, interval: Duration) -> Self {
+        PolledInput { gpio, interval, last: None }
+    }
+}
+
+impl<G> EdgeInput for PolledInput<G>
+where
+    G: GpioIn + Send,
+    G::Error: std::fmt::Debug,
+{
+    fn read_level(&mut self) -> io::Result<bool> {
+        let level = self.gpio.read_value()
+            .map_err(|e| io::Error::other(format!("{:?}", e)))? == GpioValue::High;
+        self.last = Some(level);
+        Ok(level)
+    }
+
+    fn wait_for_edge(&mut self, timeout: Duration) -> io::Result<Option<bool>> {
+        let last = match self.last {
+            Some(last) => last,
+            None => self.read_level()?,
+        };
+        let deadline = Instant::now() + timeout;
+        loop {
+            let level = self.read_level()?;
+            if level != last {
+                return Ok(Some(level));
+            }
+            let Some(wait) = deadline.checked_duration_since(Instant::now()) else {
+                return Ok(None);
+            };
+            thread::sleep(wait.min(self.interval));
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod sysfs {
+    use std::fs::File;
+    use std::io::{self, Read, Seek, SeekFrom};
+    use std::os::unix::io::AsRawFd;
+    use std::time::Duration;
+
+    use gpio::sysfs::SysFsGpioInput;
+
+    use super::EdgeInput;
+
+    /// A sysfs GPIO input, with the kernel reporting its edges.
+    pub struct SysFsEdgeInput {
+        _gpio: SysFsGpioInput,  // Keeps the pin exported as an input
+        value: File,
+    }
+
+    impl SysFsEdgeInput {
+        /// Exports the pin as an input which reports both edges.
+        pub fn open(gpio_num: u16) -> io::Result<Self> {
+            let gpio = SysFsGpioInput::open(gpio_num)?;
+            std::fs::write(format!("/sys/class/gpio/gpio{}/edge", gpio_num), "both")?;
+            let value = File::open(format!("/sys/class/gpio/gpio{}/value", gpio_num))?;
+            Ok(SysFsEdgeInput { _gpio: gpio, value })
+        }
+    }
+
+    impl EdgeInput for SysFsEdgeInput {
+        fn read_level(&mut self) -> io::Result<bool> {
+            // Reading the value also clears the pending edge
+            let mut value = [0; 2];
+            self.value.seek(SeekFrom::Start(0))?;
+            let read_len = self.value.read(&mut value)?;
+            match &value[..read_len.min(1)] {
+                b"0" => Ok(false),
+                b"1" => Ok(true),
+                _ => Err(io::Error::new(io::ErrorKind::InvalidData, "GPIO value is neither 0 nor 1")),
+            }
+        }
+
+        fn wait_for_edge(&mut self, timeout: Duration) -> io::Result<Option<bool>> {
+            let mut poll_fd = libc::pollfd { fd: self.value.as_raw_fd(), events: libc::POLLPRI | libc::POLLERR, revents: 0 };
+            let timeout_ms = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+            // SAFETY: `poll_fd` is a valid pollfd, and the count of one matches it.
+            let result = unsafe { libc::poll(&mut poll_fd, 1, timeout_ms) };
+            match result {
+                0 => Ok(None),
+                _ if result < 0 => {
+                    let e = io::Error::last_os_error();
+                    if e.kind() == io::ErrorKind::Interrupted { Ok(None) } else { Err(e) }
+                },
+                _ => self.read_level().map(Some),
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub use sysfs::SysFsEdgeInput;
+
+/// What a watcher has seen of its input.
+#[derive(Debug)]
+struct Latch {
+    level: bool,
+    edges: VecDeque<bool>,  // The levels after each edge not yet read
+    error: Option<String>,  // Why watching the input failed
+}
+
+/// Watches an input for edges, queuing them to be read in order.
+pub struct LineWatcher {
+    latch: Arc<Mutex<Latch>>,
+    stop: Arc<AtomicBool>,
+}
+
+impl LineWatcher {
+    /// Reads the input's level, then starts watching it for edges.
+    pub fn start<I: EdgeInput + 'static>(mut input: I) -> io::Result<Self> {
+        let latch = Arc::new(Mutex::new(Latch { level: input.read_level()?, edges: VecDeque::new(), error: None }));
+        let stop = Arc::new(AtomicBool::new(false));
+        let watcher = LineWatcher { latch: latch.clone(), stop: stop.clone() };
+        thread::spawn(move || {
+            while !stop.load(Ordering::Relaxed) {
+                match input.wait_for_edge(WATCH_TIMEOUT) {
+                    Ok(Some(level)) => {
+                        let mut latch = latch.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+                        // An edge back to the same level was a pulse, over by the time it was read
+                        let edges = if level == latch.level { vec![!level, level] } else { vec![level] };
+                        for level in edges {
+                            if latch.edges.len() == MAX_QUEUED_EDGES {
+                                latch.edges.pop_front();
+                            }
+                            latch.edges.push_back(level);
+                        }
+                        latch.level = level;
+                    },
+                    Ok(None) => {},
+                    Err(e) => {
+                        latch.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).error = Some(e.to_string());
+                        break;
+                    },
+                }
+            }
+        });
+        Ok(watcher)
+    }
+
+    fn latch(&self) -> MutexGuard<'_, Latch> {
+        self.latch.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Reads the level after the oldest edge not yet read, or the current
+    /// level if they have all been read.
+    pub fn read_level(&mut self) -> serialport::Result<bool> {
+        let mut latch = self.latch();
+        if let Some(error) = latch.error.as_ref() {
+            return Err(serialport::Error::new(
+                serialport::ErrorKind::Unknown,
+                format!("Failed to read GPIO input: {}", error),
+            ));
+        }
+        let level = latch.level;
+        Ok(latch.edges.pop_front().unwrap_or(level))
+    }
+}
+
+impl Drop for LineWatcher {
+    fn drop(&mut self) {
+        // The thread ends, and drops the input, at the end of its current wait
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MockGpioInput;
+
+    /// Gives the watcher thread time to see the edges.
+    fn settle() {
+        thread::sleep(Duration::from_millis(20));
+    }
+
+    #[test]
+    fn reads_initial_level() {
+        let mut watcher = LineWatcher::start(MockGpioInput::new(true)).unwrap();
+        assert!(watcher.read_level().unwrap());
+        assert!(watcher.read_level().unwrap());
+    }
+
+    #[test]
+    fn pulse_between_reads_is_seen() {
+        let input = MockGpioInput::new(false);
+        let mut watcher = LineWatcher::start(input.clone()).unwrap();
+        input.set(true);
+        input.set(false);
+        settle();
+        assert!(watcher.read_level().unwrap());
+        assert!(!watcher.read_level().unwrap());
+        assert!(!watcher.read_level().unwrap());
+    }
+
+    #[test]
+    fn edge_to_same_level_is_a_pulse() {
+        let input = MockGpioInput::new(false);
+        let mut watcher = LineWatcher::start(input.clone()).unwrap();
+        input.edge(false);
+        settle();
+        assert!(watcher.read_level().unwrap());
+        assert!(!watcher.read_level().unwrap());
+    }
+
+    #[test]
+    fn edges_are_capped() {
+        let input = MockGpioInput::new(false);
+        let mut watcher = LineWatcher::start(input.clone()).unwrap();
+        for i in 0..MAX_QUEUED_EDGES + 10 {
+            input.set(i % 2 == 0);
+        }
+        settle();
+        let levels: Vec<bool> = (0..MAX_QUEUED_EDGES + 1).map(|_| watcher.read_level().unwrap()).collect();
+        // The newest edges are kept, the last is low, then it stays there
+        assert_eq!(levels[MAX_QUEUED_EDGES - 2..], [true, false, false]);
+    }
+
+    #[test]
+    fn input_failure_is_reported() {
+        let input = MockGpioInput::new(false);
+        let mut watcher = LineWatcher::start(input.clone()).unwrap();
+        input.fail();
+        settle();
+        assert!(watcher.read_level().is_err());
+    }
+
+    #[test]
+    fn polled_input_finds_edges() {
+        let input = MockGpioInput::new(false);
+        let mut polled = PolledInput::new(input.clone(), Duration::from_millis(1));
+        assert!(!polled.read_level().unwrap());
+        assert_eq!(polled.wait_for_edge(Duration::from_millis(5)).unwrap(), None);
+        input.set(true);
+        assert_eq!(polled.wait_for_edge(Duration::from_millis(5)).unwrap(), Some(true));
+    }
+}
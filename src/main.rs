@@ -10,208 +10,100 @@
 //! - Adjustable inter-frame gap timing
 //! - Output to either files or named pipes
 //! - Automatic timestamp recording
-//! - PCAP format compatibility
+//! - PCAP and PCAPNG format compatibility
 //!
 //! # Example Usage
 //!
 //! ```bash
 //! serialpcap /dev/ttyUSB0 -b 115200 -y n -p 1 -g 10 -o output
+//! serialpcap --bridge /dev/ttyUSB0 /dev/ttyUSB1 -b 115200 -o bridged
+//! serialpcap replay -b 115200 output.pcap /dev/ttyUSB1
 //! ```
 
 
-use core::str;
 use std::fs::File;
-use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
-use clap::{value_parser, Arg, Command, ArgAction};
-use serialport::{SerialPort, SerialPortType};
-use pcap_file::{pcap::{PcapPacket, PcapWriter}, pcapng::blocks::packet, DataLink};
-use chrono::prelude::*;
-use crate::{datalink::parse_datalink, portinfo::{AnySerialPort, PortControlLines}};
+use serialpcap_rs::state::ControlLine;
+use clap::{value_parser, Arg, ArgMatches, Command, ArgAction};
+use chrono::Utc;
+use serialpcap_rs::{capture::{self, bridge_interface_info, char_time, parse_gap_chars, tap_interface_info, Capture, EncapsulationMode, StopConditions, MAX_PACKET_SIZE}, datalink::{self, parse_datalink}, extcap, framing::{parse_framing, FramingMode}, gpiopins::{parse_gpio_input, parse_gpio_line, GpioLine, GpioPins}, linemap::{parse_line_map, LineMap, LineOutput, LineRouter}, output::{parse_format, InterfaceInfo, OutputFormat}, replay::{self, parse_direction, parse_speed, Pacing}, rotate::{parse_ring_buffer, RingBufferOption, RotatingWriter, RotationPolicy}, state::Direction};
 
-pub mod datalink;
-pub mod portinfo;
-mod state;
-
-/// Represents the encapsulation mode used for the captured data.
-pub enum EncapsulationMode {
-    Raw,
-    DatalinkType
+/// Stops the capture or replay on SIGINT or SIGTERM.
+fn stop_on_signal() -> Arc<AtomicBool> {
+    let stop = Arc::new(AtomicBool::new(false));
+    let handler_stop = stop.clone();
+    ctrlc::set_handler(move || handler_stop.store(true, Ordering::Relaxed))
+        .expect("Failed to set signal handler");
+    stop
 }
 
-const MAX_PACKET_SIZE: usize = 2048; // Maximum size for a packet in bytes
-
-/// Represents a serial port capture session with configurable parameters
-/// 
-/// # Fields
-/// 
-/// * `port` - The serial port interface
-/// * `baud_rate` - The communication speed in bits per second
-/// * `parity` - Parity checking mode ('n' for none, 'e' for even, 'o' for odd)
-/// * `stopbits` - Number of stop bits (1 or 2)
-/// * `frame_gap_ms` - Time gap between frames in milliseconds
-struct CaptureSerial {
-   port: AnySerialPort,
-   datalink: DataLink,
-   bus_name: String,
-   baud_rate: u32,
-   parity: char,
-   stopbits: u8,
-   frame_gap_ms: u64,
-   encap_mode: EncapsulationMode,
-   delayed_error: Option<io::Error>,
+/// Warns of the outputs a line map given on the command line can't set.
+fn warn_unrouted(option: &str, outputs: &[LineOutput]) {
+    for output in outputs {
+        eprintln!("Warning: {} routes a line to {}, which the port doesn't have", option, output);
+    }
 }
 
-
-
-impl CaptureSerial {
-    fn new(port_name: &str, baud_rate: u32, parity: char, stopbits: u8, frame_gap_ms: u64, datalink: DataLink, encap_mode: EncapsulationMode) -> io::Result<Self> {
-        let port = AnySerialPort::Basic(serialport::new(port_name, baud_rate)
-            .parity(match parity {
-                'o' => serialport::Parity::Odd,
-                'e' => serialport::Parity::Even,
-                _ => serialport::Parity::None,
-            })
-            .stop_bits(match stopbits {
-                1 => serialport::StopBits::One,
-                2 => serialport::StopBits::Two,
-                _ => serialport::StopBits::One,
-            })
-            .timeout(Duration::from_millis(frame_gap_ms))
-            .open()?);
-
-        Ok(CaptureSerial {
-            port,
-            baud_rate,
-            parity,
-            stopbits,
-            frame_gap_ms,
-            datalink,
-            bus_name: port_name.to_string(),
-            encap_mode,
-            delayed_error: None,
-        })
+/// The GPIO pins given by the options, with those not given from the config file.
+fn gpio_pins(matches: &ArgMatches) -> GpioPins {
+    let mut pins = GpioPins {
+        chip: matches.get_one::<String>("gpio-chip").cloned(),
+        ri_out: matches.get_one::<GpioLine>("ri-out-gpio").cloned(),
+        cd_out: matches.get_one::<GpioLine>("cd-out-gpio").cloned(),
+        inputs: Vec::new(),
+    };
+    for (line, pin) in matches.get_many::<(ControlLine, GpioLine)>("gpio-in").unwrap_or_default() {
+        pins.add_input(*line, pin.clone());
     }
+    match matches.get_one::<String>("gpio-config") {
+        Some(config) => pins.or(GpioPins::read_config(config).expect("Failed to read GPIO config file")),
+        None => pins,
+    }
+}
 
+/// Replays a capture onto a serial port, the `replay` subcommand.
+fn run_replay(matches: &ArgMatches) {
+    let baud_rate = *matches.get_one::<u32>("baud").unwrap();
+    let parity = *matches.get_one::<char>("parity").unwrap();
+    let stopbits = *matches.get_one::<u8>("stopbits").unwrap();
+    let file_name = matches.get_one::<String>("file").unwrap();
+    let port_name = matches.get_one::<String>("port").unwrap();
+    let interface_id = *matches.get_one::<u32>("interface").unwrap();
+    let pacing = if matches.get_flag("fast") {
+        Pacing::AsFastAsPossible
+    } else {
+        Pacing::Speed(*matches.get_one::<f64>("speed").unwrap())
+    };
 
-    /// Captures a packet from the serial port
-    ///
-    /// # Returns
-    /// 
-    /// An `Option<Vec<u8>>` containing the captured packet data. Returns `None` if no data is captured.
-    fn capture_packet(&mut self) -> Result<state::SerialEvent, io::Error> {
-
-        if let Some(err) = self.delayed_error.take() {
-            return Err(err);
-        }
-
-        let mut buffer: Vec<u8> = vec![0; MAX_PACKET_SIZE];
-        let mut bytes_read = 0;
-
-        let mut control_lines_last = self.port.capture_control_lines()?;
-        while match self.port.as_serial_port().read(&mut buffer[bytes_read..]) {
-
-            Ok(this_read_len) => {
-                bytes_read += this_read_len;
-                bytes_read < buffer.len()
-            },
-            Err(e) => {
-                if e.kind() == io::ErrorKind::TimedOut {
-                    // Timeout is expected, but
-                    // indicates the end of a packet.
-                    false
-                } else {
-                    // Handle other errors
-                    if bytes_read == 0 {
-                        // If no bytes were read, return the error
-                        return Err(e);
-                    }
-                    self.delayed_error =  Some(e);
-                    return Ok(
-                        state::SerialEvent {
-                            timestamp: Utc::now(),
-                            data: buffer[..bytes_read].to_vec(),
-                            control_lines: control_lines_last,
-                        })
-                }
-            },
-        }  {
-                let current_control_lines = self.port.capture_control_lines()?;
-                if current_control_lines != control_lines_last {
-                    // If control lines have changed, we consider this a new packet
-                    return Ok(
-                        state::SerialEvent::new(
-                            buffer,
-                            bytes_read,
-                            control_lines_last)
-                        )
-                }
-        }
-        Ok(state::SerialEvent::new(buffer, bytes_read, control_lines_last))
+    let file = File::open(file_name).expect("Failed to open capture file");
+    let records = replay::read_capture(file, interface_id).expect("Failed to read capture file");
+    let records = match replay::select_direction(records, matches.get_one::<Direction>("direction").copied()) {
+        Ok(records) => records,
+        Err(e) => {
+            eprintln!("Error occurred: {}", e);
+            std::process::exit(2);
+        },
+    };
+    let mut port = replay::open_port(port_name, baud_rate, parity, stopbits, &gpio_pins(matches)).expect("Failed to open serial port");
+    let map = matches.get_one::<LineMap>("map");
+    let chip = matches.get_one::<String>("gpio-chip").map(String::as_str);
+    let mut lines = LineRouter::open(map.cloned().unwrap_or_default(), chip).expect("Failed to open GPIO output");
+    if map.is_some() {
+        warn_unrouted("--map", &lines.unsupported_outputs(&port));
     }
+    let stop = stop_on_signal();
 
-    /// Captures data from the serial port and writes it to a PCAP file
-    /// 
-    /// # Arguments
-    ///     
-    /// * `file` - The output file to write the captured data to
-    fn capture(&mut self, file: File) -> io::Result<()> {
-        // Setup PCap Header to set our datalink type.
-        let pcap_header = pcap_file::pcap::PcapHeader {
-            version_major: 2,
-            version_minor: 4,
-            snaplen: MAX_PACKET_SIZE as u32,
-            datalink: self.datalink.clone(),
-            ts_correction: 0,
-            ts_accuracy: 0,
-            ts_resolution: pcap_file::TsResolution::MicroSecond,
-            endianness: pcap_file::Endianness::Big
-        };
-        let mut writer = PcapWriter::with_header(file, pcap_header).expect("Error writing output file");
-        let zero_time = Utc::now().timestamp_micros(); // Initialize zero time
-        let mut control_lines = self.port.capture_control_lines().
-                    unwrap_or_default(); // Get initial control lines state
-        loop {
-            let packet_maybe = self.capture_packet(); 
-            if let Ok(packet) = packet_maybe {
-                if packet.is_insignificant(&control_lines) {
-                    continue;
-                }
-
-                // Encapsulate the packet data for the datalink type/force raw
-                let encap_packet = match self.encap_mode {
-                    EncapsulationMode::Raw => packet.data.clone(),
-                    EncapsulationMode::DatalinkType => {
-                        // Use the datalink type to encapsulate the data
-                        datalink::get_encapsulated_data(
-                            packet, &self.bus_name, &self.datalink
-                        ).unwrap()
-                    }
-                };
-                writer.write_packet(
-                    &PcapPacket {
-                        timestamp: Duration::from_micros(
-                            ((Utc::now().timestamp_micros() - zero_time) as i64).try_into().unwrap()
-                        ),
-                        orig_len: encap_packet.len() as u32,
-                        data: encap_packet.into(),
-                    }
-                ).unwrap();
-            } else {
-                // Recast error to io::Error
-                if let Err(e) = packet_maybe {
-                    return Err(e);
-                }
-                return Err(io::Error::new(io::ErrorKind::Other, "Unknown error"));
-            }
-        }
+    match replay::replay(&mut port, &records, pacing, &mut lines, &stop) {
+        Ok(stats) => eprintln!("Replayed {} packets ({} bytes), {} control line changes",
+            stats.packets, stats.bytes, stats.control_line_changes),
+        Err(e) => eprintln!("Error occurred: {}", e),
     }
 }
 
-
-
 fn main() {
-    let matches = Command::new("SerialPCAP")
+    let mut command = Command::new("SerialPCAP")
         .version("1.0")
         .author("Author Name <email@example.com>")
         .about("Captures serial port data and writes to a pcap file")
@@ -221,6 +113,7 @@ fn main() {
             .value_name("BAUD")
             .default_value("9600")
             .value_parser(value_parser!(u32))
+            .global(true)
             .help("Serial port speed (default 9600)"))
         .arg(Arg::new("parity")
             .short('y')
@@ -228,6 +121,7 @@ fn main() {
             .value_name("PARITY")
             .default_value("n")
             .value_parser(value_parser!(char))
+            .global(true)
             .help("o (=odd) | e (=even) | n (=none) (default none)"))
         .arg(Arg::new("stopbits")
             .short('p')
@@ -235,7 +129,37 @@ fn main() {
             .value_name("STOPBITS")
             .value_parser(value_parser!(u8))
             .default_value("1")
+            .global(true)
             .help("1 | 2 (default 1)"))
+        .arg(Arg::new("gpio-chip")
+            .long("gpio-chip")
+            .value_name("CHIP")
+            .global(true)
+            .help("The GPIO chip the pins are on, e.g. gpiochip0, opened through its character device (default sysfs GPIO numbers)"))
+        .arg(Arg::new("ri-out-gpio")
+            .long("ri-out-gpio")
+            .value_name("PIN")
+            .value_parser(parse_gpio_line)
+            .global(true)
+            .help("GPIO driving the port's RI output, as set by a replay, a pin number or name, then any of ,active-low ,pull-up ,pull-down ,bias-disabled"))
+        .arg(Arg::new("cd-out-gpio")
+            .long("cd-out-gpio")
+            .value_name("PIN")
+            .value_parser(parse_gpio_line)
+            .global(true)
+            .help("GPIO driving the port's CD output, as set by a replay, as for --ri-out-gpio"))
+        .arg(Arg::new("gpio-in")
+            .long("gpio-in")
+            .value_name("LINE:PIN")
+            .value_parser(parse_gpio_input)
+            .action(ArgAction::Append)
+            .global(true)
+            .help("Read a control line from a GPIO input rather than the port, e.g. ri:22 or ri:GPIO22,pull-up (repeatable)"))
+        .arg(Arg::new("gpio-config")
+            .long("gpio-config")
+            .value_name("FILE")
+            .global(true)
+            .help("Read the GPIO options from a file of option = value lines, those given on the command line take precedence"))
         .arg(Arg::new("gap")
             .short('g')
             .long("gap")
@@ -243,6 +167,12 @@ fn main() {
             .default_value("10")
             .value_parser(value_parser!(u64))
             .help("Inter frame gap in milliseconds (default 10)"))
+        .arg(Arg::new("gap-chars")
+            .long("gap-chars")
+            .value_name("CHARS")
+            .value_parser(parse_gap_chars)
+            .conflicts_with("gap")
+            .help("Inter frame gap in character times, e.g. 3.5 for Modbus RTU (instead of --gap)"))
         .arg(Arg::new("output")
             .short('o')
             .long("output")
@@ -252,44 +182,343 @@ fn main() {
             .long("pipe")
             .action(ArgAction::SetTrue)
             .help("Pipe mode: treat the output file as exact name not a prefix"))
+        .arg(Arg::new("ring-buffer")
+            .long("ring-buffer")
+            .value_name("OPTION")
+            .value_parser(parse_ring_buffer)
+            .action(ArgAction::Append)
+            .conflicts_with_all(["pipe", "fifo"])
+            .help("Switch output files: filesize:KB | duration:SECS | interval:SECS, keeping at most files:N (repeatable)"))
+        .arg(Arg::new("duration")
+            .long("duration")
+            .value_name("SECS")
+            .value_parser(value_parser!(u64))
+            .help("Stop capturing after this many seconds"))
+        .arg(Arg::new("packet-count")
+            .long("packet-count")
+            .value_name("COUNT")
+            .value_parser(value_parser!(u64))
+            .help("Stop capturing after this many packets"))
+        .arg(Arg::new("byte-count")
+            .long("byte-count")
+            .value_name("COUNT")
+            .value_parser(value_parser!(u64))
+            .help("Stop capturing after this many bytes of serial data"))
+        .arg(Arg::new("relative-time")
+            .long("relative-time")
+            .action(ArgAction::SetTrue)
+            .help("Write timestamps relative to the start of the capture instead of wall-clock time"))
         .arg(Arg::new("raw")
             .long("force-raw")
             .num_args(0)
             .help("Use raw encapsulation instead of datalink type"))
+        .arg(Arg::new("format")
+            .short('F')
+            .long("format")
+            .value_name("FORMAT")
+            .value_parser(parse_format)
+            .default_value("pcap")
+            .help("Output file format: pcap | pcapng (default pcap)"))
         .arg(Arg::new("datalinktype")
             .long("datalinktype")
-            .value_parser(&parse_datalink)
+            .value_parser(parse_datalink)
             .help("Datalink type (default USER0)")
             .default_value("USER0")
         )
+        .arg(Arg::new("framing")
+            .long("framing")
+            .value_name("FRAMING")
+            .value_parser(parse_framing)
+            .default_value("gap")
+            .help("Framing engine: gap | modbus-rtu | line | delimiter:<HEX> | slip | cobs | hdlc | hdlc32 (default gap)"))
+        .arg(Arg::new("decode")
+            .long("decode")
+            .action(ArgAction::SetTrue)
+            .help("Write the decoded payload of delimited frames instead of the wire bytes"))
+        .arg(Arg::new("mark-errors")
+            .long("mark-errors")
+            .action(ArgAction::SetTrue)
+            .help("Record breaks and parity and framing errors at the bytes they happened on (Linux only)"))
+        .arg(Arg::new("tap")
+            .long("tap")
+            .num_args(2)
+            .value_names(["DTE_PORT", "DCE_PORT"])
+            .conflicts_with("port")
+            .help("Tap mode: capture a full-duplex link from the ports hearing the DTE and DCE transmit lines"))
+        .arg(Arg::new("bridge")
+            .long("bridge")
+            .num_args(2)
+            .value_names(["DTE_PORT", "DCE_PORT"])
+            .conflicts_with_all(["port", "tap"])
+            .help("Bridge mode: splice into a link, copying data and control lines between the ports connected to the DTE and DCE"))
+        .arg(Arg::new("map")
+            .long("map")
+            .value_name("MAP")
+            .value_parser(parse_line_map)
+            .requires("bridge")
+            .help("Bridge mode: how each side's control lines drive the other's outputs, straight-through | null-modem | e.g. 'cts->rts,dsr->!dtr' (default cts->rts,dsr->dtr,cd->cd,ri->ri)"))
+        .arg(Arg::new("map-to-dce")
+            .long("map-to-dce")
+            .value_name("MAP")
+            .value_parser(parse_line_map)
+            .requires("bridge")
+            .help("Bridge mode: how the DTE side's control lines drive the DCE side's outputs, including gpio:<N> (overrides --map)"))
+        .arg(Arg::new("map-to-dte")
+            .long("map-to-dte")
+            .value_name("MAP")
+            .value_parser(parse_line_map)
+            .requires("bridge")
+            .help("Bridge mode: how the DCE side's control lines drive the DTE side's outputs, including gpio:<N> (overrides --map)"))
+        .arg(Arg::new("extcap-interfaces")
+            .long("extcap-interfaces")
+            .action(ArgAction::SetTrue)
+            .help("Wireshark extcap: list the serial ports"))
+        .arg(Arg::new("extcap-interface")
+            .long("extcap-interface")
+            .value_name("PORT")
+            .conflicts_with_all(["port", "tap", "bridge"])
+            .help("Wireshark extcap: the serial port to query or capture"))
+        .arg(Arg::new("extcap-dlts")
+            .long("extcap-dlts")
+            .action(ArgAction::SetTrue)
+            .requires("extcap-interface")
+            .help("Wireshark extcap: list the datalink types of the port"))
+        .arg(Arg::new("extcap-config")
+            .long("extcap-config")
+            .action(ArgAction::SetTrue)
+            .requires("extcap-interface")
+            .help("Wireshark extcap: list the capture options of the port"))
+        .arg(Arg::new("extcap-version")
+            .long("extcap-version")
+            .value_name("VERSION")
+            .num_args(0..=1)
+            .require_equals(true)
+            .help("Wireshark extcap: the version of Wireshark running us"))
+        .arg(Arg::new("capture")
+            .long("capture")
+            .action(ArgAction::SetTrue)
+            .requires_all(["extcap-interface", "fifo"])
+            .help("Wireshark extcap: capture the port"))
+        .arg(Arg::new("fifo")
+            .long("fifo")
+            .value_name("FIFO")
+            .help("Wireshark extcap: the fifo to write the capture to"))
+        .arg(Arg::new("extcap-capture-filter")
+            .long("extcap-capture-filter")
+            .value_name("FILTER")
+            .help("Wireshark extcap: the capture filter, which is ignored"))
         .arg(Arg::new("port")
-            .help("Serial port name")
-            .required(true)
+            .help("Serial port name(s), several ports are merged into one capture")
+            .required_unless_present_any(["tap", "bridge", "extcap-interfaces", "extcap-interface"])
+            .num_args(1..)
             .index(1))
-        .get_matches();
+        .subcommand_negates_reqs(true)
+        .subcommand(Command::new("replay")
+            .about("Writes the serial data of a capture made by this tool to a serial port, with the captured timing")
+            .arg(Arg::new("speed")
+                .long("speed")
+                .value_name("FACTOR")
+                .value_parser(parse_speed)
+                .default_value("1")
+                .help("Replay this many times faster than captured, e.g. 0.5 for half speed (default 1)"))
+            .arg(Arg::new("fast")
+                .long("fast")
+                .action(ArgAction::SetTrue)
+                .conflicts_with("speed")
+                .help("Replay as fast as the port will go, ignoring the captured timing"))
+            .arg(Arg::new("interface")
+                .long("interface")
+                .value_name("ID")
+                .value_parser(value_parser!(u32))
+                .default_value("0")
+                .help("The pcapng interface to replay, for captures of several ports (default 0)"))
+            .arg(Arg::new("direction")
+                .long("direction")
+                .value_name("DIRECTION")
+                .value_parser(parse_direction)
+                .help("The side of a tapped or bridged link to replay, tx for what the DTE sent or rx for what it received"))
+            .arg(Arg::new("map")
+                .long("map")
+                .value_name("MAP")
+                .value_parser(parse_line_map)
+                .help("How the recorded control lines drive the port's outputs, straight-through | null-modem | e.g. 'cts->rts,ri->gpio:17' (default cts->rts,dsr->dtr,cd->cd,ri->ri)"))
+            .arg(Arg::new("file")
+                .help("The pcap or pcapng capture to replay")
+                .required(true)
+                .index(1))
+            .arg(Arg::new("port")
+                .help("Serial port name")
+                .required(true)
+                .index(2)));
+    let matches = command.get_matches_mut();
+
+    if let Some(("replay", replay_matches)) = matches.subcommand() {
+        run_replay(replay_matches);
+        return;
+    }
 
     let baud_rate= *matches.get_one::<u32>("baud").unwrap(); 
     let parity = *matches.get_one::<char>("parity").unwrap();
     let stopbits = *matches.get_one::<u8>("stopbits").unwrap();
-    let frame_gap_ms = *matches.get_one::<u64>("gap").unwrap();
-    let port_name = matches.get_one::<String>("port").unwrap();
-    let output_file_prefix = matches.get_one("output").unwrap_or(port_name);
-    let use_pipe = matches.get_flag("pipe");
+    let frame_gap = match matches.get_one::<f64>("gap-chars") {
+        Some(gap_chars) => char_time(*gap_chars, baud_rate, parity, stopbits),
+        None => Duration::from_millis(*matches.get_one::<u64>("gap").unwrap()),
+    };
     let datalink = matches.get_one("datalinktype").unwrap_or(&pcap_file::DataLink::USER0);
+
+    // Wireshark extcap queries, answered without opening any port.
+    if matches.get_flag("extcap-interfaces") {
+        print!("{}", extcap::interfaces().expect("Failed to list serial ports"));
+        return;
+    }
+    if matches.get_flag("extcap-dlts") {
+        print!("{}", extcap::dlts(*datalink));
+        return;
+    }
+    if matches.get_flag("extcap-config") {
+        print!("{}", extcap::config());
+        return;
+    }
+    let extcap_interface = matches.get_one::<String>("extcap-interface");
+    if extcap_interface.is_some() && !matches.get_flag("capture") {
+        command.error(
+            clap::error::ErrorKind::MissingRequiredArgument,
+            "--extcap-interface needs one of --extcap-dlts, --extcap-config or --capture",
+        ).exit();
+    }
+
+    // A bridge is captured as a tap, with the ports connected to each other as well
+    let bridged = matches.contains_id("bridge");
+    let tap_names: Option<Vec<&String>> = matches.get_many::<String>("tap")
+        .or(matches.get_many::<String>("bridge"))
+        .map(|names| names.collect());
+    let port_names: Vec<&String> = match (&tap_names, extcap_interface) {
+        (Some(names), _) => names.clone(),
+        (None, Some(interface)) => vec![interface],
+        (None, None) => matches.get_many::<String>("port").unwrap().collect(),
+    };
+    // An extcap capture is written to the fifo Wireshark reads from.
+    let fifo = matches.get_one::<String>("fifo");
+    let output_file_prefix = fifo.or(matches.get_one("output")).unwrap_or(port_names[0]);
+    let use_pipe = matches.get_flag("pipe") || fifo.is_some();
+    let format = *matches.get_one::<OutputFormat>("format").unwrap();
+    let framing = matches.get_one::<FramingMode>("framing").unwrap();
+    let decode = matches.get_flag("decode");
+    let mark_errors = matches.get_flag("mark-errors");
     let encap_mode: EncapsulationMode = if matches.contains_id("raw") { EncapsulationMode::Raw } else { EncapsulationMode::DatalinkType };
 
+    if tap_names.is_none() && port_names.len() > 1 && format != OutputFormat::PcapNg {
+        command.error(
+            clap::error::ErrorKind::ArgumentConflict,
+            "capturing several ports requires --format pcapng",
+        ).exit();
+    }
 
-    let output_file = if use_pipe {
-        output_file_prefix.to_string()
+    // A pcap file can only tell the sides of a link apart by the encapsulation
+    let records_direction = encap_mode == EncapsulationMode::DatalinkType && datalink::records_direction(datalink);
+    if tap_names.is_some() && format != OutputFormat::PcapNg && !records_direction {
+        command.error(
+            clap::error::ErrorKind::ArgumentConflict,
+            "a tap or bridge capture requires --format pcapng, or a datalink type which records the direction",
+        ).exit();
+    }
+
+
+    // GPIO pins are attached to a lone port
+    let gpio_pins = gpio_pins(&matches);
+    if !gpio_pins.is_empty() && port_names.len() > 1 {
+        command.error(
+            clap::error::ErrorKind::ArgumentConflict,
+            "GPIO pins can only be attached when capturing a single port, in a bridge use --map-to-dce and --map-to-dte to drive GPIOs",
+        ).exit();
+    }
+
+    // A GPIO can only be driven by one side of a bridge
+    let shared_map = matches.get_one::<LineMap>("map");
+    if shared_map.is_some_and(|map| !map.gpio_pins().is_empty()) {
+        command.error(
+            clap::error::ErrorKind::ArgumentConflict,
+            "--map can't route to GPIOs, as it is used for both sides, use --map-to-dce or --map-to-dte",
+        ).exit();
+    }
+
+    let rotation = RotationPolicy::from_options(
+        &matches.get_many::<RingBufferOption>("ring-buffer").unwrap_or_default().copied().collect::<Vec<_>>());
+    if rotation.max_files.is_some() && !rotation.rotates() {
+        command.error(
+            clap::error::ErrorKind::MissingRequiredArgument,
+            "--ring-buffer files:N needs a filesize, duration or interval to switch files on",
+        ).exit();
+    }
+
+    let mut buses: Vec<Capture> = port_names.iter().enumerate().map(|(i, port_name)| {
+        let direction = match (&tap_names, i) {
+            (None, _) => Direction::Unknown,
+            (Some(_), 0) => Direction::Outbound,
+            (Some(_), _) => Direction::Inbound,
+        };
+        Capture::builder(port_name)
+            .baud_rate(baud_rate)
+            .parity(parity)
+            .stopbits(stopbits)
+            .frame_gap(frame_gap)
+            .datalink(*datalink)
+            .encapsulation(encap_mode)
+            .framing(framing.clone(), decode)
+            .mark_errors(mark_errors)
+            .direction(direction)
+            .gpio_pins(gpio_pins.clone())
+            .open()
+            .expect("Failed to open serial port")
+    }).collect();
+
+    let (interfaces, buses): (Vec<InterfaceInfo>, Vec<(u32, Capture)>) = if tap_names.is_some() {
+        // Both halves of a tapped link are written as a single interface
+        let interface = if bridged {
+            let line_router = |option: &str| {
+                let map = matches.get_one::<LineMap>(option).or(shared_map).cloned().unwrap_or_default();
+                LineRouter::open(map, gpio_pins.chip.as_deref()).expect("Failed to open GPIO output")
+            };
+            let (dte, dce) = buses.split_at_mut(1);
+            capture::bridge(&mut dte[0], &mut dce[0], line_router("map-to-dce"), line_router("map-to-dte"))
+                .expect("Failed to bridge the serial ports");
+            for (option, bus) in [("map-to-dce", &dte[0]), ("map-to-dte", &dce[0])] {
+                if matches.contains_id(option) {
+                    warn_unrouted(&format!("--{}", option), &bus.unrouted_outputs());
+                } else if shared_map.is_some() {
+                    warn_unrouted("--map", &bus.unrouted_outputs());
+                }
+            }
+            bridge_interface_info(&buses[0], &buses[1])
+        } else {
+            tap_interface_info(&buses[0], &buses[1])
+        };
+        (vec![interface], buses.into_iter().map(|bus| (0, bus)).collect())
     } else {
-        format!("{}-{}.pcap", output_file_prefix, chrono::Utc::now().format("%Y%m%d-%H%M%S"))
+        let interfaces = buses.iter().map(Capture::interface_info).collect();
+        (interfaces, buses.into_iter().enumerate().map(|(i, bus)| (i as u32, bus)).collect())
     };
 
-    let mut bus = CaptureSerial::new(port_name, baud_rate, parity, stopbits, frame_gap_ms, *datalink, encap_mode).expect("Failed to open serial port");
+    let mut writer = if use_pipe {
+        RotatingWriter::exact(output_file_prefix, format, MAX_PACKET_SIZE as u32, &interfaces)
+    } else {
+        RotatingWriter::new(output_file_prefix, format, MAX_PACKET_SIZE as u32, &interfaces, rotation)
+    }.expect("Failed to create output file");
+    if matches.get_flag("relative-time") {
+        writer = writer.relative_to(Utc::now());
+    }
+    writer = writer.on_delete_failed(|path, e| eprintln!("Warning: failed to delete old capture file {}: {}", path.display(), e));
 
-    let file = File::create(output_file).expect("Failed to create output file");
+    let limits = StopConditions {
+        duration: matches.get_one::<u64>("duration").map(|secs| Duration::from_secs(*secs)),
+        packet_count: matches.get_one::<u64>("packet-count").copied(),
+        byte_count: matches.get_one::<u64>("byte-count").copied(),
+    };
+    let stop = stop_on_signal();
 
-    if let Err(e) = bus.capture(file) {
-        eprintln!("Error occurred: {}", e);
+    match capture::run(buses, writer, &limits, stop) {
+        Ok(stats) => eprintln!("Captured {} packets ({} bytes), {} control line events, {} break and UART error events",
+            stats.packets, stats.bytes, stats.control_line_events, stats.uart_events),
+        Err(e) => eprintln!("Error occurred: {}", e),
     }
 }
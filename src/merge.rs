@@ -0,0 +1,226 @@
+//! Timestamp ordered merging of packets captured from several ports.
+//!
+//! Each port is captured on its own thread, so packets can arrive at the
+//! writer out of order. A packet is stamped with its first byte but only
+//! sent once it has all been read, so each thread marks when the packet it
+//! is reading started with an `Unfinished`. The merger holds packets back
+//! until no port is still reading an older one, plus a short window for
+//! the threads to be scheduled, and releases them in timestamp order.
+
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::sync::atomic::{self, AtomicI64};
+use std::sync::Arc;
+
+use chrono::{DateTime, TimeDelta, Utc};
+
+use crate::output::CapturedPacket;
+
+/// Heap entry ordering packets by timestamp, then by arrival order.
+struct Pending(u64, CapturedPacket);
+
+impl Ord for Pending {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.1.timestamp.cmp(&other.1.timestamp)
+            .then(self.0.cmp(&other.0))
+    }
+}
+
+impl PartialOrd for Pending {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Pending {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Pending {}
+
+const NOT_READING: i64 = i64::MAX;
+
+/// When the oldest packet a capture thread has started reading, but not yet
+/// sent, started. Clones share the mark.
+#[derive(Debug, Clone)]
+pub struct Unfinished(Arc<AtomicI64>);
+
+impl Unfinished {
+    pub fn new() -> Self {
+        Unfinished(Arc::new(AtomicI64::new(NOT_READING)))
+    }
+
+    /// Marks a packet as started at `first_byte`, unless an older one is unfinished.
+    pub fn start(&self, first_byte: DateTime<Utc>) {
+        self.0.fetch_min(first_byte.timestamp_micros(), atomic::Ordering::Relaxed);
+    }
+
+    /// Sets the oldest unfinished packet, after the others have been sent.
+    pub fn set(&self, since: Option<DateTime<Utc>>) {
+        let micros = since.map_or(NOT_READING, |since| since.timestamp_micros());
+        self.0.store(micros, atomic::Ordering::Relaxed);
+    }
+
+    /// When the oldest unfinished packet started, if there is one.
+    pub fn since(&self) -> Option<DateTime<Utc>> {
+        match self.0.load(atomic::Ordering::Relaxed) {
+            NOT_READING => None,
+            micros => DateTime::from_timestamp_micros(micros),
+        }
+    }
+}
+
+impl Default for Unfinished {
+    fn default() -> Self {
+        Unfinished::new()
+    }
+}
+
+/// Reorders packets from several sources by timestamp.
+pub struct PacketMerger {
+    window: TimeDelta,
+    pending: BinaryHeap<Reverse<Pending>>,
+    arrivals: u64,
+}
+
+impl PacketMerger {
+    /// Creates a merger which holds packets for at least `window` before releasing them.
+    pub fn new(window: TimeDelta) -> Self {
+        PacketMerger {
+            window,
+            pending: BinaryHeap::new(),
+            arrivals: 0,
+        }
+    }
+
+    pub fn push(&mut self, packet: CapturedPacket) {
+        self.pending.push(Reverse(Pending(self.arrivals, packet)));
+        self.arrivals += 1;
+    }
+
+    /// Removes and returns, in timestamp order, all packets older than the
+    /// merge window which started before `unfinished`, the oldest packet a
+    /// source is still reading.
+    pub fn pop_ready(&mut self, now: DateTime<Utc>, unfinished: Option<DateTime<Utc>>) -> Vec<CapturedPacket> {
+        let Some(cutoff) = now.checked_sub_signed(self.window) else {
+            return vec![];
+        };
+        let mut ready = Vec::new();
+        while self.pending.peek().is_some_and(|Reverse(p)| p.1.timestamp <= cutoff
+            && unfinished.is_none_or(|unfinished| p.1.timestamp < unfinished)) {
+            if let Some(Reverse(Pending(_, packet))) = self.pending.pop() {
+                ready.push(packet);
+            }
+        }
+        ready
+    }
+
+    /// Removes and returns all pending packets in timestamp order.
+    pub fn drain(&mut self) -> Vec<CapturedPacket> {
+        let mut ready = Vec::with_capacity(self.pending.len());
+        while let Some(Reverse(Pending(_, packet))) = self.pending.pop() {
+            ready.push(packet);
+        }
+        ready
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    use crate::state::{Direction, EventKind};
+
+    fn at(millis: i64) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 1, 2, 3, 4, 5).unwrap() + TimeDelta::milliseconds(millis)
+    }
+
+    fn packet(interface_id: u32, millis: i64) -> CapturedPacket {
+        CapturedPacket {
+            interface_id,
+            kind: EventKind::Data,
+            timestamp: at(millis),
+            end_timestamp: at(millis),
+            direction: Direction::Unknown,
+            frame_error: None,
+            errors: vec![],
+            captured_len: 1,
+            data: vec![interface_id as u8],
+        }
+    }
+
+    fn order(packets: &[CapturedPacket]) -> Vec<(u32, DateTime<Utc>)> {
+        packets.iter().map(|packet| (packet.interface_id, packet.timestamp)).collect()
+    }
+
+    #[test]
+    fn releases_in_timestamp_order() {
+        let mut merger = PacketMerger::new(TimeDelta::milliseconds(100));
+        merger.push(packet(0, 30));
+        merger.push(packet(1, 10));
+        merger.push(packet(2, 20));
+        assert!(merger.pop_ready(at(100), None).is_empty());
+        assert_eq!(order(&merger.pop_ready(at(125), None)), [(1, at(10)), (2, at(20))]);
+        assert_eq!(order(&merger.pop_ready(at(130), None)), [(0, at(30))]);
+    }
+
+    #[test]
+    fn equal_timestamps_keep_arrival_order() {
+        let mut merger = PacketMerger::new(TimeDelta::zero());
+        for interface_id in [2, 0, 1] {
+            merger.push(packet(interface_id, 0));
+        }
+        assert_eq!(order(&merger.drain()), [(2, at(0)), (0, at(0)), (1, at(0))]);
+    }
+
+    #[test]
+    fn zero_window_releases_straight_away() {
+        let mut merger = PacketMerger::new(TimeDelta::zero());
+        merger.push(packet(0, 10));
+        assert_eq!(order(&merger.pop_ready(at(10), None)), [(0, at(10))]);
+    }
+
+    #[test]
+    fn unfinished_packet_holds_later_ones() {
+        // A long packet is stamped with its first byte, but only arrives
+        // after its last byte and the gap, behind a later short packet.
+        let unfinished = Unfinished::new();
+        let mut merger = PacketMerger::new(TimeDelta::milliseconds(100));
+        unfinished.start(at(0));
+        merger.push(packet(0, 2_000));
+        assert!(merger.pop_ready(at(2_200), unfinished.since()).is_empty());
+        merger.push(packet(1, 0));
+        unfinished.set(None);
+        assert_eq!(order(&merger.pop_ready(at(2_200), unfinished.since())), [(1, at(0)), (0, at(2_000))]);
+    }
+
+    #[test]
+    fn packets_before_the_unfinished_one_are_released() {
+        let mut merger = PacketMerger::new(TimeDelta::milliseconds(100));
+        merger.push(packet(0, 10));
+        merger.push(packet(0, 50));
+        assert_eq!(order(&merger.pop_ready(at(1_000), Some(at(50)))), [(0, at(10))]);
+    }
+
+    #[test]
+    fn unfinished_keeps_the_oldest_start() {
+        let unfinished = Unfinished::new();
+        assert_eq!(unfinished.since(), None);
+        unfinished.start(at(20));
+        unfinished.clone().start(at(30));
+        assert_eq!(unfinished.since(), Some(at(20)));
+        unfinished.set(Some(at(40)));
+        assert_eq!(unfinished.since(), Some(at(40)));
+    }
+
+    #[test]
+    fn huge_window_holds_everything() {
+        let mut merger = PacketMerger::new(TimeDelta::MAX);
+        merger.push(packet(0, 0));
+        assert!(merger.pop_ready(at(1_000), None).is_empty());
+        assert_eq!(merger.drain().len(), 1);
+    }
+}
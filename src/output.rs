@@ -0,0 +1,213 @@
+//! Capture file writers.
+//!
+//! Wraps the classic pcap and pcapng writers from `pcap_file` behind a single
+//! type so the capture loop doesn't need to care which format was requested.
+
+use std::borrow::Cow;
+use std::io::{self, Write};
+use std::time::Duration;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use pcap_file::pcap::{PcapHeader, PcapPacket, PcapWriter};
+use pcap_file::pcapng::blocks::enhanced_packet::{EnhancedPacketBlock, EnhancedPacketOption};
+use pcap_file::pcapng::blocks::interface_description::{InterfaceDescriptionBlock, InterfaceDescriptionOption};
+use pcap_file::pcapng::PcapNgWriter;
+use pcap_file::{DataLink, PcapError, PcapResult};
+
+use crate::state::{ByteError, Direction, EventKind, FrameError};
+
+/// pcapng `if_tsresol` value for nanosecond timestamps (10^-9).
+const TSRESOL_NANOSECONDS: u8 = 9;
+
+/// pcapng `epb_flags` direction and link-layer error bits.
+const EPB_FLAGS_INBOUND: u32 = 0x01;
+const EPB_FLAGS_OUTBOUND: u32 = 0x02;
+const EPB_FLAGS_CRC_ERROR: u32 = 0x0100_0000;
+const EPB_FLAGS_SYMBOL_ERROR: u32 = 0x8000_0000;
+
+/// The file format written by the capture.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Pcap,
+    PcapNg,
+}
+
+impl OutputFormat {
+    /// The file extension conventionally used for this format.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Pcap => "pcap",
+            OutputFormat::PcapNg => "pcapng",
+        }
+    }
+}
+
+/// Parses an output format from a string.
+/// this is used in our clap argument parser.
+pub fn parse_format(format_str: &str) -> Result<OutputFormat, clap::error::Error> {
+    match format_str.to_lowercase().as_str() {
+        "pcap" => Ok(OutputFormat::Pcap),
+        "pcapng" => Ok(OutputFormat::PcapNg),
+        _ => Err(clap::error::Error::raw(
+            clap::error::ErrorKind::InvalidValue,
+            format!("Unknown output format: {}", format_str),
+        )),
+    }
+}
+
+/// Describes a capture interface (one serial port).
+///
+/// In pcapng this becomes an Interface Description Block, classic pcap
+/// only uses the datalink type.
+#[derive(Debug, Clone)]
+pub struct InterfaceInfo {
+    pub name: String,
+    pub description: String,
+    pub datalink: DataLink,
+    pub speed: u64,
+}
+
+impl InterfaceInfo {
+    fn to_idb(&self, snaplen: u32) -> InterfaceDescriptionBlock<'static> {
+        InterfaceDescriptionBlock {
+            linktype: self.datalink,
+            snaplen,
+            options: vec![
+                InterfaceDescriptionOption::IfName(Cow::Owned(self.name.clone())),
+                InterfaceDescriptionOption::IfDescription(Cow::Owned(self.description.clone())),
+                InterfaceDescriptionOption::IfSpeed(self.speed),
+                InterfaceDescriptionOption::IfTsResol(TSRESOL_NANOSECONDS),
+            ],
+        }
+    }
+}
+
+/// A captured, encapsulated packet waiting to be written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CapturedPacket {
+    pub interface_id: u32,
+    pub kind: EventKind,
+    pub timestamp: DateTime<Utc>,
+    pub end_timestamp: DateTime<Utc>,
+    pub direction: Direction,
+    pub frame_error: Option<FrameError>,
+    pub errors: Vec<ByteError>,
+    pub captured_len: usize,    // Bytes received from the port, before encapsulation
+    pub data: Vec<u8>,
+}
+
+impl CapturedPacket {
+    /// The pcapng options recorded alongside this packet.
+    fn epb_options(&self) -> Vec<EnhancedPacketOption<'static>> {
+        let mut flags = match self.direction {
+            Direction::Unknown => 0,
+            Direction::Inbound => EPB_FLAGS_INBOUND,
+            Direction::Outbound => EPB_FLAGS_OUTBOUND,
+        };
+        flags |= match self.frame_error {
+            Some(FrameError::Checksum) => EPB_FLAGS_CRC_ERROR,
+            Some(FrameError::Encoding) => EPB_FLAGS_SYMBOL_ERROR,
+            None => 0,
+        };
+        let mut options = Vec::new();
+        if flags != 0 {
+            options.push(EnhancedPacketOption::Flags(flags));
+        }
+        // The arrival of the last byte and UART errors are noted as comments,
+        // as pcapng has no fields for them
+        if self.end_timestamp > self.timestamp {
+            options.push(EnhancedPacketOption::Comment(Cow::Owned(format!("last byte at {}",
+                self.end_timestamp.to_rfc3339_opts(SecondsFormat::Nanos, true)))));
+        }
+        options.extend(self.errors.iter().map(|error| EnhancedPacketOption::Comment(Cow::Owned(error.to_string()))));
+        options
+    }
+}
+
+/// A capture file writer for either pcap or pcapng output.
+pub enum CaptureWriter<W: Write> {
+    Pcap(PcapWriter<W>),
+    PcapNg(PcapNgWriter<W>),
+}
+
+impl<W: Write> CaptureWriter<W> {
+    /// Creates a writer and emits the file header(s).
+    ///
+    /// # Arguments
+    ///
+    /// * `writer` - Where the capture is written to
+    /// * `format` - The capture file format
+    /// * `snaplen` - Maximum packet length recorded in the header(s)
+    /// * `interfaces` - The interfaces packets will be written for. Classic pcap
+    ///   supports exactly one interface.
+    pub fn new(writer: W, format: OutputFormat, snaplen: u32, interfaces: &[InterfaceInfo]) -> PcapResult<Self> {
+        match format {
+            OutputFormat::Pcap => {
+                let interface = match interfaces {
+                    [interface] => interface,
+                    _ => return Err(PcapError::InvalidField("pcap output requires exactly one interface")),
+                };
+                // Setup PCap Header to set our datalink type.
+                let pcap_header = PcapHeader {
+                    version_major: 2,
+                    version_minor: 4,
+                    snaplen,
+                    datalink: interface.datalink,
+                    ts_correction: 0,
+                    ts_accuracy: 0,
+                    ts_resolution: pcap_file::TsResolution::MicroSecond,
+                    endianness: pcap_file::Endianness::Big
+                };
+                Ok(CaptureWriter::Pcap(PcapWriter::with_header(writer, pcap_header)?))
+            },
+            OutputFormat::PcapNg => {
+                let mut ng_writer = PcapNgWriter::new(writer)?;
+                for interface in interfaces {
+                    ng_writer.write_pcapng_block(interface.to_idb(snaplen))?;
+                }
+                Ok(CaptureWriter::PcapNg(ng_writer))
+            },
+        }
+    }
+
+    /// Writes a single packet.
+    ///
+    /// # Arguments
+    ///
+    /// * `timestamp` - Packet timestamp as written to the file
+    /// * `packet` - The packet, its `interface_id` indexes the interfaces passed to `new`
+    ///
+    /// # Returns
+    ///
+    /// The number of bytes written.
+    pub fn write_packet(&mut self, timestamp: Duration, packet: &CapturedPacket) -> PcapResult<usize> {
+        match self {
+            CaptureWriter::Pcap(writer) => {
+                writer.write_packet(&PcapPacket {
+                    timestamp,
+                    orig_len: packet.data.len() as u32,
+                    data: Cow::Borrowed(&packet.data),
+                })
+            },
+            CaptureWriter::PcapNg(writer) => {
+                writer.write_pcapng_block(EnhancedPacketBlock {
+                    interface_id: packet.interface_id,
+                    timestamp,
+                    original_len: packet.data.len() as u32,
+                    data: Cow::Borrowed(&packet.data),
+                    options: packet.epb_options(),
+                })
+            },
+        }
+    }
+
+    /// Flushes the file and returns the writer.
+    pub fn finish(self) -> io::Result<W> {
+        let mut writer = match self {
+            CaptureWriter::Pcap(writer) => writer.into_writer(),
+            CaptureWriter::PcapNg(writer) => writer.into_inner(),
+        };
+        writer.flush()?;
+        Ok(writer)
+    }
+}
@@ -1,8 +1,12 @@
-use std::{default, ops::{Deref, DerefMut}};
+use std::ops::{Deref, DerefMut};
 
-use serialport::{self, SerialPort};
+use serialport;
 use gpio::{GpioOut, GpioValue};
 
+use crate::linemap::{LineOutput, LineRouter};
+use crate::linewatch::LineWatcher;
+use crate::state::ControlLine;
+
 #[derive(Debug, Clone, PartialEq, Eq, Default)]
 pub struct PortControlLines {
     pub dsr: bool,      // Data Set Ready
@@ -24,6 +28,18 @@ impl PortControlLines {
             rts: false,
         }
     }
+
+    /// Lists the lines whose level differs between `self` and `other`.
+    pub fn changed_lines(&self, other: &PortControlLines) -> Vec<ControlLine> {
+        [
+            (ControlLine::Cts, self.cts != other.cts),
+            (ControlLine::Dsr, self.dsr != other.dsr),
+            (ControlLine::Cd, self.cd != other.cd),
+            (ControlLine::Ri, self.ri != other.ri),
+            (ControlLine::Rts, self.rts != other.rts),
+            (ControlLine::Dtr, self.dtr != other.dtr),
+        ].into_iter().filter(|(_, changed)| *changed).map(|(line, _)| line).collect()
+    }
 }
 
 pub enum AnySerialPort {
@@ -62,24 +78,34 @@ impl AnySerialPort {
                     })   
         }
     }
-    pub fn reflect_control_lines(&mut self, lines: &PortControlLines) -> serialport::Result<()> {
-        match self {
-            AnySerialPort::Basic(port) => {
-                port.write_data_terminal_ready(lines.dsr)?;
-                port.write_request_to_send(lines.cts)
-            },
-            AnySerialPort::Advanced(port) => {
-                port.write_data_terminal_ready(lines.dsr)?;
-                port.write_request_to_send(lines.cts)?;
-                if port.can_set_ring_indicator() {
-                    port.set_ring_indicator(lines.ri)?;
-                }
-                if port.can_set_carrier_detect() {
-                    port.set_carrier_detect(lines.cd)?;
-                }
-                Ok(())
-            } 
+
+    /// Whether the port has the CD or RI output, RTS and DTR are always there.
+    pub fn has_output(&self, output: LineOutput) -> bool {
+        match (self, output) {
+            (_, LineOutput::Rts | LineOutput::Dtr) => true,
+            (AnySerialPort::Advanced(port), LineOutput::Cd) => port.can_set_carrier_detect(),
+            (AnySerialPort::Advanced(port), LineOutput::Ri) => port.can_set_ring_indicator(),
+            _ => false,
+        }
+    }
+
+    /// Sets the outputs `router` maps the lines to.
+    ///
+    /// CD and RI outputs the port doesn't have are left alone, while GPIO
+    /// outputs are set through the router.
+    pub fn reflect_control_lines(&mut self, lines: &PortControlLines, router: &mut LineRouter) -> serialport::Result<()> {
+        for route in router.map().routes().to_vec() {
+            let level = route.level(lines);
+            match (&mut *self, route.output) {
+                (port, LineOutput::Rts) => port.as_serial_port().write_request_to_send(level)?,
+                (port, LineOutput::Dtr) => port.as_serial_port().write_data_terminal_ready(level)?,
+                (AnySerialPort::Advanced(port), LineOutput::Cd) if port.can_set_carrier_detect() => port.set_carrier_detect(level)?,
+                (AnySerialPort::Advanced(port), LineOutput::Ri) if port.can_set_ring_indicator() => port.set_ring_indicator(level)?,
+                (_, LineOutput::Gpio(pin)) => router.set_gpio(pin, level)?,
+                (_, LineOutput::Cd | LineOutput::Ri) => {},
+            }
         }
+        Ok(())
     }
 }
 
@@ -92,7 +118,7 @@ pub trait AdvancedSerialPort : serialport::SerialPort + Send + Sync {
 
   //  fn read_carrier_detect(&mut self) -> serialport::Result<bool>;
     // Read the RI Set set state.
-   /// fn read_ring_indicator(&mut self) -> serialport::Result<bool>;
+   // fn read_ring_indicator(&mut self) -> serialport::Result<bool>;
 
     // Read the RTS Set set state.
     fn read_request_to_send(&mut self) -> serialport::Result<bool> ;
@@ -136,6 +162,7 @@ where
     port: T,
     ri_out_gpio: Option<G>,
     cd_out_gpio: Option<G>, 
+    inputs: Vec<(ControlLine, LineWatcher)>,
     last_set_rts: Option<bool>,
     last_set_dtr: Option<bool>,
 }
@@ -150,30 +177,46 @@ where
             port,
             ri_out_gpio,
             cd_out_gpio,
+            inputs: Vec::new(),
             last_set_rts: None,
             last_set_dtr: None,
         }
     }
+
+    /// Reads `line` from a GPIO input instead of the port.
+    ///
+    /// For lines the port doesn't bring out, or, for RTS and DTR, to see
+    /// the levels on the wire rather than those last set.
+    pub fn with_input(mut self, line: ControlLine, input: LineWatcher) -> Self {
+        self.inputs.retain(|(existing, _)| *existing != line);
+        self.inputs.push((line, input));
+        self
+    }
+
+    /// Reads `line` from its GPIO input, if it has one.
+    fn read_input(&mut self, line: ControlLine) -> Option<serialport::Result<bool>> {
+        self.inputs.iter_mut()
+            .find(|(existing, _)| *existing == line)
+            .map(|(_, input)| input.read_level())
+    }
     pub fn write_request_to_send(&mut self, level: bool) -> serialport::Result<()> {
-        self.port.write_request_to_send(level).and_then( |_|{
+        self.port.write_request_to_send(level).map( |_|{
             self.last_set_rts = Some(level);
-            Ok(())
-        }).or_else(|e| {
-            Err(serialport::Error::new(
+        }).map_err(|e| {
+            serialport::Error::new(
                 serialport::ErrorKind::Unknown,
                 format!("Failed to set Request To Send: {}", e),
-            ))
+            )
         })
     }
     pub fn write_data_terminal_ready(&mut self, level: bool) -> serialport::Result<()> {
-        self.port.write_data_terminal_ready(level).and_then( |_|{
+        self.port.write_data_terminal_ready(level).map( |_|{
             self.last_set_dtr = Some(level);
-            Ok(())
-        }).or_else(|e| {
-            Err(serialport::Error::new(
+        }).map_err(|e| {
+            serialport::Error::new(
                 serialport::ErrorKind::Unknown,
-                format!("Failed to set Request To Send: {}", e),
-            ))
+                format!("Failed to set Data Terminal Ready: {}", e),
+            )
         })
     }
 }   
@@ -240,21 +283,19 @@ where
     fn stop_bits(&self) -> Result<serialport::StopBits, serialport::Error> { self.port.stop_bits() }
     fn timeout(&self) -> std::time::Duration { self.port.timeout() }
     fn write_request_to_send(&mut self, level: bool) -> serialport::Result<()> { 
-        self.port.write_request_to_send(level).and_then(|_| {
+        self.port.write_request_to_send(level).map(|_| {
                 self.last_set_rts = Some(level);
-                Ok(())
             }) 
     }
     fn write_data_terminal_ready(&mut self, level: bool) -> serialport::Result<()> { 
-        self.port.write_data_terminal_ready(level).and_then(|_| {
+        self.port.write_data_terminal_ready(level).map(|_| {
             self.last_set_dtr = Some(level);
-            Ok(())
         })
     }
-    fn read_clear_to_send(&mut self) -> serialport::Result<bool> { self.port.read_clear_to_send() }
-    fn read_data_set_ready(&mut self) -> serialport::Result<bool> { self.port.read_data_set_ready() }
-    fn read_ring_indicator(&mut self) -> serialport::Result<bool> { self.port.read_ring_indicator() }
-    fn read_carrier_detect(&mut self) -> serialport::Result<bool> { self.port.read_carrier_detect() }
+    fn read_clear_to_send(&mut self) -> serialport::Result<bool> { self.read_input(ControlLine::Cts).unwrap_or_else(|| self.port.read_clear_to_send()) }
+    fn read_data_set_ready(&mut self) -> serialport::Result<bool> { self.read_input(ControlLine::Dsr).unwrap_or_else(|| self.port.read_data_set_ready()) }
+    fn read_ring_indicator(&mut self) -> serialport::Result<bool> { self.read_input(ControlLine::Ri).unwrap_or_else(|| self.port.read_ring_indicator()) }
+    fn read_carrier_detect(&mut self) -> serialport::Result<bool> { self.read_input(ControlLine::Cd).unwrap_or_else(|| self.port.read_carrier_detect()) }
     fn bytes_to_read(&self) -> serialport::Result<u32> { self.port.bytes_to_read() }
     fn bytes_to_write(&self) -> serialport::Result<u32> { self.port.bytes_to_write() }
     fn clear(&self, buffer_to_clear: serialport::ClearBuffer) -> serialport::Result<()> { self.port.clear(buffer_to_clear) }
@@ -288,23 +329,29 @@ where
     
 
     fn read_request_to_send(&mut self) -> serialport::Result<bool> {
+        if let Some(level) = self.read_input(ControlLine::Rts) {
+            return level;
+        }
         match self.last_set_rts {
             Some(level) => Ok(level),
             None => {
                 Err(serialport::Error::new(
                     serialport::ErrorKind::Unknown,
-                    format!("Unknown value in Request To Send")),
+                    "Unknown value in Request To Send"),
                 )
             },
         }   
     }   
     fn read_data_terminal_ready(&mut self) -> serialport::Result<bool> {
+        if let Some(level) = self.read_input(ControlLine::Dtr) {
+            return level;
+        }
         match self.last_set_dtr {
             Some(level) => Ok(level),
             None => {
                 Err(serialport::Error::new(
                     serialport::ErrorKind::Unknown,
-                    format!("Unknown value in Data Terminal Ready")),
+                    "Unknown value in Data Terminal Ready"),
                 )
             },
         }
@@ -318,18 +365,18 @@ where
                 } else {
                     GpioValue::Low
                 }
-            ).or_else(|_e| {
-                Err(serialport::Error::new(
+            ).map_err(|_e| {
+                serialport::Error::new(
                     serialport::ErrorKind::Unknown,
-                    format!("Failed to set Ring Indicator"),
-                ))
+                    "Failed to set Ring Indicator",
+                )
             })?;
             Ok(())
         } else {
-            return Err(serialport::Error::new(
+            Err(serialport::Error::new(
                 serialport::ErrorKind::Unknown,
                 "Ring Indicator output not supported",
-            ));
+            ))
         }
     }
     
@@ -341,18 +388,106 @@ where
                 } else {
                     GpioValue::Low
                 }
-            ).or_else(|_e| {
-                Err(serialport::Error::new(
+            ).map_err(|_e| {
+                serialport::Error::new(
                     serialport::ErrorKind::Unknown,
-                    format!("Failed to set Carrier Detect")
-                ))
+                    "Failed to set Carrier Detect"
+                )
             })?;
             Ok(())
         } else {
-            return Err(serialport::Error::new(
+            Err(serialport::Error::new(
                 serialport::ErrorKind::Unknown,
                 "Carrier Detect output not supported",
-            ));
+            ))
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{MockGpio, MockGpioInput, MockSerialPort};
+
+    #[test]
+    fn basic_port_reads_input_lines() {
+        let port = MockSerialPort::new()
+            .control_lines(PortControlLines { cts: true, cd: true, ..Default::default() })
+            .data(b"x");
+        let mut port = AnySerialPort::Basic(Box::new(port));
+        assert_eq!(port.capture_control_lines().unwrap(), PortControlLines::default());
+        assert_eq!(port.as_serial_port().read(&mut [0; 4]).unwrap(), 1);
+        let lines = port.capture_control_lines().unwrap();
+        assert_eq!(lines, PortControlLines { cts: true, cd: true, ..Default::default() });
+    }
+
+    #[test]
+    fn basic_port_without_control_lines_fails() {
+        let mut port = AnySerialPort::Basic(Box::new(MockSerialPort::new().without_control_lines()));
+        assert!(port.capture_control_lines().is_err());
+    }
+
+    #[test]
+    fn advanced_port_reports_outputs_once_set() {
+        let mock = MockSerialPort::new();
+        let mut port = SerialPortWithGpios::new(mock.clone(), None::<MockGpio>, None);
+        assert!(AdvancedSerialPort::read_request_to_send(&mut port).is_err());
+        serialport::SerialPort::write_request_to_send(&mut port, true).unwrap();
+        serialport::SerialPort::write_data_terminal_ready(&mut port, false).unwrap();
+        let mut port = AnySerialPort::Advanced(Box::new(port));
+        let lines = port.capture_control_lines().unwrap();
+        assert!(lines.rts);
+        assert!(!lines.dtr);
+        assert!(mock.current_control_lines().rts);
+    }
+
+    #[test]
+    fn reflect_drives_gpios() {
+        let mock = MockSerialPort::new();
+        let ri = MockGpio::new();
+        let cd = MockGpio::new();
+        let mut port = AnySerialPort::Advanced(Box::new(SerialPortWithGpios::new(mock.clone(), Some(ri.clone()), Some(cd.clone()))));
+        port.reflect_control_lines(&PortControlLines { dsr: true, ri: true, ..Default::default() }, &mut LineRouter::default()).unwrap();
+        port.reflect_control_lines(&PortControlLines { cts: true, cd: true, ..Default::default() }, &mut LineRouter::default()).unwrap();
+        assert_eq!(ri.values(), [GpioValue::High, GpioValue::Low]);
+        assert_eq!(cd.values(), [GpioValue::Low, GpioValue::High]);
+        let lines = mock.current_control_lines();
+        assert!(!lines.dtr);
+        assert!(lines.rts);
+    }
+
+    #[test]
+    fn reflect_skips_missing_gpios() {
+        let mut port = AnySerialPort::Advanced(Box::new(SerialPortWithGpios::new(MockSerialPort::new(), None::<MockGpio>, None)));
+        port.reflect_control_lines(&PortControlLines { ri: true, cd: true, ..Default::default() }, &mut LineRouter::default()).unwrap();
+    }
+
+    #[test]
+    fn gpio_inputs_replace_port_lines() {
+        let mock = MockSerialPort::new().control_lines(PortControlLines { cts: true, ri: true, ..Default::default() }).data(b"x");
+        let ri = MockGpioInput::new(false);
+        let cd = MockGpioInput::new(true);
+        let dtr = MockGpioInput::new(true);
+        let port = SerialPortWithGpios::new(mock, None::<MockGpio>, None)
+            .with_input(ControlLine::Ri, LineWatcher::start(ri.clone()).unwrap())
+            .with_input(ControlLine::Cd, LineWatcher::start(cd).unwrap())
+            .with_input(ControlLine::Dtr, LineWatcher::start(dtr).unwrap());
+        let mut port = AnySerialPort::Advanced(Box::new(port));
+        assert_eq!(port.as_serial_port().read(&mut [0; 4]).unwrap(), 1);
+        let lines = port.capture_control_lines().unwrap();
+        assert_eq!(lines, PortControlLines { cts: true, cd: true, dtr: true, ..Default::default() });
+
+        // A ring between two reads is seen by both
+        ri.set(true);
+        ri.set(false);
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        assert!(port.capture_control_lines().unwrap().ri);
+        assert!(!port.capture_control_lines().unwrap().ri);
+    }
+
+    #[test]
+    fn gpio_failure_is_reported() {
+        let mut port = SerialPortWithGpios::new(MockSerialPort::new(), Some(MockGpio::failing()), None);
+        assert!(port.set_ring_indicator(true).is_err());
+        assert!(port.set_carrier_detect(true).is_err());
+    }
+}
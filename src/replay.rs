@@ -0,0 +1,420 @@
+//! Replay of captures back out onto a serial port.
+//!
+//! A capture written by this tool is read back and its encapsulation
+//! removed, then the serial data is written to a port with the timing it
+//! was captured with, sped up or slowed down, or as fast as the port will
+//! take it. Control line states recorded in RTAC_SERIAL captures are
+//! re-applied as they change. A tapped link holds both directions, only one
+//! of which can be written to a port, so one direction is chosen for replay.
+
+use std::io::{self, BufRead, BufReader, Read};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use pcap_file::pcap::PcapReader;
+use pcap_file::pcapng::blocks::enhanced_packet::EnhancedPacketOption;
+use pcap_file::pcapng::blocks::interface_description::InterfaceDescriptionOption;
+use pcap_file::pcapng::{Block, PcapNgReader};
+
+use crate::capture;
+use crate::datalink;
+use crate::gpiopins::GpioPins;
+use crate::linemap::LineRouter;
+use crate::portinfo::{AnySerialPort, PortControlLines};
+use crate::state::Direction;
+
+/// The pcapng Section Header Block type, which starts every pcapng file.
+const PCAPNG_MAGIC: [u8; 4] = [0x0a, 0x0d, 0x0d, 0x0a];
+/// pcapng `if_tsresol` when the option is absent, microseconds (10^-6).
+const DEFAULT_TSRESOL: u8 = 6;
+/// pcapng `epb_flags` bits giving the direction of a packet.
+const EPB_FLAGS_DIRECTION: u32 = 0x03;
+const EPB_FLAGS_INBOUND: u32 = 0x01;
+const EPB_FLAGS_OUTBOUND: u32 = 0x02;
+/// How often a wait for the next record checks whether to stop.
+const STOP_POLL: Duration = Duration::from_millis(100);
+
+/// A captured record to be replayed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplayRecord {
+    pub timestamp: Duration,                        // Since the epoch, or the capture start for relative captures
+    pub data: Vec<u8>,
+    pub control_lines: Option<PortControlLines>,    // If the capture recorded them
+    pub direction: Direction,                       // Unknown unless the capture recorded it
+}
+
+/// How the replay is paced.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Pacing {
+    Speed(f64),         // The captured timing, this many times faster, 1.0 as captured
+    AsFastAsPossible,
+}
+
+/// Parses a replay speed factor from a string.
+/// this is used in our clap argument parser.
+pub fn parse_speed(speed_str: &str) -> Result<f64, clap::error::Error> {
+    match speed_str.parse::<f64>() {
+        Ok(speed) if speed.is_finite() && speed > 0.0 => Ok(speed),
+        _ => Err(clap::error::Error::raw(
+            clap::error::ErrorKind::InvalidValue,
+            format!("Invalid replay speed: {} (expected a factor above 0, e.g. 2 for twice as fast)", speed_str),
+        )),
+    }
+}
+
+/// Parses the direction of a tapped link to replay from a string.
+/// this is used in our clap argument parser.
+///
+/// `tx` is the data sent by the DTE, `rx` the data it received from the DCE.
+pub fn parse_direction(direction_str: &str) -> Result<Direction, clap::error::Error> {
+    match direction_str.to_lowercase().as_str() {
+        "tx" => Ok(Direction::Outbound),
+        "rx" => Ok(Direction::Inbound),
+        _ => Err(clap::error::Error::raw(
+            clap::error::ErrorKind::InvalidValue,
+            format!("Invalid direction: {} (expected rx or tx)", direction_str),
+        )),
+    }
+}
+
+/// Keeps the records sent in one direction.
+///
+/// Without a direction the records are all kept, unless they hold both
+/// directions of a tapped link, which would all be written to the one port.
+/// Fails if none of the records were sent in the direction asked for.
+pub fn select_direction(records: Vec<ReplayRecord>, direction: Option<Direction>) -> io::Result<Vec<ReplayRecord>> {
+    match direction {
+        Some(direction) => {
+            let captured = !records.is_empty();
+            let selected: Vec<ReplayRecord> = records.into_iter().filter(|record| record.direction == direction).collect();
+            if captured && selected.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                    format!("the capture holds no records sent {}", direction_name(direction))));
+            }
+            Ok(selected)
+        },
+        None if records.iter().any(|record| record.direction == Direction::Inbound)
+            && records.iter().any(|record| record.direction == Direction::Outbound) => {
+            Err(io::Error::new(io::ErrorKind::InvalidInput,
+                "the capture holds both directions of a link, choose one with --direction rx or tx"))
+        },
+        None => Ok(records),
+    }
+}
+
+/// Names a direction as `parse_direction` takes it.
+fn direction_name(direction: Direction) -> &'static str {
+    match direction {
+        Direction::Outbound => "tx",
+        Direction::Inbound => "rx",
+        Direction::Unknown => "in an unknown direction",
+    }
+}
+
+/// Converts a pcapng timestamp, which `pcap_file` reads as nanoseconds, to
+/// the interface's actual resolution.
+fn scale_timestamp(timestamp: Duration, tsresol: u8) -> Duration {
+    let units = timestamp.as_nanos();
+    let nanos = if tsresol & 0x80 != 0 {
+        // A negative power of 2
+        (units * 1_000_000_000) >> (tsresol & 0x7f).min(127)
+    } else if tsresol <= 9 {
+        units * 10u128.pow(9 - tsresol as u32)
+    } else {
+        units / 10u128.pow((tsresol as u32 - 9).min(38))
+    };
+    Duration::from_nanos(nanos.min(u64::MAX as u128) as u64)
+}
+
+/// Reads the records of a pcap or pcapng capture.
+///
+/// # Arguments
+///
+/// * `reader` - The capture file
+/// * `interface_id` - For pcapng, the interface (port) whose records are read
+pub fn read_capture<R: Read>(reader: R, interface_id: u32) -> io::Result<Vec<ReplayRecord>> {
+    let mut reader = BufReader::new(reader);
+    if reader.fill_buf()?.starts_with(&PCAPNG_MAGIC) {
+        read_pcapng(reader, interface_id)
+    } else {
+        read_pcap(reader)
+    }
+}
+
+fn read_pcap<R: Read>(reader: R) -> io::Result<Vec<ReplayRecord>> {
+    let mut reader = PcapReader::new(reader).map_err(io::Error::other)?;
+    let datalink = reader.header().datalink;
+    let mut records = Vec::new();
+    while let Some(packet) = reader.next_packet() {
+        let packet = packet.map_err(io::Error::other)?;
+        let decapsulated = datalink::get_decapsulated_data(&packet.data, &datalink)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        records.push(ReplayRecord {
+            timestamp: packet.timestamp,
+            data: decapsulated.data,
+            control_lines: decapsulated.control_lines,
+            direction: decapsulated.direction,
+        });
+    }
+    Ok(records)
+}
+
+fn read_pcapng<R: Read>(reader: R, interface_id: u32) -> io::Result<Vec<ReplayRecord>> {
+    let mut reader = PcapNgReader::new(reader).map_err(io::Error::other)?;
+    let mut records = Vec::new();
+    while let Some(block) = reader.next_block() {
+        let (timestamp, data, options) = match block.map_err(io::Error::other)? {
+            Block::EnhancedPacket(packet) if packet.interface_id == interface_id => (packet.timestamp, packet.data.into_owned(), packet.options),
+            _ => continue,
+        };
+        let flags = options.iter().find_map(|option| match option {
+            EnhancedPacketOption::Flags(flags) => Some(*flags),
+            _ => None,
+        }).unwrap_or(0);
+        let flags_direction = match flags & EPB_FLAGS_DIRECTION {
+            EPB_FLAGS_INBOUND => Direction::Inbound,
+            EPB_FLAGS_OUTBOUND => Direction::Outbound,
+            _ => Direction::Unknown,
+        };
+        let interface = reader.interfaces().get(interface_id as usize)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("no interface {} in the capture", interface_id)))?;
+        let tsresol = interface.options.iter().find_map(|option| match option {
+            InterfaceDescriptionOption::IfTsResol(tsresol) => Some(*tsresol),
+            _ => None,
+        }).unwrap_or(DEFAULT_TSRESOL);
+        let decapsulated = datalink::get_decapsulated_data(&data, &interface.linktype)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        records.push(ReplayRecord {
+            timestamp: scale_timestamp(timestamp, tsresol),
+            data: decapsulated.data,
+            control_lines: decapsulated.control_lines,
+            // Without the flags, the encapsulation may still record it
+            direction: match flags_direction {
+                Direction::Unknown => decapsulated.direction,
+                direction => direction,
+            },
+        });
+    }
+    Ok(records)
+}
+
+/// Opens a port to replay onto, with the GPIO pins attached, whose RI and
+/// CD outputs are then driven as the control lines are routed to them.
+pub fn open_port(port_name: &str, baud_rate: u32, parity: char, stopbits: u8, gpio_pins: &GpioPins) -> io::Result<AnySerialPort> {
+    let timeout = capture::write_timeout(baud_rate, parity, stopbits);
+    let (port, _tty) = capture::open_port(capture::port_builder(port_name, baud_rate, parity, stopbits).timeout(timeout), gpio_pins)?;
+    Ok(port)
+}
+
+/// Totals of what was replayed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReplayStats {
+    pub packets: u64,
+    pub bytes: u64,
+    pub control_line_changes: u64,
+}
+
+/// How long after the first record, captured at `first`, a record captured
+/// at `timestamp` is due when replayed `speed` times faster.
+fn due_after(timestamp: Duration, first: Duration, speed: f64) -> Duration {
+    timestamp.saturating_sub(first).div_f64(speed)
+}
+
+/// Sleeps until `due`, returning false if `stop` is set first.
+fn wait_until(due: Instant, stop: &AtomicBool) -> bool {
+    while let Some(wait) = due.checked_duration_since(Instant::now()) {
+        if stop.load(Ordering::Relaxed) {
+            return false;
+        }
+        thread::sleep(wait.min(STOP_POLL));
+    }
+    !stop.load(Ordering::Relaxed)
+}
+
+/// Writes the records to the port, paced as asked.
+///
+/// Each record is written when its first byte was captured, relative to the
+/// first record. A record which is due while the previous one is still
+/// being sent follows straight after it. Runs until all the records have
+/// been written or `stop` is set.
+///
+/// Recorded control lines are set on the port's outputs as `lines` routes them.
+///
+/// # Arguments
+///
+/// * `port` - The port to write to
+/// * `records` - The records, in timestamp order
+/// * `pacing` - How the records are spaced out
+/// * `lines` - Routes the recorded control lines to the port's outputs
+/// * `stop` - Set when the replay is to stop
+pub fn replay(port: &mut AnySerialPort, records: &[ReplayRecord], pacing: Pacing, lines: &mut LineRouter, stop: &AtomicBool) -> io::Result<ReplayStats> {
+    let mut stats = ReplayStats::default();
+    let start = Instant::now();
+    let first = records.first().map(|record| record.timestamp).unwrap_or_default();
+    let mut control_lines: Option<&PortControlLines> = None;
+    for record in records {
+        if let Pacing::Speed(speed) = pacing {
+            let due = start + due_after(record.timestamp, first, speed);
+            if !wait_until(due, stop) {
+                break;
+            }
+        } else if stop.load(Ordering::Relaxed) {
+            break;
+        }
+        if let Some(record_lines) = record.control_lines.as_ref().filter(|record_lines| control_lines != Some(*record_lines)) {
+            port.reflect_control_lines(record_lines, lines)?;
+            control_lines = Some(record_lines);
+            stats.control_line_changes += 1;
+        }
+        if record.data.is_empty() {
+            continue;
+        }
+        let serial_port = port.as_serial_port();
+        serial_port.write_all(&record.data)?;
+        // Wait for the data to go out, so the next record's timing is from when it did
+        serial_port.flush()?;
+        stats.packets += 1;
+        stats.bytes += record.data.len() as u64;
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use pcap_file::pcap::{PcapHeader, PcapPacket, PcapWriter};
+    use pcap_file::pcapng::blocks::enhanced_packet::EnhancedPacketBlock;
+    use pcap_file::pcapng::blocks::interface_description::InterfaceDescriptionBlock;
+    use pcap_file::pcapng::PcapNgWriter;
+    use pcap_file::{DataLink, TsResolution};
+
+    use super::*;
+
+    fn record(millis: u64, data: &[u8], direction: Direction) -> ReplayRecord {
+        ReplayRecord { timestamp: Duration::from_millis(millis), data: data.to_vec(), control_lines: None, direction }
+    }
+
+    /// A pcapng capture of one USER0 interface, with the given `if_tsresol` if any.
+    fn pcapng(tsresol: Option<u8>, packets: &[(u64, &[u8], u32)]) -> Vec<u8> {
+        let mut writer = PcapNgWriter::new(Vec::new()).unwrap();
+        let options = tsresol.map(InterfaceDescriptionOption::IfTsResol).into_iter().collect();
+        writer.write_pcapng_block(InterfaceDescriptionBlock { linktype: DataLink::USER0, snaplen: 2048, options }).unwrap();
+        for (units, data, flags) in packets {
+            writer.write_pcapng_block(EnhancedPacketBlock {
+                interface_id: 0,
+                // Written as is, in units of the interface's resolution
+                timestamp: Duration::from_nanos(*units),
+                original_len: data.len() as u32,
+                data: Cow::Borrowed(data),
+                options: vec![EnhancedPacketOption::Flags(*flags)],
+            }).unwrap();
+        }
+        writer.into_inner()
+    }
+
+    #[test]
+    fn reads_pcap() {
+        let header = PcapHeader { datalink: DataLink::USER0, ts_resolution: TsResolution::NanoSecond, ..Default::default() };
+        let mut writer = PcapWriter::with_header(Vec::new(), header).unwrap();
+        writer.write_packet(&PcapPacket::new(Duration::new(5, 123_456_789), 3, b"one")).unwrap();
+        let records = read_capture(writer.into_writer().as_slice(), 0).unwrap();
+        assert_eq!(records, [ReplayRecord {
+            timestamp: Duration::new(5, 123_456_789),
+            data: b"one".to_vec(),
+            control_lines: None,
+            direction: Direction::Unknown,
+        }]);
+    }
+
+    #[test]
+    fn reads_pcap_directions_from_rtac_records() {
+        let header = PcapHeader { datalink: DataLink::RTAC_SERIAL, ..Default::default() };
+        let mut writer = PcapWriter::with_header(Vec::new(), header).unwrap();
+        for (event_type, data) in [(0x01, b"tx"), (0x02, b"rx")] {
+            let encapsulated = datalink::RtacRecord {
+                timestamp: chrono::DateTime::UNIX_EPOCH,
+                event_type,
+                control_lines: PortControlLines::default(),
+                port_id: 0,
+                data: data.to_vec(),
+            }.encode();
+            writer.write_packet(&PcapPacket::new(Duration::ZERO, encapsulated.len() as u32, &encapsulated)).unwrap();
+        }
+        let records = read_capture(writer.into_writer().as_slice(), 0).unwrap();
+        let directions: Vec<Direction> = records.iter().map(|record| record.direction).collect();
+        assert_eq!(directions, [Direction::Outbound, Direction::Inbound]);
+        assert!(select_direction(records.clone(), None).is_err());
+        assert_eq!(select_direction(records, Some(Direction::Inbound)).unwrap()[0].data, b"rx");
+    }
+
+    #[test]
+    fn reads_pcapng_with_directions() {
+        let capture = pcapng(Some(9), &[(1, b"tx", EPB_FLAGS_OUTBOUND), (2, b"rx", EPB_FLAGS_INBOUND), (3, b"?", 0)]);
+        let records = read_capture(capture.as_slice(), 0).unwrap();
+        let directions: Vec<(&[u8], Direction)> = records.iter().map(|record| (record.data.as_slice(), record.direction)).collect();
+        assert_eq!(directions, [(&b"tx"[..], Direction::Outbound), (b"rx", Direction::Inbound), (b"?", Direction::Unknown)]);
+        assert!(read_capture(capture.as_slice(), 1).unwrap().is_empty());
+    }
+
+    #[test]
+    fn pcapng_timestamps_follow_tsresol() {
+        for (tsresol, units, expected) in [
+            (None, 1_500_000, Duration::from_millis(1500)),
+            (Some(6), 1_500_000, Duration::from_millis(1500)),
+            (Some(3), 1_500, Duration::from_millis(1500)),
+            (Some(9), 1_500_000_000, Duration::from_millis(1500)),
+            (Some(0x83), 12, Duration::from_millis(1500)),
+        ] {
+            let records = read_capture(pcapng(tsresol, &[(units, b"x", 0)]).as_slice(), 0).unwrap();
+            assert_eq!(records[0].timestamp, expected, "tsresol {:?}", tsresol);
+        }
+    }
+
+    #[test]
+    fn scales_timestamps() {
+        assert_eq!(scale_timestamp(Duration::from_nanos(7), 0), Duration::from_secs(7));
+        assert_eq!(scale_timestamp(Duration::from_nanos(12_345), 12), Duration::from_nanos(12));
+        assert_eq!(scale_timestamp(Duration::from_nanos(1), 0x81), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn selects_a_direction() {
+        let records = vec![record(0, b"tx", Direction::Outbound), record(1, b"rx", Direction::Inbound)];
+        assert_eq!(select_direction(records.clone(), Some(Direction::Inbound)).unwrap(), [record(1, b"rx", Direction::Inbound)]);
+        assert_eq!(select_direction(records.clone(), Some(Direction::Outbound)).unwrap(), [record(0, b"tx", Direction::Outbound)]);
+        assert_eq!(select_direction(records, None).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+        let one_way = vec![record(0, b"tx", Direction::Outbound), record(1, b"?", Direction::Unknown)];
+        assert_eq!(select_direction(one_way.clone(), None).unwrap(), one_way);
+        assert_eq!(select_direction(one_way, Some(Direction::Inbound)).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert!(select_direction(vec![], Some(Direction::Inbound)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn parses_directions() {
+        assert_eq!(parse_direction("tx").unwrap(), Direction::Outbound);
+        assert_eq!(parse_direction("RX").unwrap(), Direction::Inbound);
+        assert!(parse_direction("both").is_err());
+    }
+
+    #[test]
+    fn parses_speeds() {
+        assert_eq!(parse_speed("0.5").unwrap(), 0.5);
+        for invalid in ["0", "-1", "nan", "inf", "fast"] {
+            assert!(parse_speed(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn pacing() {
+        let first = Duration::from_secs(100);
+        assert_eq!(due_after(first, first, 1.0), Duration::ZERO);
+        assert_eq!(due_after(first + Duration::from_millis(300), first, 1.0), Duration::from_millis(300));
+        assert_eq!(due_after(first + Duration::from_millis(300), first, 2.0), Duration::from_millis(150));
+        assert_eq!(due_after(first + Duration::from_millis(300), first, 0.5), Duration::from_millis(600));
+        // Out of order records are due straight away
+        assert_eq!(due_after(first - Duration::from_millis(10), first, 1.0), Duration::ZERO);
+    }
+}
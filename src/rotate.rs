@@ -0,0 +1,394 @@
+//! Capture file rotation.
+//!
+//! Long running captures can be split across several files, switching to a
+//! new file after a size, after a duration, or at wall-clock boundaries. A
+//! maximum file count turns the files into a ring buffer, deleting the
+//! oldest file as each new one is started. A file that can't be deleted is
+//! reported to the caller, and the capture carries on.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+
+use crate::output::{CaptureWriter, CapturedPacket, InterfaceInfo, OutputFormat};
+use crate::sink::Sink;
+
+/// A single `--ring-buffer` option, as in dumpcap's `-b`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RingBufferOption {
+    FileSize(u64),      // Switch after this many kilobytes
+    Duration(Duration), // Switch after the file has been open this long
+    Interval(Duration), // Switch when the wall-clock time is a multiple of this
+    Files(usize),       // Keep at most this many files
+}
+
+/// Parses a ring buffer option from a string.
+/// this is used in our clap argument parser.
+pub fn parse_ring_buffer(option_str: &str) -> Result<RingBufferOption, clap::error::Error> {
+    let invalid = || clap::error::Error::raw(
+        clap::error::ErrorKind::InvalidValue,
+        format!("Invalid ring buffer option: {} (expected filesize:KB, duration:SECS, interval:SECS or files:N)", option_str),
+    );
+    let (key, value) = option_str.split_once(':').ok_or_else(invalid)?;
+    let value: u64 = value.parse().map_err(|_| invalid())?;
+    if value == 0 {
+        return Err(invalid());
+    }
+    match key.to_lowercase().as_str() {
+        "filesize" => Ok(RingBufferOption::FileSize(value)),
+        "duration" => Ok(RingBufferOption::Duration(Duration::from_secs(value))),
+        "interval" => Ok(RingBufferOption::Interval(Duration::from_secs(value))),
+        "files" => Ok(RingBufferOption::Files(value as usize)),
+        _ => Err(invalid()),
+    }
+}
+
+/// When to switch to a new capture file, and how many files to keep.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RotationPolicy {
+    pub file_size: Option<u64>,         // In bytes
+    pub duration: Option<Duration>,
+    pub interval: Option<Duration>,
+    pub max_files: Option<usize>,
+}
+
+impl RotationPolicy {
+    pub fn from_options(options: &[RingBufferOption]) -> Self {
+        let mut policy = RotationPolicy::default();
+        for option in options {
+            match *option {
+                RingBufferOption::FileSize(kilobytes) => policy.file_size = Some(kilobytes * 1000),
+                RingBufferOption::Duration(duration) => policy.duration = Some(duration),
+                RingBufferOption::Interval(interval) => policy.interval = Some(interval),
+                RingBufferOption::Files(files) => policy.max_files = Some(files),
+            }
+        }
+        policy
+    }
+
+    /// Whether any condition switches files, without one there's only ever one file.
+    pub fn rotates(&self) -> bool {
+        self.file_size.is_some() || self.duration.is_some() || self.interval.is_some()
+    }
+
+    /// Whether a file opened at `opened`, with `size` bytes written, should be closed at `now`.
+    fn due(&self, opened: DateTime<Utc>, size: u64, now: DateTime<Utc>) -> bool {
+        if self.file_size.is_some_and(|limit| size >= limit) {
+            return true;
+        }
+        let open_for = (now - opened).to_std().unwrap_or_default();
+        if self.duration.is_some_and(|duration| open_for >= duration) {
+            return true;
+        }
+        self.interval.is_some_and(|interval| {
+            // Crossed a multiple of the interval since the file was opened
+            let interval = interval.as_millis() as i64;
+            opened.timestamp_millis().div_euclid(interval) != now.timestamp_millis().div_euclid(interval)
+        })
+    }
+}
+
+/// Names the files in a series, in the order they are created.
+struct FileNames {
+    prefix: String,
+    extension: &'static str,
+    last_stem: String,
+    suffix: usize,
+}
+
+impl FileNames {
+    fn next(&mut self, now: DateTime<Utc>) -> PathBuf {
+        let stem = format!("{}-{}", self.prefix, now.format("%Y%m%d-%H%M%S"));
+        if stem == self.last_stem {
+            // Several files can be started within a second when they are small
+            self.suffix += 1;
+            return PathBuf::from(format!("{}-{}.{}", stem, self.suffix, self.extension));
+        }
+        self.suffix = 0;
+        let path = PathBuf::from(format!("{}.{}", stem, self.extension));
+        self.last_stem = stem;
+        path
+    }
+}
+
+fn create(path: &Path, format: OutputFormat, snaplen: u32, interfaces: &[InterfaceInfo]) -> io::Result<CaptureWriter<File>> {
+    let file = File::create(path)?;
+    CaptureWriter::new(file, format, snaplen, interfaces).map_err(io::Error::other)
+}
+
+/// Called with an old capture file which couldn't be deleted, and why.
+pub type DeleteFailed = Box<dyn FnMut(&Path, &io::Error)>;
+
+/// Writes a capture to a series of files named `{prefix}-{YYYYmmdd-HHMMSS}.{extension}`.
+///
+/// Each file starts with its own header, so every file is a valid capture.
+pub struct RotatingWriter {
+    names: Option<FileNames>,  // `None` when writing to a single named file
+    format: OutputFormat,
+    snaplen: u32,
+    interfaces: Vec<InterfaceInfo>,
+    policy: RotationPolicy,
+    writer: CaptureWriter<File>,
+    opened: DateTime<Utc>,
+    size: u64,
+    files: VecDeque<PathBuf>,
+    zero_time: DateTime<Utc>,  // Timestamps are written relative to this
+    delete_failed: Option<DeleteFailed>,
+}
+
+impl RotatingWriter {
+    /// Creates the first capture file.
+    ///
+    /// # Arguments
+    ///
+    /// * `prefix` - Prefix of the file names
+    /// * `format` - The capture file format
+    /// * `snaplen` - Maximum packet length recorded in the header(s)
+    /// * `interfaces` - The interfaces packets will be written for
+    /// * `policy` - When to switch files
+    pub fn new(prefix: &str, format: OutputFormat, snaplen: u32, interfaces: &[InterfaceInfo], policy: RotationPolicy) -> io::Result<Self> {
+        let now = Utc::now();
+        let mut names = FileNames { prefix: prefix.to_string(), extension: format.extension(), last_stem: String::new(), suffix: 0 };
+        let path = names.next(now);
+        Ok(RotatingWriter {
+            writer: create(&path, format, snaplen, interfaces)?,
+            names: Some(names),
+            format,
+            snaplen,
+            interfaces: interfaces.to_vec(),
+            policy,
+            opened: now,
+            size: 0,
+            files: VecDeque::from([path]),
+            zero_time: DateTime::UNIX_EPOCH,
+            delete_failed: None,
+        })
+    }
+
+    /// Writes the capture to a single file, or pipe, with exactly the name given.
+    pub fn exact(path: &str, format: OutputFormat, snaplen: u32, interfaces: &[InterfaceInfo]) -> io::Result<Self> {
+        Ok(RotatingWriter {
+            writer: create(Path::new(path), format, snaplen, interfaces)?,
+            names: None,
+            format,
+            snaplen,
+            interfaces: interfaces.to_vec(),
+            policy: RotationPolicy::default(),
+            opened: Utc::now(),
+            size: 0,
+            files: VecDeque::from([PathBuf::from(path)]),
+            zero_time: DateTime::UNIX_EPOCH,
+            delete_failed: None,
+        })
+    }
+
+    /// Switches to a new file if the current one is due to be closed at `now`.
+    pub fn rotate_if_due(&mut self, now: DateTime<Utc>) -> io::Result<()> {
+        let Some(names) = self.names.as_mut() else {
+            return Ok(());
+        };
+        if !self.policy.due(self.opened, self.size, now) {
+            return Ok(());
+        }
+        let path = names.next(now);
+        let writer = create(&path, self.format, self.snaplen, &self.interfaces)?;
+        std::mem::replace(&mut self.writer, writer).finish()?;
+        self.opened = now;
+        self.size = 0;
+        self.files.push_back(path);
+        if let Some(max_files) = self.policy.max_files {
+            while self.files.len() > max_files {
+                if let Some(oldest) = self.files.pop_front() {
+                    // A file that can't be deleted shouldn't stop the capture
+                    if let Err(e) = std::fs::remove_file(&oldest) {
+                        if let Some(delete_failed) = self.delete_failed.as_mut() {
+                            delete_failed(&oldest, &e);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes timestamps relative to `zero_time`, rather than the epoch.
+    pub fn relative_to(mut self, zero_time: DateTime<Utc>) -> Self {
+        self.zero_time = zero_time;
+        self
+    }
+
+    /// Calls `delete_failed` when an old file in the ring buffer can't be deleted.
+    pub fn on_delete_failed(mut self, delete_failed: impl FnMut(&Path, &io::Error) + 'static) -> Self {
+        self.delete_failed = Some(Box::new(delete_failed));
+        self
+    }
+
+    /// Writes a single packet, first switching files if due.
+    ///
+    /// # Arguments
+    ///
+    /// * `timestamp` - Packet timestamp as written to the file
+    /// * `packet` - The packet, its `interface_id` indexes the interfaces passed to `new`
+    pub fn write_packet(&mut self, timestamp: Duration, packet: &CapturedPacket) -> io::Result<()> {
+        self.rotate_if_due(Utc::now())?;
+        self.size += self.writer.write_packet(timestamp, packet).map_err(io::Error::other)? as u64;
+        Ok(())
+    }
+
+    /// Flushes and closes the current file.
+    pub fn finish(self) -> io::Result<()> {
+        self.writer.finish()?;
+        Ok(())
+    }
+}
+
+impl Sink for RotatingWriter {
+    fn write(&mut self, packet: &CapturedPacket) -> io::Result<()> {
+        self.write_packet((packet.timestamp - self.zero_time).to_std().unwrap_or_default(), packet)
+    }
+
+    fn tick(&mut self, now: DateTime<Utc>) -> io::Result<()> {
+        self.rotate_if_due(now)
+    }
+
+    fn finish(self) -> io::Result<()> {
+        RotatingWriter::finish(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use chrono::TimeZone;
+    use pcap_file::DataLink;
+
+    fn at(hour: u32, min: u32, sec: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 1, 2, hour, min, sec).unwrap()
+    }
+
+    /// A directory for a test's capture files, removed when dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("serialpcap-rotate-{}-{}", name, std::process::id()));
+            std::fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+
+        fn file_count(&self) -> usize {
+            std::fs::read_dir(&self.0).unwrap().count()
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn ring_buffer(dir: &TempDir, options: &[&str]) -> RotatingWriter {
+        let options: Vec<RingBufferOption> = options.iter().map(|option| parse_ring_buffer(option).unwrap()).collect();
+        let interface = InterfaceInfo { name: "test".to_string(), description: String::new(), datalink: DataLink::USER0, speed: 9600 };
+        let prefix = dir.0.join("ring");
+        RotatingWriter::new(prefix.to_str().unwrap(), OutputFormat::Pcap, 2048, &[interface], RotationPolicy::from_options(&options)).unwrap()
+    }
+
+    #[test]
+    fn parses_options() {
+        assert_eq!(parse_ring_buffer("filesize:10").unwrap(), RingBufferOption::FileSize(10));
+        assert_eq!(parse_ring_buffer("DURATION:60").unwrap(), RingBufferOption::Duration(Duration::from_secs(60)));
+        assert_eq!(parse_ring_buffer("interval:3600").unwrap(), RingBufferOption::Interval(Duration::from_secs(3600)));
+        assert_eq!(parse_ring_buffer("files:5").unwrap(), RingBufferOption::Files(5));
+        for invalid in ["filesize", "filesize:0", "filesize:-1", "files:many", "packets:10"] {
+            assert!(parse_ring_buffer(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn policy_from_options() {
+        let policy = RotationPolicy::from_options(&[
+            RingBufferOption::FileSize(10),
+            RingBufferOption::Duration(Duration::from_secs(60)),
+            RingBufferOption::Files(3),
+        ]);
+        assert_eq!(policy, RotationPolicy {
+            file_size: Some(10_000),
+            duration: Some(Duration::from_secs(60)),
+            interval: None,
+            max_files: Some(3),
+        });
+        assert!(policy.rotates());
+        assert!(!RotationPolicy::from_options(&[RingBufferOption::Files(3)]).rotates());
+    }
+
+    #[test]
+    fn due_on_file_size() {
+        let policy = RotationPolicy { file_size: Some(10_000), ..Default::default() };
+        assert!(!policy.due(at(12, 0, 0), 9_999, at(13, 0, 0)));
+        assert!(policy.due(at(12, 0, 0), 10_000, at(12, 0, 0)));
+    }
+
+    #[test]
+    fn due_on_duration() {
+        let policy = RotationPolicy { duration: Some(Duration::from_secs(60)), ..Default::default() };
+        assert!(!policy.due(at(12, 0, 30), 0, at(12, 1, 29)));
+        assert!(policy.due(at(12, 0, 30), 0, at(12, 1, 30)));
+    }
+
+    #[test]
+    fn due_on_interval() {
+        let policy = RotationPolicy { interval: Some(Duration::from_secs(60)), ..Default::default() };
+        assert!(!policy.due(at(12, 0, 0), 0, at(12, 0, 59)));
+        assert!(policy.due(at(12, 0, 59), 0, at(12, 1, 0)));
+        assert!(!RotationPolicy::default().due(at(12, 0, 0), u64::MAX, at(23, 0, 0)));
+    }
+
+    #[test]
+    fn file_names_within_a_second_get_a_suffix() {
+        let mut names = FileNames { prefix: "cap".to_string(), extension: "pcap", last_stem: String::new(), suffix: 0 };
+        assert_eq!(names.next(at(12, 0, 0)), PathBuf::from("cap-20240102-120000.pcap"));
+        assert_eq!(names.next(at(12, 0, 0)), PathBuf::from("cap-20240102-120000-1.pcap"));
+        assert_eq!(names.next(at(12, 0, 0)), PathBuf::from("cap-20240102-120000-2.pcap"));
+        assert_eq!(names.next(at(12, 0, 1)), PathBuf::from("cap-20240102-120001.pcap"));
+        assert_eq!(names.next(at(12, 0, 1)), PathBuf::from("cap-20240102-120001-1.pcap"));
+    }
+
+    #[test]
+    fn ring_buffer_keeps_newest_files() {
+        let dir = TempDir::new("prune");
+        let mut writer = ring_buffer(&dir, &["duration:1", "files:2"]);
+        let start = writer.opened;
+        for secs in 1..=4 {
+            writer.rotate_if_due(start + chrono::TimeDelta::seconds(secs * 2)).unwrap();
+        }
+        assert_eq!(writer.files.len(), 2);
+        assert_eq!(dir.file_count(), 2);
+        assert!(writer.files.iter().all(|path| path.exists()));
+        writer.finish().unwrap();
+    }
+
+    #[test]
+    fn ring_buffer_reports_a_failed_delete() {
+        let dir = TempDir::new("delete");
+        let failed = Rc::new(RefCell::new(Vec::new()));
+        let reported = failed.clone();
+        let mut writer = ring_buffer(&dir, &["duration:1", "files:1"])
+            .on_delete_failed(move |path, e| reported.borrow_mut().push((path.to_path_buf(), e.kind())));
+        let start = writer.opened;
+        let first = writer.files[0].clone();
+        std::fs::remove_file(&first).unwrap();
+        writer.rotate_if_due(start + chrono::TimeDelta::seconds(2)).unwrap();
+        assert_eq!(writer.files.len(), 1);
+        assert_eq!(dir.file_count(), 1);
+        assert_eq!(*failed.borrow(), [(first, io::ErrorKind::NotFound)]);
+        writer.finish().unwrap();
+    }
+}
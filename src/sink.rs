@@ -0,0 +1,57 @@
+//! Destinations for captured packets.
+//!
+//! `capture::run` writes everything it captures to a `Sink`. Capture files
+//! are written by `rotate::RotatingWriter`, and packets can be collected in a
+//! `Vec` or sent down a channel for inspection by other code.
+
+use std::io;
+use std::sync::mpsc;
+
+use chrono::{DateTime, Utc};
+
+use crate::output::CapturedPacket;
+
+/// Somewhere captured packets are written.
+pub trait Sink {
+    /// Writes a captured packet, packets are written in timestamp order.
+    fn write(&mut self, packet: &CapturedPacket) -> io::Result<()>;
+
+    /// Called regularly while capturing, even when nothing is captured.
+    fn tick(&mut self, _now: DateTime<Utc>) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Called once the capture has ended, to flush and close the sink.
+    fn finish(self) -> io::Result<()>
+    where
+        Self: Sized,
+    {
+        Ok(())
+    }
+}
+
+impl Sink for Vec<CapturedPacket> {
+    fn write(&mut self, packet: &CapturedPacket) -> io::Result<()> {
+        self.push(packet.clone());
+        Ok(())
+    }
+}
+
+impl Sink for mpsc::Sender<CapturedPacket> {
+    fn write(&mut self, packet: &CapturedPacket) -> io::Result<()> {
+        self.send(packet.clone())
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "packet receiver has gone"))
+    }
+}
+
+/// Lets a sink be borrowed for a capture and inspected afterwards. It isn't
+/// finished when the capture ends, that's left to its owner.
+impl<S: Sink + ?Sized> Sink for &mut S {
+    fn write(&mut self, packet: &CapturedPacket) -> io::Result<()> {
+        (**self).write(packet)
+    }
+
+    fn tick(&mut self, now: DateTime<Utc>) -> io::Result<()> {
+        (**self).tick(now)
+    }
+}
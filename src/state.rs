@@ -1,24 +1,126 @@
 use chrono::prelude::*;
 use crate::portinfo::PortControlLines;
 
+/// Why a framer flagged a frame as bad.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameError {
+    Checksum,   // The frame check sequence didn't match
+    Encoding,   // The frame couldn't be decoded, so holds the raw wire bytes
+}
+
+/// The direction data travelled on a tapped link, relative to the DTE.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Direction {
+    #[default]
+    Unknown,
+    Inbound,    // Sent by the DCE, towards the DTE
+    Outbound,   // Sent by the DTE, towards the DCE
+}
+
+/// A modem control line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlLine {
+    Cts,
+    Dsr,
+    Cd,
+    Ri,
+    Rts,
+    Dtr,
+}
+
+/// What a `SerialEvent` records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EventKind {
+    #[default]
+    Data,
+    ControlLine(ControlLine),   // An edge on the line, its new level is in `control_lines`
+    Break,
+    FramingError,
+    ParityError,
+    Overrun,
+}
+
+/// A UART error reported in line with the received data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineError {
+    Break,
+    Framing,
+    Parity,
+}
+
+impl LineError {
+    /// The standalone event recording this error.
+    pub fn event_kind(&self) -> EventKind {
+        match self {
+            LineError::Break => EventKind::Break,
+            LineError::Framing => EventKind::FramingError,
+            LineError::Parity => EventKind::ParityError,
+        }
+    }
+}
 
+/// Where in an event's data a UART error happened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteError {
+    pub offset: usize,  // The errored byte, or for a break the byte following it
+    pub kind: LineError,
+}
+
+impl std::fmt::Display for ByteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.kind {
+            LineError::Break => write!(f, "break before byte {}", self.offset),
+            LineError::Framing => write!(f, "framing error at byte {}", self.offset),
+            LineError::Parity => write!(f, "parity error at byte {}", self.offset),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct SerialEvent {
-    pub timestamp: DateTime<Utc>,
+    pub kind: EventKind,
+    pub timestamp: DateTime<Utc>,       // When the first byte arrived
+    pub end_timestamp: DateTime<Utc>,   // When the last byte arrived
     pub data: Vec<u8>,
     pub control_lines: PortControlLines,
+    pub direction: Direction,
+    pub frame_error: Option<FrameError>,
+    pub errors: Vec<ByteError>,
 }
 
 impl SerialEvent {
-    pub fn new(data: Vec<u8>, valid_len: usize, control_lines: PortControlLines) -> Self {
+    pub fn new(data: Vec<u8>, valid_len: usize, control_lines: PortControlLines, direction: Direction) -> Self {
+        let timestamp = Utc::now(); // Use current time as timestamp
         SerialEvent {
-            timestamp: Utc::now(), // Use current time as timestamp
+            kind: EventKind::Data,
+            timestamp,
+            end_timestamp: timestamp,
             data: data[..valid_len].to_vec(), // Ensure we only take valid length of data
             control_lines,
+            direction,
+            frame_error: None,
+            errors: Vec::new(),
         }
     }
-    /// Checks if the event contains any data
-    pub fn is_insignificant(&self, line_status: &PortControlLines) -> bool {
-        self.data.is_empty() && self.control_lines == *line_status
+
+    /// Creates a standalone event, such as a control line edge or a UART error, which carries no data
+    pub fn line_event(kind: EventKind, control_lines: PortControlLines, direction: Direction) -> Self {
+        let timestamp = Utc::now();
+        SerialEvent {
+            kind,
+            timestamp,
+            end_timestamp: timestamp,
+            data: Vec::new(),
+            control_lines,
+            direction,
+            frame_error: None,
+            errors: Vec::new(),
+        }
+    }
+
+    /// Checks if the event is a data event without any data
+    pub fn is_insignificant(&self) -> bool {
+        self.kind == EventKind::Data && self.data.is_empty()
     }
 }
 
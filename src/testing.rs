@@ -0,0 +1,481 @@
+//! In-memory stand-ins for serial ports and GPIO lines, with the `testing` feature.
+//!
+//! A `MockSerialPort` plays a script of reads, timeouts, errors and control
+//! line changes, and records what is written to it. A `MockGpio` records the
+//! levels it is set to, and a `MockGpioInput` has its level set by the test.
+//! A `MockGpioBackend` hands out mock lines to `GpioPins::attach`, keeping
+//! them to be inspected. All are cheap to clone, and clones share their
+//! state, so a clone kept by a test can be inspected after the original has
+//! been handed to the code under test.
+//!
+//! ```
+//! use std::io::Read;
+//!
+//! use serialpcap_rs::portinfo::PortControlLines;
+//! use serialpcap_rs::testing::MockSerialPort;
+//!
+//! let mut port = MockSerialPort::new()
+//!     .data(b"hello")
+//!     .control_lines(PortControlLines { cts: true, ..Default::default() })
+//!     .timeout();
+//! let mut buffer = [0; 16];
+//! assert_eq!(port.read(&mut buffer).unwrap(), 5);
+//! assert!(port.read(&mut buffer).is_err());
+//! ```
+
+use std::collections::VecDeque;
+use std::io;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use gpio::{GpioIn, GpioOut, GpioValue};
+use serialport::{ClearBuffer, DataBits, FlowControl, Parity, SerialPort, StopBits};
+
+use crate::gpiopins::{GpioBackend, GpioLine, LineId};
+use crate::linewatch::EdgeInput;
+use crate::portinfo::PortControlLines;
+
+/// A step in a `MockSerialPort`'s script.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MockStep {
+    Data(Vec<u8>),                  // Returned by reads, over several if it doesn't fit
+    Timeout,                        // A read waits for the port's timeout, then times out
+    Error(io::ErrorKind),           // A read fails
+    ControlLines(PortControlLines), // The control lines change, before the next read
+    Delay(Duration),                // Time passes, before the next read
+}
+
+/// The state shared between clones of a `MockSerialPort`.
+#[derive(Debug)]
+struct MockState {
+    script: VecDeque<MockStep>,
+    control_lines: PortControlLines,
+    has_control_lines: bool,
+    written: Vec<u8>,
+    breaking: bool,
+    baud_rate: u32,
+    data_bits: DataBits,
+    flow_control: FlowControl,
+    parity: Parity,
+    stop_bits: StopBits,
+    timeout: Duration,
+}
+
+/// A scripted serial port.
+///
+/// Reads work through the script in order. Once it runs out, reads time
+/// out as they would on an idle line. Control line reads return the state
+/// set by the last `MockStep::ControlLines` step reached, and writes of
+/// RTS and DTR change it.
+#[derive(Debug, Clone)]
+pub struct MockSerialPort {
+    name: String,
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockSerialPort {
+    /// A port at 9600 baud, 8N1, with a zero timeout, all control lines low and an empty script.
+    pub fn new() -> Self {
+        MockSerialPort {
+            name: "mock".to_string(),
+            state: Arc::new(Mutex::new(MockState {
+                script: VecDeque::new(),
+                control_lines: PortControlLines::default(),
+                has_control_lines: true,
+                written: Vec::new(),
+                breaking: false,
+                baud_rate: 9600,
+                data_bits: DataBits::Eight,
+                flow_control: FlowControl::None,
+                parity: Parity::None,
+                stop_bits: StopBits::One,
+                timeout: Duration::ZERO,
+            })),
+        }
+    }
+
+    /// Sets the name the port reports.
+    pub fn named(mut self, name: &str) -> Self {
+        self.name = name.to_string();
+        self
+    }
+
+    /// Adds a step to the end of the script.
+    pub fn step(self, step: MockStep) -> Self {
+        self.state().script.push_back(step);
+        self
+    }
+
+    /// Adds data to be read.
+    pub fn data(self, data: &[u8]) -> Self {
+        self.step(MockStep::Data(data.to_vec()))
+    }
+
+    /// Adds a read which times out.
+    pub fn timeout(self) -> Self {
+        self.step(MockStep::Timeout)
+    }
+
+    /// Adds a read which fails.
+    pub fn error(self, kind: io::ErrorKind) -> Self {
+        self.step(MockStep::Error(kind))
+    }
+
+    /// Adds a change of the control lines.
+    pub fn control_lines(self, control_lines: PortControlLines) -> Self {
+        self.step(MockStep::ControlLines(control_lines))
+    }
+
+    /// Adds a delay.
+    pub fn delay(self, delay: Duration) -> Self {
+        self.step(MockStep::Delay(delay))
+    }
+
+    /// Makes control line reads and writes fail, as they do on a pseudo terminal.
+    pub fn without_control_lines(self) -> Self {
+        self.state().has_control_lines = false;
+        self
+    }
+
+    /// Everything written to the port so far.
+    pub fn written(&self) -> Vec<u8> {
+        self.state().written.clone()
+    }
+
+    /// The current state of the control lines, including RTS and DTR as last written.
+    pub fn current_control_lines(&self) -> PortControlLines {
+        self.state().control_lines.clone()
+    }
+
+    /// Whether a break is being sent.
+    pub fn breaking(&self) -> bool {
+        self.state().breaking
+    }
+
+    /// Whether the whole script has been read.
+    pub fn script_done(&self) -> bool {
+        self.state().script.is_empty()
+    }
+
+    fn state(&self) -> MutexGuard<'_, MockState> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Reads or writes the control lines with `access`, or fails if the port has none.
+    fn control_line<T>(&self, access: impl FnOnce(&mut PortControlLines) -> T) -> serialport::Result<T> {
+        let mut state = self.state();
+        if !state.has_control_lines {
+            return Err(serialport::Error::new(serialport::ErrorKind::Unknown, "Not a typewriter"));
+        }
+        Ok(access(&mut state.control_lines))
+    }
+}
+
+impl Default for MockSerialPort {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl io::Read for MockSerialPort {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let mut state = self.state();
+            let Some(step) = state.script.pop_front() else {
+                let timeout = state.timeout;
+                drop(state);
+                thread::sleep(timeout);
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "Operation timed out"));
+            };
+            match step {
+                MockStep::Data(mut data) => {
+                    let read_len = data.len().min(buf.len());
+                    buf[..read_len].copy_from_slice(&data[..read_len]);
+                    if read_len < data.len() {
+                        state.script.push_front(MockStep::Data(data.split_off(read_len)));
+                    }
+                    return Ok(read_len);
+                },
+                MockStep::Timeout => {
+                    let timeout = state.timeout;
+                    drop(state);
+                    thread::sleep(timeout);
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "Operation timed out"));
+                },
+                MockStep::Error(kind) => return Err(io::Error::new(kind, "mock port error")),
+                MockStep::ControlLines(control_lines) => state.control_lines = control_lines,
+                MockStep::Delay(delay) => {
+                    drop(state);
+                    thread::sleep(delay);
+                },
+            }
+        }
+    }
+}
+
+impl io::Write for MockSerialPort {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.state().written.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SerialPort for MockSerialPort {
+    fn name(&self) -> Option<String> { Some(self.name.clone()) }
+    fn baud_rate(&self) -> serialport::Result<u32> { Ok(self.state().baud_rate) }
+    fn data_bits(&self) -> serialport::Result<DataBits> { Ok(self.state().data_bits) }
+    fn flow_control(&self) -> serialport::Result<FlowControl> { Ok(self.state().flow_control) }
+    fn parity(&self) -> serialport::Result<Parity> { Ok(self.state().parity) }
+    fn stop_bits(&self) -> serialport::Result<StopBits> { Ok(self.state().stop_bits) }
+    fn timeout(&self) -> Duration { self.state().timeout }
+    fn set_baud_rate(&mut self, baud_rate: u32) -> serialport::Result<()> { self.state().baud_rate = baud_rate; Ok(()) }
+    fn set_data_bits(&mut self, data_bits: DataBits) -> serialport::Result<()> { self.state().data_bits = data_bits; Ok(()) }
+    fn set_flow_control(&mut self, flow_control: FlowControl) -> serialport::Result<()> { self.state().flow_control = flow_control; Ok(()) }
+    fn set_parity(&mut self, parity: Parity) -> serialport::Result<()> { self.state().parity = parity; Ok(()) }
+    fn set_stop_bits(&mut self, stop_bits: StopBits) -> serialport::Result<()> { self.state().stop_bits = stop_bits; Ok(()) }
+    fn set_timeout(&mut self, timeout: Duration) -> serialport::Result<()> { self.state().timeout = timeout; Ok(()) }
+    fn write_request_to_send(&mut self, level: bool) -> serialport::Result<()> { self.control_line(|lines| lines.rts = level) }
+    fn write_data_terminal_ready(&mut self, level: bool) -> serialport::Result<()> { self.control_line(|lines| lines.dtr = level) }
+    fn read_clear_to_send(&mut self) -> serialport::Result<bool> { self.control_line(|lines| lines.cts) }
+    fn read_data_set_ready(&mut self) -> serialport::Result<bool> { self.control_line(|lines| lines.dsr) }
+    fn read_ring_indicator(&mut self) -> serialport::Result<bool> { self.control_line(|lines| lines.ri) }
+    fn read_carrier_detect(&mut self) -> serialport::Result<bool> { self.control_line(|lines| lines.cd) }
+    fn bytes_to_read(&self) -> serialport::Result<u32> {
+        let state = self.state();
+        let buffered = state.script.iter()
+            .take_while(|step| !matches!(step, MockStep::Timeout | MockStep::Error(_) | MockStep::Delay(_)))
+            .map(|step| match step {
+                MockStep::Data(data) => data.len(),
+                _ => 0,
+            })
+            .sum::<usize>();
+        Ok(buffered as u32)
+    }
+    fn bytes_to_write(&self) -> serialport::Result<u32> { Ok(0) }
+    fn clear(&self, buffer_to_clear: ClearBuffer) -> serialport::Result<()> {
+        if buffer_to_clear != ClearBuffer::Output {
+            // Drops the data already received, but not what is still to come
+            let mut state = self.state();
+            while matches!(state.script.front(), Some(MockStep::Data(_) | MockStep::ControlLines(_))) {
+                if let Some(MockStep::ControlLines(control_lines)) = state.script.pop_front() {
+                    state.control_lines = control_lines;
+                }
+            }
+        }
+        Ok(())
+    }
+    fn try_clone(&self) -> serialport::Result<Box<dyn SerialPort>> { Ok(Box::new(self.clone())) }
+    fn set_break(&self) -> serialport::Result<()> { self.state().breaking = true; Ok(()) }
+    fn clear_break(&self) -> serialport::Result<()> { self.state().breaking = false; Ok(()) }
+}
+
+/// A GPIO output which records the levels it is set to.
+#[derive(Debug, Clone, Default)]
+pub struct MockGpio {
+    values: Arc<Mutex<Vec<GpioValue>>>,
+    failing: bool,
+}
+
+impl MockGpio {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A GPIO whose writes all fail.
+    pub fn failing() -> Self {
+        MockGpio { failing: true, ..Self::default() }
+    }
+
+    /// Every level set so far, in order.
+    pub fn values(&self) -> Vec<GpioValue> {
+        self.lock().clone()
+    }
+
+    /// The level last set, if any.
+    pub fn value(&self) -> Option<GpioValue> {
+        self.lock().last().copied()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<GpioValue>> {
+        self.values.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn set(&mut self, value: GpioValue) -> io::Result<()> {
+        if self.failing {
+            return Err(io::Error::other("mock GPIO error"));
+        }
+        self.lock().push(value);
+        Ok(())
+    }
+}
+
+impl GpioOut for MockGpio {
+    type Error = io::Error;
+
+    fn set_low(&mut self) -> io::Result<()> {
+        self.set(GpioValue::Low)
+    }
+
+    fn set_high(&mut self) -> io::Result<()> {
+        self.set(GpioValue::High)
+    }
+}
+
+/// The state shared between clones of a `MockGpioInput`.
+#[derive(Debug)]
+struct MockInputState {
+    level: bool,
+    edges: VecDeque<bool>,  // Edges not yet waited for
+    failing: bool,
+}
+
+/// A GPIO input whose level is set by the test, with edge detection.
+#[derive(Debug, Clone)]
+pub struct MockGpioInput {
+    state: Arc<(Mutex<MockInputState>, Condvar)>,
+}
+
+impl MockGpioInput {
+    pub fn new(level: bool) -> Self {
+        MockGpioInput {
+            state: Arc::new((Mutex::new(MockInputState { level, edges: VecDeque::new(), failing: false }), Condvar::new())),
+        }
+    }
+
+    /// Sets the level, an edge if it differs from the current level.
+    pub fn set(&self, level: bool) {
+        let mut state = self.lock();
+        if state.level != level {
+            state.level = level;
+            state.edges.push_back(level);
+            self.state.1.notify_all();
+        }
+    }
+
+    /// Reports an edge to `level`, without it having been seen to change,
+    /// as when an input pulses quicker than its edges are handled.
+    pub fn edge(&self, level: bool) {
+        let mut state = self.lock();
+        state.level = level;
+        state.edges.push_back(level);
+        self.state.1.notify_all();
+    }
+
+    /// Makes reads and waits for edges fail from now on.
+    pub fn fail(&self) {
+        self.lock().failing = true;
+        self.state.1.notify_all();
+    }
+
+    fn lock(&self) -> MutexGuard<'_, MockInputState> {
+        self.state.0.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl GpioIn for MockGpioInput {
+    type Error = io::Error;
+
+    fn read_value(&mut self) -> io::Result<GpioValue> {
+        self.read_level().map(GpioValue::from)
+    }
+}
+
+impl EdgeInput for MockGpioInput {
+    fn read_level(&mut self) -> io::Result<bool> {
+        let state = self.lock();
+        if state.failing {
+            return Err(io::Error::other("mock GPIO error"));
+        }
+        Ok(state.level)
+    }
+
+    fn wait_for_edge(&mut self, timeout: Duration) -> io::Result<Option<bool>> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.lock();
+        loop {
+            if state.failing {
+                return Err(io::Error::other("mock GPIO error"));
+            }
+            if let Some(level) = state.edges.pop_front() {
+                return Ok(Some(level));
+            }
+            let Some(wait) = deadline.checked_duration_since(Instant::now()) else {
+                return Ok(None);
+            };
+            state = self.state.1.wait_timeout(state, wait).unwrap_or_else(|poisoned| poisoned.into_inner()).0;
+        }
+    }
+}
+
+/// The lines a `MockGpioBackend` has handed out.
+#[derive(Debug, Default)]
+struct MockLines {
+    outputs: Vec<(GpioLine, MockGpio)>,
+    inputs: Vec<(GpioLine, MockGpioInput)>,
+    missing: Vec<String>,   // Names of lines which aren't found
+}
+
+/// A GPIO backend handing out mock lines, inputs starting low.
+#[derive(Debug, Clone, Default)]
+pub struct MockGpioBackend {
+    lines: Arc<Mutex<MockLines>>,
+}
+
+impl MockGpioBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Makes opening the named line fail, as if the chip has no such line.
+    pub fn without_line(self, name: &str) -> Self {
+        self.lock().missing.push(name.to_string());
+        self
+    }
+
+    /// The output opened for `id`, and the settings it was opened with.
+    pub fn output(&self, id: &LineId) -> Option<(GpioLine, MockGpio)> {
+        self.lock().outputs.iter().find(|(line, _)| line.id == *id).cloned()
+    }
+
+    /// The input opened for `id`, and the settings it was opened with.
+    pub fn input(&self, id: &LineId) -> Option<(GpioLine, MockGpioInput)> {
+        self.lock().inputs.iter().find(|(line, _)| line.id == *id).cloned()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, MockLines> {
+        self.lines.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn check_found(&self, line: &GpioLine) -> io::Result<()> {
+        match &line.id {
+            LineId::Name(name) if self.lock().missing.contains(name) => {
+                Err(io::Error::new(io::ErrorKind::NotFound, format!("no GPIO line {}", name)))
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
+impl GpioBackend for MockGpioBackend {
+    type Output = MockGpio;
+    type Input = MockGpioInput;
+
+    fn open_output(&mut self, line: &GpioLine) -> io::Result<MockGpio> {
+        self.check_found(line)?;
+        let output = MockGpio::new();
+        self.lock().outputs.push((line.clone(), output.clone()));
+        Ok(output)
+    }
+
+    fn open_input(&mut self, line: &GpioLine) -> io::Result<MockGpioInput> {
+        self.check_found(line)?;
+        let input = MockGpioInput::new(false);
+        self.lock().inputs.push((line.clone(), input.clone()));
+        Ok(input)
+    }
+}
@@ -0,0 +1,343 @@
+//! Low level tty access for things the serialport crate doesn't expose.
+//!
+//! On Linux the kernel keeps per-port counters of breaks and UART errors,
+//! read with the `TIOCGICOUNT` ioctl. Elsewhere, and on ttys without the
+//! counters such as pseudo terminals, these events aren't captured.
+//!
+//! Linux can also mark errored bytes in line with the data (`PARMRK`), which
+//! places each error at the byte it happened on.
+
+use std::io;
+
+use serialport::{SerialPort, SerialPortBuilder};
+
+use crate::state::{ByteError, EventKind, LineError};
+
+/// Marker byte starting an error sequence, or doubled for a literal 0xFF.
+const MARK: u8 = 0xFF;
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::io;
+    use std::os::unix::io::RawFd;
+
+    use crate::state::EventKind;
+
+    /// `struct serial_icounter_struct` from `<linux/serial.h>`.
+    #[repr(C)]
+    #[derive(Default, Clone, Copy)]
+    struct SerialIcounter {
+        cts: libc::c_int,
+        dsr: libc::c_int,
+        rng: libc::c_int,
+        dcd: libc::c_int,
+        rx: libc::c_int,
+        tx: libc::c_int,
+        frame: libc::c_int,
+        overrun: libc::c_int,
+        parity: libc::c_int,
+        brk: libc::c_int,
+        buf_overrun: libc::c_int,
+        reserved: [libc::c_int; 9],
+    }
+
+    fn read_icount(fd: RawFd) -> io::Result<SerialIcounter> {
+        let mut counts = SerialIcounter::default();
+        // SAFETY: TIOCGICOUNT fills in a serial_icounter_struct, which `counts` matches.
+        let result = unsafe { libc::ioctl(fd, libc::TIOCGICOUNT, &mut counts as *mut SerialIcounter) };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(counts)
+    }
+
+    /// Makes the tty mark breaks and parity and framing errors in the data,
+    /// rather than dropping them or passing on the errored bytes as is.
+    pub fn mark_errors(fd: RawFd) -> io::Result<()> {
+        // SAFETY: termios is plain data, and is filled in by tcgetattr before use.
+        let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+        // SAFETY: `termios` is a valid termios struct for the calls to read and write.
+        unsafe {
+            if libc::tcgetattr(fd, &mut termios) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            termios.c_iflag |= libc::PARMRK | libc::INPCK;
+            termios.c_iflag &= !(libc::IGNPAR | libc::IGNBRK | libc::BRKINT | libc::ISTRIP);
+            if libc::tcsetattr(fd, libc::TCSANOW, &termios) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    /// Tracks the kernel's break and UART error counters for a tty.
+    pub struct UartCounters {
+        fd: RawFd,
+        last: SerialIcounter,
+    }
+
+    impl UartCounters {
+        /// Starts tracking the counters of `fd`, returns `None` if the tty doesn't keep them.
+        ///
+        /// `fd` must stay open for as long as the counters are polled.
+        pub fn new(fd: RawFd) -> Option<Self> {
+            read_icount(fd).ok().map(|last| UartCounters { fd, last })
+        }
+
+        /// Returns an event for each break and UART error since the last poll.
+        pub fn poll(&mut self) -> io::Result<Vec<EventKind>> {
+            let counts = read_icount(self.fd)?;
+            let mut events = Vec::new();
+            for (kind, now, last) in [
+                (EventKind::Break, counts.brk, self.last.brk),
+                (EventKind::FramingError, counts.frame, self.last.frame),
+                (EventKind::ParityError, counts.parity, self.last.parity),
+                (EventKind::Overrun, counts.overrun.wrapping_add(counts.buf_overrun),
+                    self.last.overrun.wrapping_add(self.last.buf_overrun)),
+            ] {
+                let new_events = now.wrapping_sub(last).max(0) as usize;
+                events.extend(std::iter::repeat_n(kind, new_events));
+            }
+            self.last = counts;
+            Ok(events)
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub use linux::UartCounters;
+
+/// The tty behind an open serial port, for the features the serialport crate doesn't expose.
+pub struct Tty {
+    #[cfg(target_os = "linux")]
+    fd: std::os::unix::io::RawFd,
+}
+
+impl Tty {
+    /// Starts tracking the UART counters, returns `None` if they aren't supported.
+    #[cfg(target_os = "linux")]
+    pub fn uart_counters(&self) -> Option<UartCounters> {
+        UartCounters::new(self.fd)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn uart_counters(&self) -> Option<UartCounters> {
+        None
+    }
+
+    /// Enables marking of UART errors in the received data, which must then
+    /// be passed through an `ErrorMarkDecoder`.
+    #[cfg(target_os = "linux")]
+    pub fn mark_errors(&self) -> io::Result<()> {
+        linux::mark_errors(self.fd)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn mark_errors(&self) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "marking UART errors is only supported on Linux"))
+    }
+}
+
+/// Placeholder for platforms without UART counters, it is never constructed.
+#[cfg(not(target_os = "linux"))]
+pub struct UartCounters(());
+
+#[cfg(not(target_os = "linux"))]
+impl UartCounters {
+    pub fn poll(&mut self) -> io::Result<Vec<EventKind>> {
+        Ok(vec![])
+    }
+}
+
+/// Whether the named port is a pseudo terminal, which has no control lines.
+#[cfg(target_os = "linux")]
+pub fn is_pty(port_name: &str) -> bool {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+    // Unix98 pty slaves are character devices with majors 136 to 143
+    std::fs::metadata(port_name).is_ok_and(|metadata| {
+        metadata.file_type().is_char_device() && (136..=143).contains(&libc::major(metadata.rdev()))
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn is_pty(_port_name: &str) -> bool {
+    false
+}
+
+/// Opens a serial port, along with its tty.
+///
+/// The tty must not outlive the port.
+#[cfg(target_os = "linux")]
+pub fn open(builder: SerialPortBuilder) -> serialport::Result<(Box<dyn SerialPort>, Tty)> {
+    let (port, tty) = open_native(builder)?;
+    Ok((Box::new(port), tty))
+}
+
+/// Opens a serial port as its native type, along with its tty.
+///
+/// The tty must not outlive the port.
+#[cfg(target_os = "linux")]
+pub fn open_native(builder: SerialPortBuilder) -> serialport::Result<(serialport::TTYPort, Tty)> {
+    use std::os::unix::io::AsRawFd;
+
+    let port = builder.open_native()?;
+    let tty = Tty { fd: port.as_raw_fd() };
+    Ok((port, tty))
+}
+
+/// Opens a serial port, along with its tty.
+#[cfg(not(target_os = "linux"))]
+pub fn open(builder: SerialPortBuilder) -> serialport::Result<(Box<dyn SerialPort>, Tty)> {
+    Ok((builder.open()?, Tty {}))
+}
+
+/// Decodes the error marks inserted in the data by a tty with `PARMRK` set.
+///
+/// An errored byte `c` is received as 0xFF 0x00 `c` and a break as
+/// 0xFF 0x00 0x00, while a literal 0xFF is doubled. A parity or framing
+/// error on a 0x00 byte therefore reads as a break. The marks don't say
+/// whether an error was a parity or a framing error, so the UART counters
+/// are used to tell them apart where the tty keeps them.
+#[derive(Debug, Default)]
+pub struct ErrorMarkDecoder {
+    pending: Vec<u8>,       // An incomplete mark sequence at the end of the last read
+    framing_errors: usize,  // Framing errors counted, but not yet matched to a mark
+}
+
+impl ErrorMarkDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Notes a UART counter event, returning true if it is reported by the
+    /// marks instead of as an event of its own.
+    pub fn counted(&mut self, kind: EventKind) -> bool {
+        match kind {
+            EventKind::FramingError => {
+                self.framing_errors += 1;
+                true
+            },
+            EventKind::Break | EventKind::ParityError => true,
+            _ => false,
+        }
+    }
+
+    /// Strips the marks from `raw`, returning the received data and the errors within it.
+    pub fn decode(&mut self, raw: &[u8]) -> (Vec<u8>, Vec<ByteError>) {
+        let mut input = std::mem::take(&mut self.pending);
+        input.extend_from_slice(raw);
+        let mut data = Vec::with_capacity(input.len());
+        let mut errors = Vec::new();
+        let mut i = 0;
+        while i < input.len() {
+            if input[i] != MARK {
+                data.push(input[i]);
+                i += 1;
+                continue;
+            }
+            match (input.get(i + 1), input.get(i + 2)) {
+                (Some(&MARK), _) => {
+                    data.push(MARK);
+                    i += 2;
+                },
+                (Some(0), Some(0)) => {
+                    errors.push(ByteError { offset: data.len(), kind: LineError::Break });
+                    i += 3;
+                },
+                (Some(0), Some(byte)) => {
+                    let kind = if self.framing_errors > 0 {
+                        self.framing_errors -= 1;
+                        LineError::Framing
+                    } else {
+                        LineError::Parity
+                    };
+                    errors.push(ByteError { offset: data.len(), kind });
+                    data.push(*byte);
+                    i += 3;
+                },
+                (None, _) | (Some(0), None) => {
+                    // Wait for the rest of the sequence
+                    self.pending = input[i..].to_vec();
+                    break;
+                },
+                (Some(_), _) => {
+                    // Not a mark sequence, keep the byte as received
+                    data.push(MARK);
+                    i += 1;
+                },
+            }
+        }
+        (data, errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads given to a decoder, and what it should return for the last of them.
+    struct Case<'a> {
+        name: &'a str,
+        reads: &'a [&'a [u8]],
+        data: &'a [u8],
+        errors: &'a [ByteError],
+    }
+
+    fn error(offset: usize, kind: LineError) -> ByteError {
+        ByteError { offset, kind }
+    }
+
+    fn check(cases: &[Case<'_>]) {
+        for case in cases {
+            let mut decoder = ErrorMarkDecoder::new();
+            let (last, earlier) = case.reads.split_last().unwrap();
+            for read in earlier {
+                decoder.decode(read);
+            }
+            let (data, errors) = decoder.decode(last);
+            assert_eq!(data, case.data, "{}", case.name);
+            assert_eq!(errors, case.errors, "{}", case.name);
+        }
+    }
+
+    #[test]
+    fn decodes_marks() {
+        check(&[
+            Case { name: "plain data", reads: &[&[0x01, 0x02]], data: &[0x01, 0x02], errors: &[] },
+            Case { name: "doubled 0xFF", reads: &[&[0x01, 0xFF, 0xFF, 0x02]], data: &[0x01, 0xFF, 0x02], errors: &[] },
+            Case { name: "errored byte", reads: &[&[0x01, 0xFF, 0x00, 0x41]], data: &[0x01, 0x41], errors: &[error(1, LineError::Parity)] },
+            Case { name: "errored 0xFF", reads: &[&[0xFF, 0x00, 0xFF]], data: &[0xFF], errors: &[error(0, LineError::Parity)] },
+            Case { name: "break", reads: &[&[0x01, 0xFF, 0x00, 0x00, 0x02]], data: &[0x01, 0x02], errors: &[error(1, LineError::Break)] },
+            Case { name: "unmarked 0xFF", reads: &[&[0xFF, 0x41]], data: &[0xFF, 0x41], errors: &[] },
+            Case {
+                name: "back to back",
+                reads: &[&[0xFF, 0xFF, 0xFF, 0x00, 0x00, 0xFF, 0x00, 0x42]],
+                data: &[0xFF, 0x42],
+                errors: &[error(1, LineError::Break), error(1, LineError::Parity)],
+            },
+        ]);
+    }
+
+    #[test]
+    fn decodes_marks_split_across_reads() {
+        check(&[
+            Case { name: "held back marker", reads: &[&[0x01, 0xFF]], data: &[0x01], errors: &[] },
+            Case { name: "after the marker", reads: &[&[0x01, 0xFF], &[0x00, 0x41]], data: &[0x41], errors: &[error(0, LineError::Parity)] },
+            Case { name: "before the byte", reads: &[&[0x01, 0xFF, 0x00], &[0x41, 0x02]], data: &[0x41, 0x02], errors: &[error(0, LineError::Parity)] },
+            Case { name: "break", reads: &[&[0xFF, 0x00], &[0x00, 0x02]], data: &[0x02], errors: &[error(0, LineError::Break)] },
+            Case { name: "doubled 0xFF", reads: &[&[0x01, 0xFF], &[0xFF, 0x02]], data: &[0xFF, 0x02], errors: &[] },
+            Case { name: "over three reads", reads: &[&[0xFF], &[0x00], &[0x00]], data: &[], errors: &[error(0, LineError::Break)] },
+        ]);
+    }
+
+    #[test]
+    fn counted_framing_errors_label_marks() {
+        let mut decoder = ErrorMarkDecoder::new();
+        assert!(decoder.counted(EventKind::FramingError));
+        assert!(decoder.counted(EventKind::ParityError));
+        assert!(!decoder.counted(EventKind::Overrun));
+        let (_, errors) = decoder.decode(&[0xFF, 0x00, 0x41, 0xFF, 0x00, 0x42]);
+        assert_eq!(errors, [error(0, LineError::Framing), error(1, LineError::Parity)]);
+    }
+}
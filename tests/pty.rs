@@ -0,0 +1,321 @@
+//! Captures from Linux pseudo-terminals.
+//!
+//! Each test opens a pty pair, captures the slave side, and plays a script
+//! of writes with delays between them into the master side, as the far end
+//! of a serial line would. The capture is written as a pcap file, and the
+//! records read back from it are checked. Replays go the other way, from a
+//! pcap file out of the slave side, and are checked as read from the master.
+//! Bridges join the slaves of two pairs. With the `tokio` feature, async
+//! captures are read as a stream instead.
+
+#![cfg(target_os = "linux")]
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::fd::{FromRawFd, OwnedFd};
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use pcap_file::pcap::{PcapHeader, PcapPacket, PcapReader, PcapWriter};
+use pcap_file::{DataLink, TsResolution};
+use serialpcap_rs::capture::{self, Capture, CaptureBuilder, EncapsulationMode, StopConditions, MAX_PACKET_SIZE};
+use serialpcap_rs::gpiopins::GpioPins;
+use serialpcap_rs::linemap::LineRouter;
+use serialpcap_rs::output::{CapturedPacket, OutputFormat};
+use serialpcap_rs::replay::{self, Pacing};
+use serialpcap_rs::state::Direction;
+use serialpcap_rs::rotate::RotatingWriter;
+
+const BAUD_RATE: u32 = 115200;
+const FRAME_GAP: Duration = Duration::from_millis(20);
+
+/// A pty pair, the master is the far end of the line and the slave is captured.
+struct Pty {
+    master: File,
+    slave: OwnedFd,   // Held open so the master doesn't see a hangup before the capture opens
+    slave_path: String,
+}
+
+fn openpty() -> Pty {
+    let mut master = 0;
+    let mut slave = 0;
+    let result = unsafe { libc::openpty(&mut master, &mut slave, std::ptr::null_mut(), std::ptr::null(), std::ptr::null()) };
+    assert_eq!(result, 0, "openpty failed: {}", io::Error::last_os_error());
+    let slave_path = std::fs::read_link(format!("/proc/self/fd/{}", slave)).unwrap();
+    Pty {
+        master: unsafe { File::from_raw_fd(master) },
+        slave: unsafe { OwnedFd::from_raw_fd(slave) },
+        slave_path: slave_path.to_str().unwrap().to_string(),
+    }
+}
+
+fn builder(pty: &Pty) -> CaptureBuilder {
+    Capture::builder(&pty.slave_path)
+        .baud_rate(BAUD_RATE)
+        .frame_gap(FRAME_GAP)
+        .encapsulation(EncapsulationMode::Raw)
+}
+
+/// Writes each chunk of the script to the master after its delay.
+///
+/// If `hang_up`, the master is closed at the end of the script, hanging up
+/// the slave so its reads fail. A hangup discards input not yet read, so the
+/// capture is given a moment to read the last chunk, but less than the frame
+/// gap. Otherwise the master is handed back to be kept open until the capture ends.
+fn play(mut master: File, script: Vec<(Duration, Vec<u8>)>, hang_up: bool) -> thread::JoinHandle<Option<File>> {
+    thread::spawn(move || {
+        for (delay, data) in script {
+            thread::sleep(delay);
+            master.write_all(&data).unwrap();
+        }
+        if hang_up {
+            thread::sleep(FRAME_GAP / 4);
+            return None;
+        }
+        Some(master)
+    })
+}
+
+/// A pcap file in the temporary directory, removed when dropped.
+struct TempCapture(PathBuf);
+
+impl TempCapture {
+    fn new(name: &str) -> Self {
+        TempCapture(std::env::temp_dir().join(format!("serialpcap-pty-{}-{}.pcap", name, std::process::id())))
+    }
+
+    /// The records in the file, as their timestamps and data.
+    fn records(&self) -> Vec<(Duration, Vec<u8>)> {
+        let mut reader = PcapReader::new(File::open(&self.0).unwrap()).unwrap();
+        let mut records = Vec::new();
+        while let Some(packet) = reader.next_packet() {
+            let packet = packet.unwrap();
+            records.push((packet.timestamp, packet.data.into_owned()));
+        }
+        records
+    }
+}
+
+impl Drop for TempCapture {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Plays the script into a capture of the pty, running the capture for
+/// `duration`, and returns the pcap file along with how the capture ended.
+fn capture(name: &str, builder: CaptureBuilder, pty: Pty, script: Vec<(Duration, Vec<u8>)>, hang_up: bool, duration: Duration) -> (TempCapture, io::Result<capture::CaptureStats>) {
+    let port = builder.open().unwrap();
+    let file = TempCapture::new(name);
+    let writer = RotatingWriter::exact(file.0.to_str().unwrap(), OutputFormat::Pcap, MAX_PACKET_SIZE as u32, &[port.interface_info()]).unwrap();
+    let player = play(pty.master, script, hang_up);
+    let limits = StopConditions { duration: Some(duration), ..Default::default() };
+    let result = capture::run(vec![(0, port)], writer, &limits, Arc::new(AtomicBool::new(false)));
+    drop(player.join().unwrap());
+    drop(pty.slave);
+    (file, result)
+}
+
+#[test]
+fn gap_splits_frames() {
+    let pty = openpty();
+    let script = vec![
+        (Duration::from_millis(50), b"hello".to_vec()),
+        (Duration::from_millis(2), b" world".to_vec()),
+        (Duration::from_millis(100), b"second".to_vec()),
+        (Duration::from_millis(100), b"third".to_vec()),
+    ];
+    let (file, result) = capture("gap", builder(&pty), pty, script, false, Duration::from_millis(500));
+    let stats = result.unwrap();
+    let records = file.records();
+    let data: Vec<&[u8]> = records.iter().map(|(_, data)| data.as_slice()).collect();
+    assert_eq!(data, [&b"hello world"[..], b"second", b"third"]);
+    assert_eq!(stats.packets, 3);
+    assert_eq!(stats.bytes, 22);
+    // Timestamps are of the first byte, so are the delays apart
+    for pair in records.windows(2) {
+        let apart = pair[1].0 - pair[0].0;
+        assert!(apart >= Duration::from_millis(90) && apart < Duration::from_millis(200), "records {:?} apart", apart);
+    }
+}
+
+#[test]
+fn full_buffer_splits_packet() {
+    let pty = openpty();
+    let data: Vec<u8> = (0..MAX_PACKET_SIZE + 100).map(|i| i as u8).collect();
+    let script = vec![(Duration::from_millis(50), data.clone())];
+    let (file, result) = capture("split", builder(&pty), pty, script, false, Duration::from_millis(300));
+    result.unwrap();
+    let records = file.records();
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0].1, data[..MAX_PACKET_SIZE]);
+    assert_eq!(records[1].1, data[MAX_PACKET_SIZE..]);
+}
+
+#[test]
+fn port_error_follows_data() {
+    let pty = openpty();
+    let mut capture = builder(&pty).open().unwrap();
+    let player = play(pty.master, vec![(Duration::from_millis(50), b"last words".to_vec())], true);
+    // The data read before the port failed comes first, then the failure
+    let event = capture.next().unwrap().unwrap();
+    assert_eq!(event.data, b"last words");
+    assert!(capture.next().unwrap().is_err());
+    assert!(capture.next().is_none());
+    player.join().unwrap();
+}
+
+#[test]
+fn port_error_ends_capture_after_writing_data() {
+    let pty = openpty();
+    let script = vec![(Duration::from_millis(50), b"before the hangup".to_vec())];
+    let (file, result) = capture("hangup", builder(&pty), pty, script, true, Duration::from_secs(5));
+    assert!(result.is_err());
+    let records = file.records();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].1, b"before the hangup");
+}
+
+#[test]
+fn run_closes_the_port_before_returning() {
+    let pty = openpty();
+    // The port's thread only sees the stop between reads, which last a frame gap
+    let port = builder(&pty).frame_gap(Duration::from_millis(500)).open().unwrap();
+    let limits = StopConditions { duration: Some(Duration::from_millis(100)), ..Default::default() };
+    capture::run(vec![(0, port)], Vec::new(), &limits, Arc::new(AtomicBool::new(false))).unwrap();
+    drop(pty.slave);
+    // With every slave closed, the master sees a hangup rather than blocking
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let mut master = pty.master;
+    thread::spawn(move || sender.send(master.read(&mut [0; 1]).map_err(|e| e.raw_os_error())));
+    let read = receiver.recv_timeout(Duration::from_millis(100)).expect("the port is still open");
+    assert_eq!(read, Err(Some(libc::EIO)));
+}
+
+#[test]
+fn unsupported_datalink_is_rejected() {
+    let pty = openpty();
+    let result = builder(&pty).encapsulation(EncapsulationMode::DatalinkType).datalink(DataLink::ETHERNET).open();
+    assert_eq!(result.err().map(|e| e.kind()), Some(io::ErrorKind::Unsupported));
+    // Raw captures don't encapsulate, so any datalink type will do
+    assert!(builder(&pty).datalink(DataLink::ETHERNET).open().is_ok());
+}
+
+#[test]
+fn replay_keeps_timing() {
+    let file = TempCapture::new("replay");
+    let header = PcapHeader { datalink: DataLink::USER0, ts_resolution: TsResolution::NanoSecond, ..Default::default() };
+    let mut writer = PcapWriter::with_header(File::create(&file.0).unwrap(), header).unwrap();
+    let start = Duration::from_secs(1_700_000_000);
+    for (offset, data) in [(0, &b"one"[..]), (100, b"two"), (300, b"three")] {
+        writer.write_packet(&PcapPacket::new(start + Duration::from_millis(offset), data.len() as u32, data)).unwrap();
+    }
+    drop(writer);
+    let records = replay::read_capture(File::open(&file.0).unwrap(), 0).unwrap();
+    assert_eq!(records.len(), 3);
+
+    let pty = openpty();
+    let mut port = replay::open_port(&pty.slave_path, BAUD_RATE, 'n', 1, &GpioPins::default()).unwrap();
+    let replayer = thread::spawn(move || replay::replay(&mut port, &records, Pacing::Speed(2.0), &mut LineRouter::default(), &AtomicBool::new(false)));
+    // When each byte arrived at the far end
+    let mut master = pty.master;
+    let mut received = Vec::new();
+    let mut arrivals = Vec::new();
+    while received.len() < 11 {
+        let mut buffer = [0; 16];
+        let read_len = master.read(&mut buffer).unwrap();
+        received.extend_from_slice(&buffer[..read_len]);
+        arrivals.extend(std::iter::repeat_n(Instant::now(), read_len));
+    }
+    let stats = replayer.join().unwrap().unwrap();
+    assert_eq!(received, b"onetwothree");
+    assert_eq!((stats.packets, stats.bytes), (3, 11));
+    // Twice as fast, so half the captured spacing
+    for (offset, expected) in [(3, 50), (6, 150)] {
+        let apart = arrivals[offset] - arrivals[0];
+        assert!(apart >= Duration::from_millis(expected - 5) && apart < Duration::from_millis(expected + 50), "byte {} after {:?}", offset, apart);
+    }
+    drop(pty.slave);
+}
+
+#[test]
+fn bridge_forwards_both_ways() {
+    let dte = openpty();
+    let dce = openpty();
+    let mut dte_port = builder(&dte).direction(Direction::Outbound).open().unwrap();
+    let mut dce_port = builder(&dce).direction(Direction::Inbound).open().unwrap();
+    capture::bridge(&mut dte_port, &mut dce_port, LineRouter::default(), LineRouter::default()).unwrap();
+
+    let player = play(dte.master.try_clone().unwrap(), vec![(Duration::from_millis(50), b"request".to_vec())], false);
+    let answerer = thread::spawn({
+        let mut dce_master = dce.master.try_clone().unwrap();
+        move || {
+            let mut request = [0; 7];
+            dce_master.read_exact(&mut request).unwrap();
+            thread::sleep(Duration::from_millis(50));
+            dce_master.write_all(b"response").unwrap();
+            request
+        }
+    });
+    let mut packets: Vec<CapturedPacket> = Vec::new();
+    let limits = StopConditions { duration: Some(Duration::from_millis(400)), ..Default::default() };
+    capture::run(vec![(0, dte_port), (0, dce_port)], &mut packets, &limits, Arc::new(AtomicBool::new(false))).unwrap();
+    player.join().unwrap();
+    assert_eq!(&answerer.join().unwrap(), b"request");
+
+    let mut response = [0; 8];
+    let mut dte_master = dte.master;
+    dte_master.read_exact(&mut response).unwrap();
+    assert_eq!(&response, b"response");
+    let captured: Vec<(Direction, &[u8])> = packets.iter().map(|packet| (packet.direction, packet.data.as_slice())).collect();
+    assert_eq!(captured, [(Direction::Outbound, &b"request"[..]), (Direction::Inbound, b"response")]);
+    drop((dte.slave, dce.slave));
+}
+
+/// Reads `count` events from an async capture of the pty as the script plays.
+#[cfg(feature = "tokio")]
+async fn capture_async(builder: CaptureBuilder, pty: Pty, script: Vec<(Duration, Vec<u8>)>, count: usize) -> Vec<Vec<u8>> {
+    let mut port = builder.open_async().unwrap();
+    let player = play(pty.master, script, false);
+    let mut events = Vec::new();
+    while events.len() < count {
+        let event = tokio::time::timeout(Duration::from_secs(5), port.next_event()).await
+            .expect("timed out waiting for an event")
+            .expect("capture ended early")
+            .unwrap();
+        events.push(event.data);
+    }
+    drop(player.join().unwrap());
+    drop(pty.slave);
+    events
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn async_gap_splits_frames() {
+    let pty = openpty();
+    let script = vec![
+        (Duration::from_millis(50), b"hello".to_vec()),
+        (Duration::from_millis(2), b" world".to_vec()),
+        (Duration::from_millis(100), b"second".to_vec()),
+    ];
+    let events = capture_async(builder(&pty), pty, script, 2).await;
+    assert_eq!(events, [b"hello world".to_vec(), b"second".to_vec()]);
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn async_framer_joins_across_gaps() {
+    let pty = openpty();
+    let script = vec![
+        (Duration::from_millis(50), b"he".to_vec()),
+        (Duration::from_millis(100), b"llo\nwor".to_vec()),
+        (Duration::from_millis(100), b"ld\n".to_vec()),
+    ];
+    let builder = builder(&pty).framing(serialpcap_rs::framing::FramingMode::Delimiter(b"\n".to_vec()), false);
+    let events = capture_async(builder, pty, script, 2).await;
+    assert_eq!(events, [b"hello\n".to_vec(), b"world\n".to_vec()]);
+}